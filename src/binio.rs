@@ -0,0 +1,133 @@
+use crate::mathvec::{Float, Vec2d};
+
+use std::io::{self, Read, Write};
+
+//Little endian encoding shared by checkpoints and binary snapshots. Floats
+//of either precision are stored as f64, which round trips f32 exactly.
+
+pub fn invalid_data(msg : &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn write_magic<W : Write>(out : &mut W, magic : &[u8; 4], version : u32) -> io::Result<()> {
+    out.write_all(magic)?;
+    write_u32(out, version)
+}
+
+/// Checks the magic and returns the version, rejecting anything newer than
+/// `max_version`.
+pub fn read_magic<R : Read>(input : &mut R, magic : &[u8; 4], max_version : u32) -> io::Result<u32> {
+    let mut found = [0u8; 4];
+    input.read_exact(&mut found)?;
+    if &found != magic {
+        return Err(invalid_data("wrong magic number"));
+    }
+    let version = read_u32(input)?;
+    if version == 0 || version > max_version {
+        return Err(invalid_data("unsupported format version"));
+    }
+    Ok(version)
+}
+
+pub fn write_u8<W : Write>(out : &mut W, val : u8) -> io::Result<()> {
+    out.write_all(&[val])
+}
+pub fn read_u8<R : Read>(input : &mut R) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    input.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+pub fn write_bool<W : Write>(out : &mut W, val : bool) -> io::Result<()> {
+    write_u8(out, val as u8)
+}
+pub fn read_bool<R : Read>(input : &mut R) -> io::Result<bool> {
+    match read_u8(input)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(invalid_data("invalid bool")),
+    }
+}
+
+pub fn write_u32<W : Write>(out : &mut W, val : u32) -> io::Result<()> {
+    out.write_all(&val.to_le_bytes())
+}
+pub fn read_u32<R : Read>(input : &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub fn write_u64<W : Write>(out : &mut W, val : u64) -> io::Result<()> {
+    out.write_all(&val.to_le_bytes())
+}
+pub fn read_u64<R : Read>(input : &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    input.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+pub fn write_u128<W : Write>(out : &mut W, val : u128) -> io::Result<()> {
+    out.write_all(&val.to_le_bytes())
+}
+pub fn read_u128<R : Read>(input : &mut R) -> io::Result<u128> {
+    let mut buf = [0u8; 16];
+    input.read_exact(&mut buf)?;
+    Ok(u128::from_le_bytes(buf))
+}
+
+pub fn write_len<W : Write>(out : &mut W, len : usize) -> io::Result<()> {
+    write_u64(out, len as u64)
+}
+pub fn read_len<R : Read>(input : &mut R) -> io::Result<usize> {
+    let len = read_u64(input)?;
+    if len > usize::MAX as u64 {
+        return Err(invalid_data("length does not fit in memory"));
+    }
+    Ok(len as usize)
+}
+
+pub fn write_f64<W : Write>(out : &mut W, val : f64) -> io::Result<()> {
+    out.write_all(&val.to_le_bytes())
+}
+pub fn read_f64<R : Read>(input : &mut R) -> io::Result<f64> {
+    let mut buf = [0u8; 8];
+    input.read_exact(&mut buf)?;
+    Ok(f64::from_le_bytes(buf))
+}
+
+pub fn write_f32<W : Write>(out : &mut W, val : f32) -> io::Result<()> {
+    out.write_all(&val.to_le_bytes())
+}
+pub fn read_f32<R : Read>(input : &mut R) -> io::Result<f32> {
+    let mut buf = [0u8; 4];
+    input.read_exact(&mut buf)?;
+    Ok(f32::from_le_bytes(buf))
+}
+
+pub fn write_float<W : Write, S : Float>(out : &mut W, val : S) -> io::Result<()> {
+    write_f64(out, val.to_f64())
+}
+pub fn read_float<R : Read, S : Float>(input : &mut R) -> io::Result<S> {
+    Ok(S::from_f64(read_f64(input)?))
+}
+
+pub fn write_vec2d<W : Write, S : Float>(out : &mut W, val : Vec2d<S>) -> io::Result<()> {
+    write_float(out, val.x)?;
+    write_float(out, val.y)
+}
+pub fn read_vec2d<R : Read, S : Float>(input : &mut R) -> io::Result<Vec2d<S>> {
+    let x = read_float(input)?;
+    let y = read_float(input)?;
+    Ok(Vec2d::new(x, y))
+}
+
+pub fn write_option_float<W : Write, S : Float>(out : &mut W, val : Option<S>) -> io::Result<()> {
+    write_bool(out, val.is_some())?;
+    write_float(out, val.unwrap_or(S::ZERO))
+}
+pub fn read_option_float<R : Read, S : Float>(input : &mut R) -> io::Result<Option<S>> {
+    let present = read_bool(input)?;
+    let val = read_float(input)?;
+    Ok(if present { Some(val) } else { None })
+}
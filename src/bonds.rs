@@ -0,0 +1,411 @@
+use crate::mathvec::{Scalar, Vec2d};
+use crate::particles::Particle;
+use crate::binio::*;
+
+use std::io::{self, Read, Write};
+
+const PI : Scalar = std::f64::consts::PI;
+
+/// Harmonic spring between particles `a` and `b`. A bond with `max_strain`
+/// breaks for good once `|L - L0|/L0` exceeds it.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Bond {
+    pub a : usize,
+    pub b : usize,
+    pub rest_length : Scalar,
+    pub stiffness : Scalar,
+    pub damping : Scalar,
+    pub max_strain : Option<Scalar>,
+    pub broken : bool,
+}
+
+impl Bond {
+    pub fn new(a : usize, b : usize, rest_length : Scalar, stiffness : Scalar) -> Bond {
+        Bond {
+            a,
+            b,
+            rest_length,
+            stiffness,
+            damping : 0.0,
+            max_strain : None,
+            broken : false,
+        }
+    }
+    pub fn with_damping(self, damping : Scalar) -> Bond {
+        Bond {
+            damping,
+            ..self
+        }
+    }
+    pub fn with_max_strain(self, max_strain : Scalar) -> Bond {
+        Bond {
+            max_strain : Some(max_strain),
+            ..self
+        }
+    }
+
+    pub fn strain(&self, parts : &[Particle]) -> Scalar {
+        let length = (parts[self.b].pos - parts[self.a].pos).mag();
+        (length - self.rest_length).abs()/self.rest_length
+    }
+
+    /// Force on particle `a`; particle `b` feels the opposite.
+    pub fn force_on_a(&self, part_a : &Particle, part_b : &Particle) -> Vec2d {
+        let diff = part_b.pos - part_a.pos;
+        let length = diff.mag();
+        if length < 0.00001 {
+            return Vec2d::zero();
+        }
+        let dir = diff/length;
+        let relvel = part_b.vel - part_a.vel;
+        (self.stiffness * (length - self.rest_length) + self.damping * dir.dot(relvel)) * dir
+    }
+
+    pub fn energy(&self, parts : &[Particle]) -> Scalar {
+        let stretch = (parts[self.b].pos - parts[self.a].pos).mag() - self.rest_length;
+        0.5 * self.stiffness * stretch * stretch
+    }
+}
+
+/// Harmonic three-body bond on the signed angle `a - center - c`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct AngleBond {
+    pub a : usize,
+    pub center : usize,
+    pub c : usize,
+    pub rest_angle : Scalar,
+    pub stiffness : Scalar,
+}
+
+fn wrap_angle(angle : Scalar) -> Scalar {
+    let mut retval = angle;
+    while retval > PI {
+        retval -= 2.0 * PI;
+    }
+    while retval < -PI {
+        retval += 2.0 * PI;
+    }
+    retval
+}
+
+impl AngleBond {
+    pub fn new(a : usize, center : usize, c : usize, rest_angle : Scalar, stiffness : Scalar) -> AngleBond {
+        AngleBond {
+            a,
+            center,
+            c,
+            rest_angle,
+            stiffness,
+        }
+    }
+
+    pub fn angle_between(pos_a : Vec2d, pos_center : Vec2d, pos_c : Vec2d) -> Scalar {
+        let u = pos_a - pos_center;
+        let v = pos_c - pos_center;
+        (u.x * v.y - u.y * v.x).atan2(u.dot(v))
+    }
+
+    /// Forces on `(a, center, c)`.
+    pub fn forces(&self, parts : &[Particle]) -> (Vec2d, Vec2d, Vec2d) {
+        let u = parts[self.a].pos - parts[self.center].pos;
+        let v = parts[self.c].pos - parts[self.center].pos;
+        let (u2, v2) = (u.mag_squared(), v.mag_squared());
+        if u2 < 0.00001 || v2 < 0.00001 {
+            return (Vec2d::zero(), Vec2d::zero(), Vec2d::zero());
+        }
+        let angle = (u.x * v.y - u.y * v.x).atan2(u.dot(v));
+        let torque = -self.stiffness * wrap_angle(angle - self.rest_angle);
+        let f_a = torque * (-1.0 * u.perp()/u2);
+        let f_c = torque * (v.perp()/v2);
+        (f_a, -1.0 * (f_a + f_c), f_c)
+    }
+
+    pub fn energy(&self, parts : &[Particle]) -> Scalar {
+        let angle = AngleBond::angle_between(parts[self.a].pos, parts[self.center].pos, parts[self.c].pos);
+        let delta = wrap_angle(angle - self.rest_angle);
+        0.5 * self.stiffness * delta * delta
+    }
+}
+
+/// The bonded topology of a simulation, indexed by particle position in the
+/// handler's particle list.
+#[derive(Clone, Default)]
+pub struct BondList {
+    bonds : Vec<Bond>,
+    angles : Vec<AngleBond>,
+    bonds_of : Vec<Vec<usize>>,
+    angles_of : Vec<Vec<usize>>,
+    snapshot : Vec<Particle>,
+}
+
+impl BondList {
+    pub fn new() -> BondList {
+        BondList::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bonds.is_empty() && self.angles.is_empty()
+    }
+
+    pub fn bonds(&self) -> &[Bond] {
+        &self.bonds
+    }
+    pub fn angles(&self) -> &[AngleBond] {
+        &self.angles
+    }
+
+    pub fn add_bond(&mut self, bond : Bond) {
+        self.bonds.push(bond);
+        self.bonds_of.clear();
+    }
+    pub fn add_angle(&mut self, angle : AngleBond) {
+        self.angles.push(angle);
+        self.angles_of.clear();
+    }
+
+    /// Appends another list whose indices start at `offset` in this one.
+    pub fn extend_with_offset(&mut self, other : &BondList, offset : usize) {
+        for bond in other.bonds.iter() {
+            self.add_bond(Bond { a : bond.a + offset, b : bond.b + offset, ..*bond });
+        }
+        for angle in other.angles.iter() {
+            self.add_angle(AngleBond { a : angle.a + offset, center : angle.center + offset, c : angle.c + offset, ..*angle });
+        }
+    }
+
+    /// Renumbers particle indices after particles were removed; `map[old]`
+    /// is the new index or `None` for removed particles. Bonds and angles
+    /// touching a removed particle are dropped.
+    pub fn remap(&mut self, map : &[Option<usize>]) {
+        let lookup = |idx : usize| map.get(idx).cloned().unwrap_or(None);
+        self.bonds = self.bonds.iter()
+            .filter_map(|bond| match (lookup(bond.a), lookup(bond.b)) {
+                (Some(a), Some(b)) => Some(Bond { a, b, ..*bond }),
+                _ => None,
+            })
+            .collect();
+        self.angles = self.angles.iter()
+            .filter_map(|angle| match (lookup(angle.a), lookup(angle.center), lookup(angle.c)) {
+                (Some(a), Some(center), Some(c)) => Some(AngleBond { a, center, c, ..*angle }),
+                _ => None,
+            })
+            .collect();
+        self.bonds_of.clear();
+        self.angles_of.clear();
+        self.snapshot.clear();
+    }
+
+    /// Bonds and angles including which bonds have broken; the per-particle
+    /// index is rebuilt on the next `update`.
+    pub fn write_to<W : Write>(&self, out : &mut W) -> io::Result<()> {
+        write_len(out, self.bonds.len())?;
+        for bond in self.bonds.iter() {
+            write_len(out, bond.a)?;
+            write_len(out, bond.b)?;
+            write_f64(out, bond.rest_length)?;
+            write_f64(out, bond.stiffness)?;
+            write_f64(out, bond.damping)?;
+            write_option_float(out, bond.max_strain)?;
+            write_bool(out, bond.broken)?;
+        }
+        write_len(out, self.angles.len())?;
+        for angle in self.angles.iter() {
+            write_len(out, angle.a)?;
+            write_len(out, angle.center)?;
+            write_len(out, angle.c)?;
+            write_f64(out, angle.rest_angle)?;
+            write_f64(out, angle.stiffness)?;
+        }
+        Ok(())
+    }
+    pub fn read_from<R : Read>(input : &mut R) -> io::Result<BondList> {
+        let mut retval = BondList::new();
+        for _ in 0..read_len(input)? {
+            retval.add_bond(Bond {
+                a : read_len(input)?,
+                b : read_len(input)?,
+                rest_length : read_f64(input)?,
+                stiffness : read_f64(input)?,
+                damping : read_f64(input)?,
+                max_strain : read_option_float(input)?,
+                broken : read_bool(input)?,
+            });
+        }
+        for _ in 0..read_len(input)? {
+            retval.add_angle(AngleBond {
+                a : read_len(input)?,
+                center : read_len(input)?,
+                c : read_len(input)?,
+                rest_angle : read_f64(input)?,
+                stiffness : read_f64(input)?,
+            });
+        }
+        Ok(retval)
+    }
+
+    pub fn num_broken(&self) -> usize {
+        self.bonds.iter().filter(|b| b.broken).count()
+    }
+
+    fn rebuild_index(&mut self, num_particles : usize) {
+        self.bonds_of = vec![Vec::new() ; num_particles];
+        for (idx, bond) in self.bonds.iter().enumerate() {
+            if bond.broken || bond.a >= num_particles || bond.b >= num_particles {
+                continue;
+            }
+            self.bonds_of[bond.a].push(idx);
+            self.bonds_of[bond.b].push(idx);
+        }
+        self.angles_of = vec![Vec::new() ; num_particles];
+        for (idx, angle) in self.angles.iter().enumerate() {
+            if angle.a >= num_particles || angle.center >= num_particles || angle.c >= num_particles {
+                continue;
+            }
+            self.angles_of[angle.a].push(idx);
+            self.angles_of[angle.center].push(idx);
+            self.angles_of[angle.c].push(idx);
+        }
+    }
+
+    /// Takes the particle state for the coming force evaluation and breaks
+    /// any bond stretched past its threshold.
+    pub fn update(&mut self, parts : Vec<Particle>) {
+        let mut changed = self.bonds_of.len() != parts.len() || self.angles_of.len() != parts.len();
+        for bond in self.bonds.iter_mut() {
+            if bond.broken || bond.a >= parts.len() || bond.b >= parts.len() {
+                continue;
+            }
+            if let Some(max_strain) = bond.max_strain {
+                if bond.strain(&parts) > max_strain {
+                    bond.broken = true;
+                    changed = true;
+                }
+            }
+        }
+        if changed {
+            self.rebuild_index(parts.len());
+        }
+        self.snapshot = parts;
+    }
+
+    pub fn calculate_forces(&self, idx : usize) -> Vec2d {
+        let mut retval = Vec2d::zero();
+        let parts = &self.snapshot;
+        if let Some(bond_ids) = self.bonds_of.get(idx) {
+            for &bond_idx in bond_ids {
+                let bond = &self.bonds[bond_idx];
+                let f_a = bond.force_on_a(&parts[bond.a], &parts[bond.b]);
+                retval += if bond.a == idx { f_a } else { -1.0 * f_a };
+            }
+        }
+        if let Some(angle_ids) = self.angles_of.get(idx) {
+            for &angle_idx in angle_ids {
+                let angle = &self.angles[angle_idx];
+                let (f_a, f_center, f_c) = angle.forces(parts);
+                if angle.a == idx {
+                    retval += f_a;
+                }
+                if angle.center == idx {
+                    retval += f_center;
+                }
+                if angle.c == idx {
+                    retval += f_c;
+                }
+            }
+        }
+        retval
+    }
+
+    pub fn energy(&self, parts : &[Particle]) -> Scalar {
+        let n = parts.len();
+        let bond_energy : Scalar = self.bonds.iter()
+            .filter(|b| !b.broken && b.a < n && b.b < n)
+            .map(|b| b.energy(parts))
+            .sum();
+        let angle_energy : Scalar = self.angles.iter()
+            .filter(|a| a.a < n && a.center < n && a.c < n)
+            .map(|a| a.energy(parts))
+            .sum();
+        bond_energy + angle_energy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(stretch : Scalar) -> Vec<Particle> {
+        vec![
+            Particle::new(1.0, 0.1, Vec2d::zero(), Vec2d::zero()),
+            Particle::new(1.0, 0.1, Vec2d::new(1.0 + stretch, 0.0), Vec2d::zero()),
+        ]
+    }
+
+    /// Stretch of the pair after `time`, by velocity Verlet through the list.
+    fn stretch_after(bonds : &mut BondList, mut parts : Vec<Particle>, time : Scalar) -> Scalar {
+        let steps = 20_000;
+        let dt = time/steps as Scalar;
+        bonds.update(parts.clone());
+        for _ in 0..steps {
+            for (idx, part) in parts.iter_mut().enumerate() {
+                part.vel += 0.5 * dt * bonds.calculate_forces(idx)/part.mass;
+                part.pos += dt * part.vel;
+            }
+            bonds.update(parts.clone());
+            for (idx, part) in parts.iter_mut().enumerate() {
+                part.vel += 0.5 * dt * bonds.calculate_forces(idx)/part.mass;
+            }
+        }
+        (parts[1].pos - parts[0].pos).mag() - 1.0
+    }
+
+    #[test]
+    fn harmonic_bond_oscillates_with_the_reduced_mass_period() {
+        let mut bonds = BondList::new();
+        bonds.add_bond(Bond::new(0, 1, 1.0, 10.0));
+        // Reduced mass 1/2, so omega = sqrt(k/mu) = sqrt(20).
+        let period = 2.0 * PI/(20.0 as Scalar).sqrt();
+        assert!((stretch_after(&mut bonds.clone(), pair(0.1), 0.5 * period) + 0.1).abs() < 1e-6);
+        assert!((stretch_after(&mut bonds, pair(0.1), period) - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn bond_breaks_past_its_strain_for_good() {
+        let mut bonds = BondList::new();
+        bonds.add_bond(Bond::new(0, 1, 1.0, 10.0).with_max_strain(0.2));
+        bonds.update(pair(0.15));
+        assert_eq!(bonds.num_broken(), 0);
+        assert!(bonds.calculate_forces(0).x > 0.0);
+
+        bonds.update(pair(0.25));
+        assert_eq!(bonds.num_broken(), 1);
+        bonds.update(pair(0.0));
+        assert_eq!(bonds.num_broken(), 1);
+        bonds.update(pair(0.15));
+        assert_eq!(bonds.calculate_forces(0), Vec2d::zero());
+        assert_eq!(bonds.energy(&pair(0.15)), 0.0);
+    }
+
+    #[test]
+    fn angle_forces_are_minus_the_energy_gradient() {
+        let angle = AngleBond::new(0, 1, 2, 0.5 * PI, 3.0);
+        let parts = vec![
+            Particle::new(1.0, 0.1, Vec2d::new(1.2, 0.1), Vec2d::zero()),
+            Particle::new(1.0, 0.1, Vec2d::new(0.0, 0.0), Vec2d::zero()),
+            Particle::new(1.0, 0.1, Vec2d::new(0.3, 0.9), Vec2d::zero()),
+        ];
+        let (f_a, f_center, f_c) = angle.forces(&parts);
+        let eps = 1e-6;
+        for (idx, force) in [f_a, f_center, f_c].iter().enumerate() {
+            let gradient = |offset : Vec2d| {
+                let mut plus = parts.clone();
+                let mut minus = parts.clone();
+                plus[idx].pos += offset;
+                minus[idx].pos -= offset;
+                (angle.energy(&plus) - angle.energy(&minus))/(2.0 * eps)
+            };
+            let expected = -1.0 * Vec2d::new(gradient(Vec2d::new(eps, 0.0)), gradient(Vec2d::new(0.0, eps)));
+            assert!((*force - expected).mag() < 1e-6, "particle {}", idx);
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use crate::mathvec::{Scalar, Vec2d};
+use crate::particles::Particle;
+use crate::gridhandler::contact_law;
+use crate::binio::*;
+
+use std::io::{self, Read, Write};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum WallShape {
+    /// Infinite plane; particles are kept on the side `normal` points into.
+    Plane {
+        point : Vec2d,
+        normal : Vec2d,
+    },
+    Segment {
+        start : Vec2d,
+        end : Vec2d,
+    },
+    /// With `inside` set, particles are kept inside the circle (a drum);
+    /// otherwise the circle is a solid obstacle.
+    Circle {
+        center : Vec2d,
+        radius : Scalar,
+        inside : bool,
+    },
+    /// Container box; particles are kept inside.
+    Box {
+        plus_corner : Vec2d,
+        minus_corner : Vec2d,
+    },
+}
+
+/// A single contact: the outward unit normal (pointing from the wall into the
+/// allowed region), the signed distance of the particle centre along it, and
+/// the contact point on the wall.
+struct WallContact {
+    normal : Vec2d,
+    distance : Scalar,
+    point : Vec2d,
+}
+
+fn plane_contact(pos : Vec2d, point : Vec2d, normal : Vec2d) -> WallContact {
+    let distance = (pos - point).dot(normal);
+    WallContact {
+        normal,
+        distance,
+        point : pos - distance * normal,
+    }
+}
+
+impl WallShape {
+    pub fn write_to<W : Write>(&self, out : &mut W) -> io::Result<()> {
+        match *self {
+            WallShape::Plane { point, normal } => {
+                write_u8(out, 0)?;
+                write_vec2d(out, point)?;
+                write_vec2d(out, normal)
+            },
+            WallShape::Segment { start, end } => {
+                write_u8(out, 1)?;
+                write_vec2d(out, start)?;
+                write_vec2d(out, end)
+            },
+            WallShape::Circle { center, radius, inside } => {
+                write_u8(out, 2)?;
+                write_vec2d(out, center)?;
+                write_f64(out, radius)?;
+                write_bool(out, inside)
+            },
+            WallShape::Box { plus_corner, minus_corner } => {
+                write_u8(out, 3)?;
+                write_vec2d(out, plus_corner)?;
+                write_vec2d(out, minus_corner)
+            },
+        }
+    }
+    pub fn read_from<R : Read>(input : &mut R) -> io::Result<WallShape> {
+        Ok(match read_u8(input)? {
+            0 => WallShape::Plane { point : read_vec2d(input)?, normal : read_vec2d(input)? },
+            1 => WallShape::Segment { start : read_vec2d(input)?, end : read_vec2d(input)? },
+            2 => WallShape::Circle { center : read_vec2d(input)?, radius : read_f64(input)?, inside : read_bool(input)? },
+            3 => WallShape::Box { plus_corner : read_vec2d(input)?, minus_corner : read_vec2d(input)? },
+            _ => return Err(invalid_data("unknown wall shape")),
+        })
+    }
+
+    fn contacts(&self, pos : Vec2d, reach : Scalar) -> Vec<WallContact> {
+        let mut retval = Vec::new();
+        match *self {
+            WallShape::Plane { point, normal } => {
+                retval.push(plane_contact(pos, point, normal.unit()));
+            },
+            WallShape::Segment { start, end } => {
+                let seg = end - start;
+                let len_sq = seg.mag_squared();
+                let t = if len_sq > 0.0 { ((pos - start).dot(seg)/len_sq).clamp(0.0, 1.0) } else { 0.0 };
+                let point = start + t * seg;
+                let diff = pos - point;
+                let distance = diff.mag();
+                if distance > 0.0 {
+                    retval.push(WallContact { normal : diff/distance, distance, point });
+                }
+            },
+            WallShape::Circle { center, radius, inside } => {
+                let diff = pos - center;
+                let d = diff.mag();
+                if d > 0.0 {
+                    let dir = diff/d;
+                    let point = center + radius * dir;
+                    if inside {
+                        retval.push(WallContact { normal : -1.0 * dir, distance : radius - d, point });
+                    }
+                    else {
+                        retval.push(WallContact { normal : dir, distance : d - radius, point });
+                    }
+                }
+            },
+            WallShape::Box { plus_corner, minus_corner } => {
+                retval.push(plane_contact(pos, minus_corner, Vec2d::new(1.0, 0.0)));
+                retval.push(plane_contact(pos, minus_corner, Vec2d::new(0.0, 1.0)));
+                retval.push(plane_contact(pos, plus_corner, Vec2d::new(-1.0, 0.0)));
+                retval.push(plane_contact(pos, plus_corner, Vec2d::new(0.0, -1.0)));
+            },
+        }
+        retval.retain(|c| c.distance < reach);
+        retval
+    }
+}
+
+/// Static or moving collision geometry. The shape is stored as it was at
+/// creation; `position` and `angle` track how far the wall has translated
+/// and rotated about its pivot since then.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Wall {
+    shape : WallShape,
+    pivot : Vec2d,
+    position : Vec2d,
+    angle : Scalar,
+    velocity : Vec2d,
+    angular_velocity : Scalar,
+}
+
+impl Wall {
+    pub fn new(shape : WallShape) -> Wall {
+        Wall {
+            shape,
+            pivot : Vec2d::zero(),
+            position : Vec2d::zero(),
+            angle : 0.0,
+            velocity : Vec2d::zero(),
+            angular_velocity : 0.0,
+        }
+    }
+    pub fn plane(point : Vec2d, normal : Vec2d) -> Wall {
+        Wall::new(WallShape::Plane { point, normal })
+    }
+    pub fn segment(start : Vec2d, end : Vec2d) -> Wall {
+        Wall::new(WallShape::Segment { start, end })
+    }
+    pub fn circle(center : Vec2d, radius : Scalar) -> Wall {
+        Wall::new(WallShape::Circle { center, radius, inside : false })
+    }
+    pub fn drum(center : Vec2d, radius : Scalar) -> Wall {
+        Wall::new(WallShape::Circle { center, radius, inside : true })
+    }
+    pub fn bounding_box(plus_corner : Vec2d, minus_corner : Vec2d) -> Wall {
+        Wall::new(WallShape::Box { plus_corner, minus_corner })
+    }
+
+    pub fn with_velocity(self, velocity : Vec2d) -> Wall {
+        Wall {
+            velocity,
+            ..self
+        }
+    }
+    pub fn with_rotation(self, pivot : Vec2d, angular_velocity : Scalar) -> Wall {
+        Wall {
+            pivot,
+            position : pivot,
+            angular_velocity,
+            ..self
+        }
+    }
+
+    /// Includes how far the wall has moved, so a moving wall resumes where
+    /// it was.
+    pub fn write_to<W : Write>(&self, out : &mut W) -> io::Result<()> {
+        self.shape.write_to(out)?;
+        write_vec2d(out, self.pivot)?;
+        write_vec2d(out, self.position)?;
+        write_f64(out, self.angle)?;
+        write_vec2d(out, self.velocity)?;
+        write_f64(out, self.angular_velocity)
+    }
+    pub fn read_from<R : Read>(input : &mut R) -> io::Result<Wall> {
+        Ok(Wall {
+            shape : WallShape::read_from(input)?,
+            pivot : read_vec2d(input)?,
+            position : read_vec2d(input)?,
+            angle : read_f64(input)?,
+            velocity : read_vec2d(input)?,
+            angular_velocity : read_f64(input)?,
+        })
+    }
+
+    pub fn shape(&self) -> WallShape {
+        self.shape
+    }
+    pub fn angle(&self) -> Scalar {
+        self.angle
+    }
+    pub fn offset(&self) -> Vec2d {
+        self.position - self.pivot
+    }
+
+    fn to_local(self, pos : Vec2d) -> Vec2d {
+        self.pivot + (pos - self.position).rotate(-self.angle)
+    }
+    fn to_world(self, pos : Vec2d) -> Vec2d {
+        self.position + (pos - self.pivot).rotate(self.angle)
+    }
+
+    pub fn velocity_at(&self, pos : Vec2d) -> Vec2d {
+        self.velocity + self.angular_velocity * (pos - self.position).perp()
+    }
+
+    pub fn advance(&mut self, dt : Scalar) {
+        self.position += dt * self.velocity;
+        self.angle += dt * self.angular_velocity;
+    }
+
+    pub fn calculate_contact_force(&self, arg : Particle, K : Scalar, damping : Scalar) -> Vec2d {
+        let local = self.to_local(arg.pos);
+        let mut retval = Vec2d::zero();
+        for contact in self.shape.contacts(local, arg.radius) {
+            let direction = -1.0 * contact.normal.rotate(self.angle);
+            let overlap = arg.radius - contact.distance;
+            let relvel = self.velocity_at(self.to_world(contact.point)) - arg.vel;
+            retval += contact_law(direction, overlap, relvel, K, damping);
+        }
+        retval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Velocity Verlet against a single wall until the particle has left it.
+    fn bounce(wall : &Wall, mut part : Particle, damping : Scalar) -> Vec2d {
+        let dt = 1e-4;
+        let mut force = wall.calculate_contact_force(part, 1e4, damping);
+        for _ in 0..20_000 {
+            part.vel += 0.5 * dt * force/part.mass;
+            part.pos += dt * part.vel;
+            force = wall.calculate_contact_force(part, 1e4, damping);
+            part.vel += 0.5 * dt * force/part.mass;
+        }
+        part.vel
+    }
+
+    #[test]
+    fn elastic_wall_reflects_the_normal_velocity() {
+        let wall = Wall::plane(Vec2d::zero(), Vec2d::new(0.0, 1.0));
+        let part = Particle::new(1.0, 0.5, Vec2d::new(0.0, 1.0), Vec2d::new(0.3, -1.0));
+        let vel = bounce(&wall, part, 0.0);
+        assert!((vel - Vec2d::new(0.3, 1.0)).mag() < 1e-3, "{:?}", vel);
+
+        let damped = bounce(&wall, part, 10.0);
+        assert!(damped.y > 0.0 && damped.y < 0.99);
+        assert!((damped.x - 0.3).abs() < 1e-12);
+    }
+
+    #[test]
+    fn particle_co_rotating_with_a_drum_feels_no_damping() {
+        let center = Vec2d::new(1.0, 2.0);
+        let drum = Wall::drum(center, 5.0).with_rotation(center, 0.7);
+        let pos = center + Vec2d::new(30.0f64.to_radians().cos(), 30.0f64.to_radians().sin()) * 4.6;
+        let mut part = Particle::new(1.0, 0.5, pos, Vec2d::zero());
+        part.vel = drum.velocity_at(pos);
+        let force = drum.calculate_contact_force(part, 1000.0, 50.0);
+        let elastic = (center - pos)/4.6 * (1000.0 * 0.1);
+        assert!((force - elastic).mag() < 1e-9, "{:?}", force);
+    }
+
+    #[test]
+    fn rotating_box_carries_its_walls_around() {
+        let mut wall = Wall::bounding_box(Vec2d::new(3.0, 1.0), Vec2d::new(-3.0, -1.0))
+            .with_rotation(Vec2d::zero(), std::f64::consts::FRAC_PI_2);
+        let part = Particle::new(1.0, 0.2, Vec2d::new(0.0, 2.9), Vec2d::zero());
+        wall.advance(1.0);
+        let force = wall.calculate_contact_force(part, 1000.0, 10.0);
+        assert!((force - Vec2d::new(0.0, -100.0)).mag() < 1e-9, "{:?}", force);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::mathvec::{Scalar, Vec2d};
+use rust_nbody::mathvec::{Scalar, Vec2d, Vec3d};
 
 pub struct CameraController {
     
@@ -14,15 +14,16 @@ pub struct CameraController {
     scroll_drag : f64, 
     scroll_vel : f64, 
     scroll : f64,
-    scale : f64, 
     lastdt : f64, 
+
+    yaw : f64,
+    pitch : f64,
 }
 
 impl CameraController {
 
-    pub fn new(xpos : Scalar, ypos : Scalar, scroll : f64, aspect : f64) -> CameraController {
+    pub fn new(xpos : Scalar, ypos : Scalar, scroll : f64) -> CameraController {
         let camera_center = Vec2d::new(xpos, ypos);
-        let scale = scroll.exp();
         CameraController {
             vel_drag : 0.9,
             vel : Vec2d::zero(),
@@ -37,8 +38,10 @@ impl CameraController {
             scroll_drag : 0.87,
             scroll_vel : 0.0,
             scroll,
-            scale,
             lastdt : 0.0,
+
+            yaw : 0.0,
+            pitch : 0.0,
         }
     }
 
@@ -96,6 +99,24 @@ impl CameraController {
         self.mouse_zoom_pos = Vec2d::new(x, y);
     }
 
+    pub fn rotate_view(&mut self, dyaw : f64, dpitch : f64) {
+        let limit = std::f64::consts::FRAC_PI_2;
+        self.yaw += dyaw;
+        self.pitch = (self.pitch + dpitch).max(-limit).min(limit);
+    }
+
+    /// Orthographic projection of a 3D point onto the view plane: turn by
+    /// `yaw` about the z axis, then tilt by `pitch` about the screen x axis.
+    /// Returns the projected point and its depth along the view direction,
+    /// larger depths being nearer the viewer.
+    pub fn project(&self, pos : Vec3d) -> (Vec2d, Scalar) {
+        let turned = Vec2d::new(pos.x, pos.y).rotate(-self.yaw);
+        let (pitch_sin, pitch_cos) = self.pitch.sin_cos();
+        let y = turned.y * pitch_cos - pos.z * pitch_sin;
+        let depth = turned.y * pitch_sin + pos.z * pitch_cos;
+        (Vec2d::new(turned.x, y), depth)
+    }
+
     pub fn mouse_zoom(&mut self, zamount : f64) {
         self.scroll_vel += zamount * self.scroll_drag;
     }
@@ -0,0 +1,318 @@
+use crate::mathvec::{Scalar, Vec2d};
+use crate::particles::Particle;
+use crate::masstree::Span;
+use crate::binio::*;
+
+use std::io::{self, Read, Write};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ElectrostaticsParams {
+    pub coulomb_constant : Scalar,
+    pub opening_angle : Scalar,
+    pub softening : Scalar,
+}
+
+impl Default for ElectrostaticsParams {
+    fn default() -> ElectrostaticsParams {
+        ElectrostaticsParams {
+            coulomb_constant : 1.0,
+            opening_angle : 0.5,
+            softening : 0.0,
+        }
+    }
+}
+
+impl ElectrostaticsParams {
+    pub fn write_to<W : Write>(&self, out : &mut W) -> io::Result<()> {
+        write_f64(out, self.coulomb_constant)?;
+        write_f64(out, self.opening_angle)?;
+        write_f64(out, self.softening)
+    }
+    pub fn read_from<R : Read>(input : &mut R) -> io::Result<ElectrostaticsParams> {
+        Ok(ElectrostaticsParams {
+            coulomb_constant : read_f64(input)?,
+            opening_angle : read_f64(input)?,
+            softening : read_f64(input)?,
+        })
+    }
+}
+
+/// Monopole, dipole and quadrupole moments about `center`, which is the
+/// |q|-weighted centre of the node so that it stays well defined when the
+/// charges cancel. `qxx`, `qxy` and `qyy` are the in-plane part of the 3D
+/// traceless quadrupole sum q (3 d d^T - |d|^2 I) that goes with the 1/r
+/// potential; the zz entry -(qxx + qyy) is implied, so qxx + qyy itself is
+/// not zero.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+struct Moments {
+    center : Vec2d,
+    charge : Scalar,
+    abs_charge : Scalar,
+    dipole : Vec2d,
+    qxx : Scalar,
+    qxy : Scalar,
+    qyy : Scalar,
+}
+
+impl Moments {
+    fn point(pos : Vec2d, charge : Scalar) -> Moments {
+        Moments {
+            center : pos,
+            charge,
+            abs_charge : charge.abs(),
+            ..Moments::default()
+        }
+    }
+
+    fn combine(children : &[Moments]) -> Moments {
+        let abs_charge : Scalar = children.iter().map(|m| m.abs_charge).sum();
+        if abs_charge <= 0.0 {
+            return Moments::default();
+        }
+        let center = children.iter().map(|m| m.abs_charge * m.center).sum::<Vec2d>()/abs_charge;
+        let mut retval = Moments {
+            center,
+            abs_charge,
+            ..Moments::default()
+        };
+        for child in children {
+            if child.abs_charge <= 0.0 {
+                continue;
+            }
+            let d = child.center - center;
+            let p = child.dipole;
+            let pd = p.dot(d);
+            let d2 = d.mag_squared();
+            retval.charge += child.charge;
+            retval.dipole += p + child.charge * d;
+            retval.qxx += child.qxx + 6.0 * p.x * d.x - 2.0 * pd + child.charge * (3.0 * d.x * d.x - d2);
+            retval.qxy += child.qxy + 3.0 * (p.x * d.y + p.y * d.x) + child.charge * 3.0 * d.x * d.y;
+            retval.qyy += child.qyy + 6.0 * p.y * d.y - 2.0 * pd + child.charge * (3.0 * d.y * d.y - d2);
+        }
+        retval
+    }
+
+    /// Field of the expansion at `pos`, per unit Coulomb constant.
+    fn field(&self, pos : Vec2d) -> Vec2d {
+        let r = pos - self.center;
+        let r2 = r.mag_squared();
+        let d = r2.sqrt();
+        let inv_r3 = 1.0/(r2 * d);
+        let inv_r5 = inv_r3/r2;
+        let inv_r7 = inv_r5/r2;
+
+        let monopole = self.charge * inv_r3 * r;
+        let dipole = 3.0 * self.dipole.dot(r) * inv_r5 * r - self.dipole * inv_r3;
+        let qr = Vec2d::new(self.qxx * r.x + self.qxy * r.y, self.qxy * r.x + self.qyy * r.y);
+        let rqr = r.dot(qr);
+        let quadrupole = 2.5 * rqr * inv_r7 * r - qr * inv_r5;
+        monopole + dipole + quadrupole
+    }
+}
+
+#[derive(Copy, Clone)]
+enum NodeType {
+    Empty,
+    Leaf(Moments),
+    Branch {
+        moments : Moments,
+        subnodes : [u32 ; 4],
+    }
+}
+
+#[derive(Copy, Clone)]
+struct ChargeNode {
+    span : Span,
+    data : NodeType,
+}
+
+impl ChargeNode {
+    fn moments(&self) -> Moments {
+        match self.data {
+            NodeType::Empty => Moments::default(),
+            NodeType::Leaf(m) => m,
+            NodeType::Branch{ moments, .. } => moments,
+        }
+    }
+}
+
+/// Quadtree over the charged particles for signed-charge (Coulomb) forces.
+pub struct ChargeTree {
+    params : ElectrostaticsParams,
+    nodes : Vec<ChargeNode>,
+}
+
+impl ChargeTree {
+    pub fn new(params : ElectrostaticsParams) -> ChargeTree {
+        ChargeTree {
+            params,
+            nodes : Vec::new(),
+        }
+    }
+
+    pub fn params(&self) -> ElectrostaticsParams {
+        self.params
+    }
+
+    pub fn build(params : ElectrostaticsParams, parts : &[Particle]) -> ChargeTree {
+        let mut retval = ChargeTree::new(params);
+        let mut charged = parts.iter().filter(|p| p.charge != 0.0);
+        let first = match charged.next() {
+            Some(p) => p.pos,
+            None => { return retval; }
+        };
+        let (mut min, mut max) = (first, first);
+        for part in parts.iter().filter(|p| p.charge != 0.0) {
+            min = Vec2d::new(min.x.min(part.pos.x), min.y.min(part.pos.y));
+            max = Vec2d::new(max.x.max(part.pos.x), max.y.max(part.pos.y));
+        }
+        let half = 0.5 * (max.x - min.x).max(max.y - min.y) + 1.0;
+        let mid = 0.5 * (min + max);
+        let span = Span::new(mid + Vec2d::new(half, half), mid - Vec2d::new(half, half));
+        retval.nodes.push(ChargeNode { span, data : NodeType::Empty });
+
+        for part in parts.iter().filter(|p| p.charge != 0.0) {
+            retval.add_charge(part.pos, part.charge);
+        }
+        retval.compute_moments();
+        retval
+    }
+
+    fn add_charge(&mut self, pos : Vec2d, charge : Scalar) {
+        let mut cur_idx = 0;
+        loop {
+            let cur_node = self.nodes[cur_idx];
+            match cur_node.data {
+                NodeType::Empty => {
+                    self.nodes[cur_idx].data = NodeType::Leaf(Moments::point(pos, charge));
+                    return;
+                },
+                NodeType::Leaf(m) => {
+                    if m.center == pos || cur_node.span.width() < Vec2d::EPSILON {
+                        let merged = Moments::combine(&[m, Moments::point(pos, charge)]);
+                        self.nodes[cur_idx].data = NodeType::Leaf(merged);
+                        return;
+                    }
+                    let start_len = self.nodes.len();
+                    let spans = cur_node.span.subspans();
+                    let mut subnodes = [0 ; 4];
+                    for offset in 0..4 {
+                        self.nodes.push(ChargeNode { span : spans[offset], data : NodeType::Empty });
+                        subnodes[offset] = (start_len + offset) as u32;
+                    }
+                    let (x_idx, y_idx) = cur_node.span.subspan_idx_for_pos(m.center);
+                    self.nodes[subnodes[x_idx * 2 + y_idx] as usize].data = NodeType::Leaf(m);
+                    self.nodes[cur_idx].data = NodeType::Branch { moments : Moments::default(), subnodes };
+                },
+                NodeType::Branch{ subnodes, .. } => {
+                    let (x_idx, y_idx) = cur_node.span.subspan_idx_for_pos(pos);
+                    cur_idx = subnodes[x_idx * 2 + y_idx] as usize;
+                }
+            }
+        }
+    }
+
+    fn compute_moments(&mut self) {
+        // Children are always pushed after their parent, so a reverse sweep
+        // sees every child before the node that owns it.
+        for idx in (0..self.nodes.len()).rev() {
+            if let NodeType::Branch{ subnodes, .. } = self.nodes[idx].data {
+                let children = [
+                    self.nodes[subnodes[0] as usize].moments(),
+                    self.nodes[subnodes[1] as usize].moments(),
+                    self.nodes[subnodes[2] as usize].moments(),
+                    self.nodes[subnodes[3] as usize].moments(),
+                ];
+                self.nodes[idx].data = NodeType::Branch { moments : Moments::combine(&children), subnodes };
+            }
+        }
+    }
+
+    /// Electric field at `pos`, excluding any charge sitting exactly on it.
+    pub fn calculate_field(&self, pos : Vec2d) -> Vec2d {
+        let mut retval = Vec2d::zero();
+        if self.nodes.is_empty() {
+            return retval;
+        }
+        let eps2 = self.params.softening * self.params.softening;
+        let mut to_calc : Vec<u32> = Vec::with_capacity(4);
+        to_calc.push(0);
+        while let Some(cur_idx) = to_calc.pop() {
+            let cur_node = &self.nodes[cur_idx as usize];
+            match cur_node.data {
+                NodeType::Branch{ moments, subnodes } => {
+                    if moments.abs_charge <= 0.0 {
+                        continue;
+                    }
+                    // Opening distance grows with the offset between the
+                    // expansion centre and the cell centre (Barnes 1994), which
+                    // keeps the error bounded when the charges cancel.
+                    let offset = (moments.center - cur_node.span.midpoint()).mag();
+                    let open_dist = cur_node.span.width()/self.params.opening_angle + offset;
+                    if (pos - moments.center).mag_squared() < open_dist * open_dist {
+                        to_calc.extend_from_slice(&subnodes);
+                    }
+                    else {
+                        retval += moments.field(pos);
+                    }
+                },
+                NodeType::Leaf(moments) => {
+                    let diff = pos - moments.center;
+                    let d2 = diff.mag_squared();
+                    if d2 < 0.00001 {
+                        continue;
+                    }
+                    let d2 = d2 + eps2;
+                    retval += diff * (moments.charge/(d2 * d2.sqrt()));
+                },
+                NodeType::Empty => {},
+            }
+        }
+        self.params.coulomb_constant * retval
+    }
+
+    pub fn calculate_forces(&self, arg : Particle) -> Vec2d {
+        if arg.charge == 0.0 {
+            return Vec2d::zero();
+        }
+        arg.charge * self.calculate_field(arg.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn direct_field(parts : &[Particle], pos : Vec2d) -> Vec2d {
+        parts.iter()
+            .map(|part| {
+                let diff = pos - part.pos;
+                diff * (part.charge/(diff.mag_squared() * diff.mag()))
+            })
+            .sum()
+    }
+
+    fn relative_error(parts : &[Particle], pos : Vec2d) -> Scalar {
+        let tree = ChargeTree::build(ElectrostaticsParams::default(), parts);
+        let expected = direct_field(parts, pos);
+        (tree.calculate_field(pos) - expected).mag()/expected.mag()
+    }
+
+    fn charge(x : Scalar, y : Scalar, charge : Scalar) -> Particle {
+        Particle::new(1.0, 0.0, Vec2d::new(x, y), Vec2d::zero()).with_charge(charge)
+    }
+
+    #[test]
+    fn far_field_matches_direct_sum() {
+        // Neutral and without a dipole, so the quadrupole is the leading term.
+        let quadrupole = [charge(0.5, 0.0, 1.0), charge(-0.5, 0.0, 1.0), charge(0.0, 0.5, -1.0), charge(0.0, -0.5, -1.0)];
+        assert!(relative_error(&quadrupole, Vec2d::new(30.0, 7.0)) < 1e-3);
+
+        // Uneven charges over several tree levels, merged with shifted moments.
+        let cluster = [
+            charge(0.1, 0.2, 2.0), charge(-0.7, 0.4, -1.0), charge(0.6, -0.3, 0.5),
+            charge(-0.2, -0.8, -1.5), charge(0.9, 0.9, 1.0), charge(0.65, -0.35, -0.25),
+        ];
+        assert!(relative_error(&cluster, Vec2d::new(-25.0, 31.0)) < 1e-3);
+    }
+}
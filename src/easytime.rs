@@ -1,4 +1,4 @@
-use std::time::{Instant, Duration};
+use std::time::Instant;
 
 pub const NANOS_TO_SECS : f64 = 1_000_000_000.0;
 pub struct EasyTimer {
@@ -14,9 +14,9 @@ impl EasyTimer {
 
     pub fn tick(&mut self) -> f64 {
         let ntimer = Instant::now();
-        let dur = ntimer.duration_since(ntimer);
+        let dur = ntimer.duration_since(self.timer);
         self.timer = ntimer;
-        ((dur.as_nanos() as f64)/NANOS_TO_SECS)
+        (dur.as_nanos() as f64)/NANOS_TO_SECS
     }
 }
 
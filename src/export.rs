@@ -0,0 +1,228 @@
+use crate::mathvec::{Scalar, Float};
+use crate::particles::{Particle, GasState};
+use crate::physics_handler::PhysicsHandler;
+use crate::easytime::PhysicsHandlerThreadedTiming;
+
+use std::io::{self, Write};
+
+//Tabular output for analysis tools. Every row goes straight to the writer,
+//so wrap files in a `BufWriter` and nothing grows with N.
+
+const PARTICLE_COLUMNS : [&str; 15] = [
+    "id", "species", "x", "y", "vx", "vy", "mass", "radius", "charge", "fx", "fy",
+    "internal_energy", "smoothing_length", "density", "pressure",
+];
+
+const DIAGNOSTIC_COLUMNS : [&str; 16] = [
+    "step", "time", "kinetic_energy", "potential_energy", "contact_energy", "total_energy",
+    "virial_ratio", "momentum_x", "momentum_y", "angular_momentum", "center_of_mass_x", "center_of_mass_y",
+    "real_time", "time_stepping", "max_thread_time", "min_thread_time",
+];
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TableFormat {
+    /// Comma separated with a header row; missing values are empty.
+    Csv,
+    /// One JSON object per line; missing and non-finite values are `null`.
+    JsonLines,
+}
+
+/// A row value, so the two formats can share the column lists.
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Cell {
+    Int(u64),
+    Float(Scalar),
+    Missing,
+}
+
+fn write_row<W : Write>(out : &mut W, format : TableFormat, names : &[&str], cells : &[Cell]) -> io::Result<()> {
+    match format {
+        TableFormat::Csv => {
+            for (idx, cell) in cells.iter().enumerate() {
+                if idx > 0 {
+                    out.write_all(b",")?;
+                }
+                match *cell {
+                    Cell::Int(val) => write!(out, "{}", val)?,
+                    Cell::Float(val) => write!(out, "{:?}", val)?,
+                    Cell::Missing => {},
+                }
+            }
+        },
+        TableFormat::JsonLines => {
+            out.write_all(b"{")?;
+            for (idx, (name, cell)) in names.iter().zip(cells.iter()).enumerate() {
+                if idx > 0 {
+                    out.write_all(b",")?;
+                }
+                write!(out, "\"{}\":", name)?;
+                match *cell {
+                    Cell::Int(val) => write!(out, "{}", val)?,
+                    Cell::Float(val) if val.is_finite() => write!(out, "{:?}", val)?,
+                    _ => out.write_all(b"null")?,
+                }
+            }
+            out.write_all(b"}")?;
+        },
+    }
+    out.write_all(b"\n")
+}
+
+fn write_header<W : Write>(out : &mut W, format : TableFormat, names : &[&str]) -> io::Result<()> {
+    if format == TableFormat::Csv {
+        writeln!(out, "{}", names.join(","))?;
+    }
+    Ok(())
+}
+
+fn particle_cells(part : &Particle) -> [Cell; 15] {
+    let force = part.force();
+    let gas = |val : fn(&GasState) -> Scalar| part.gas.as_ref().map_or(Cell::Missing, |gas| Cell::Float(val(gas)));
+    [
+        Cell::Int(part.id),
+        Cell::Int(part.species as u64),
+        Cell::Float(part.pos.x),
+        Cell::Float(part.pos.y),
+        Cell::Float(part.vel.x),
+        Cell::Float(part.vel.y),
+        Cell::Float(part.mass),
+        Cell::Float(part.radius),
+        Cell::Float(part.charge),
+        Cell::Float(force.x),
+        Cell::Float(force.y),
+        gas(|gas| gas.internal_energy),
+        gas(|gas| gas.smoothing_length),
+        gas(|gas| gas.density),
+        gas(|gas| gas.pressure),
+    ]
+}
+
+/// Writes one row per particle, with a header row for CSV. Gas columns are
+/// missing for particles without gas state.
+pub fn write_particles<W : Write, P : Float, F : Float, T : IntoIterator<Item=Particle<P, F>>>(out : &mut W, format : TableFormat, data : T) -> io::Result<()> {
+    write_header(out, format, &PARTICLE_COLUMNS)?;
+    for part in data.into_iter() {
+        write_row(out, format, &PARTICLE_COLUMNS, &particle_cells(&part.cast()))?;
+    }
+    Ok(())
+}
+
+/// Appends one row of energies, momenta and, when given, wall clock timing
+/// per `record` call, so a run can log its diagnostics as it goes.
+pub struct DiagnosticsLog<W : Write> {
+    out : W,
+    format : TableFormat,
+    header_written : bool,
+}
+
+impl<W : Write> DiagnosticsLog<W> {
+    pub fn new(out : W, format : TableFormat) -> DiagnosticsLog<W> {
+        DiagnosticsLog {
+            out,
+            format,
+            header_written : false,
+        }
+    }
+
+    pub fn record<H : PhysicsHandler>(&mut self, step : usize, time : Scalar, handler : &H, timing : Option<PhysicsHandlerThreadedTiming>) -> io::Result<()> {
+        if !self.header_written {
+            write_header(&mut self.out, self.format, &DIAGNOSTIC_COLUMNS)?;
+            self.header_written = true;
+        }
+        let (kinetic, potential, contact) = (handler.kinetic_energy(), handler.potential_energy(), handler.contact_energy());
+        let momentum = handler.total_momentum();
+        let center_of_mass = handler.total_mass_pos()/handler.total_mass();
+        let timed = |val : fn(&PhysicsHandlerThreadedTiming) -> f64| timing.as_ref().map_or(Cell::Missing, |timing| Cell::Float(val(timing)));
+        let cells = [
+            Cell::Int(step as u64),
+            Cell::Float(time),
+            Cell::Float(kinetic),
+            Cell::Float(potential),
+            Cell::Float(contact),
+            Cell::Float(kinetic + potential + contact + handler.extra_energy()),
+            Cell::Float(handler.virial_ratio()),
+            Cell::Float(momentum.x),
+            Cell::Float(momentum.y),
+            Cell::Float(handler.angular_momentum()),
+            Cell::Float(center_of_mass.x),
+            Cell::Float(center_of_mass.y),
+            timed(|timing| timing.real_time),
+            timed(|timing| timing.time_stepping),
+            timed(|timing| timing.max_thread_time),
+            timed(|timing| timing.min_thread_time),
+        ];
+        write_row(&mut self.out, self.format, &DIAGNOSTIC_COLUMNS, &cells)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mathvec::Vec2d;
+    use crate::physics_handler::threaded::PhysicsHandlerThreaded;
+
+    fn particles() -> Vec<Particle> {
+        let mut solid = Particle::new(1.0, 0.5, Vec2d::new(1.0, 2.0), Vec2d::new(0.0, -1.0)).with_species(2);
+        solid.id = 3;
+        solid.charge = Scalar::NAN;
+        let mut gas = Particle::new_gas(2.0, Vec2d::new(0.0, 0.0), Vec2d::new(0.5, 0.0), 1.5, 0.25);
+        gas.id = 4;
+        gas.f_grav = Vec2d::new(0.125, Scalar::INFINITY);
+        vec![solid, gas]
+    }
+
+    fn written(format : TableFormat) -> String {
+        let mut out = Vec::new();
+        write_particles(&mut out, format, particles()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn particles_as_csv() {
+        assert_eq!(written(TableFormat::Csv), "\
+id,species,x,y,vx,vy,mass,radius,charge,fx,fy,internal_energy,smoothing_length,density,pressure
+3,2,1.0,2.0,0.0,-1.0,1.0,0.5,NaN,0.0,0.0,,,,
+4,0,0.0,0.0,0.5,0.0,2.0,0.0,0.0,0.125,inf,1.5,0.25,0.0,0.0
+");
+    }
+
+    #[test]
+    fn particles_as_json_lines() {
+        assert_eq!(written(TableFormat::JsonLines), "\
+{\"id\":3,\"species\":2,\"x\":1.0,\"y\":2.0,\"vx\":0.0,\"vy\":-1.0,\"mass\":1.0,\"radius\":0.5,\"charge\":null,\"fx\":0.0,\"fy\":0.0,\
+\"internal_energy\":null,\"smoothing_length\":null,\"density\":null,\"pressure\":null}
+{\"id\":4,\"species\":0,\"x\":0.0,\"y\":0.0,\"vx\":0.5,\"vy\":0.0,\"mass\":2.0,\"radius\":0.0,\"charge\":0.0,\"fx\":0.125,\"fy\":null,\
+\"internal_energy\":1.5,\"smoothing_length\":0.25,\"density\":0.0,\"pressure\":0.0}
+");
+    }
+
+    #[test]
+    fn diagnostics_header_is_written_once() {
+        let mut handler : PhysicsHandlerThreaded = PhysicsHandlerThreaded::new(1.0, 1000.0, 10.0);
+        handler.load_particles(vec![
+            Particle::new(1.0, 0.5, Vec2d::new(-1.0, 0.0), Vec2d::new(0.0, -0.5)),
+            Particle::new(1.0, 0.5, Vec2d::new(1.0, 0.5), Vec2d::new(0.0, 0.5)),
+        ]);
+        let mut log = DiagnosticsLog::new(Vec::new(), TableFormat::Csv);
+        log.record(0, 0.0, &handler, None).unwrap();
+        log.record(1, 0.01, &handler, None).unwrap();
+        let text = String::from_utf8(log.into_inner()).unwrap();
+        let lines = text.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], DIAGNOSTIC_COLUMNS.join(","));
+        let row = lines[2].split(',').collect::<Vec<&str>>();
+        assert_eq!(row.len(), DIAGNOSTIC_COLUMNS.len());
+        assert_eq!(&row[..2], &["1", "0.01"]);
+        assert_eq!(row[5].parse::<Scalar>().unwrap(), handler.total_energy());
+        assert_eq!(row[6].parse::<Scalar>().unwrap(), handler.virial_ratio());
+        assert!(row[12..].iter().all(|cell| cell.is_empty()));
+    }
+}
@@ -0,0 +1,203 @@
+use crate::mathvec::{Scalar, Vec2d};
+use crate::binio::*;
+
+use std::io::{self, Read, Write};
+
+/// Fixed analytic potentials felt by every particle. The halo profiles are the
+/// usual spherical ones, evaluated in the simulation plane.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ExternalPotential {
+    PointMass {
+        center : Vec2d,
+        mass : Scalar,
+        softening : Scalar,
+    },
+    UniformField {
+        accel : Vec2d,
+    },
+    Plummer {
+        center : Vec2d,
+        mass : Scalar,
+        scale : Scalar,
+    },
+    Hernquist {
+        center : Vec2d,
+        mass : Scalar,
+        scale : Scalar,
+    },
+    /// `mass` is the characteristic mass `4 pi rho_0 r_s^3`.
+    Nfw {
+        center : Vec2d,
+        mass : Scalar,
+        scale : Scalar,
+    },
+    Logarithmic {
+        center : Vec2d,
+        v0 : Scalar,
+        core : Scalar,
+    },
+}
+
+impl ExternalPotential {
+    pub fn point_mass(center : Vec2d, mass : Scalar) -> ExternalPotential {
+        ExternalPotential::PointMass { center, mass, softening : 0.0 }
+    }
+    pub fn uniform_field(accel : Vec2d) -> ExternalPotential {
+        ExternalPotential::UniformField { accel }
+    }
+    pub fn plummer(center : Vec2d, mass : Scalar, scale : Scalar) -> ExternalPotential {
+        ExternalPotential::Plummer { center, mass, scale }
+    }
+    pub fn hernquist(center : Vec2d, mass : Scalar, scale : Scalar) -> ExternalPotential {
+        ExternalPotential::Hernquist { center, mass, scale }
+    }
+    pub fn nfw(center : Vec2d, mass : Scalar, scale : Scalar) -> ExternalPotential {
+        ExternalPotential::Nfw { center, mass, scale }
+    }
+    pub fn logarithmic(center : Vec2d, v0 : Scalar, core : Scalar) -> ExternalPotential {
+        ExternalPotential::Logarithmic { center, v0, core }
+    }
+
+    pub fn write_to<W : Write>(&self, out : &mut W) -> io::Result<()> {
+        let (tag, center, a, b) = match *self {
+            ExternalPotential::PointMass { center, mass, softening } => (0, center, mass, softening),
+            ExternalPotential::UniformField { accel } => (1, accel, 0.0, 0.0),
+            ExternalPotential::Plummer { center, mass, scale } => (2, center, mass, scale),
+            ExternalPotential::Hernquist { center, mass, scale } => (3, center, mass, scale),
+            ExternalPotential::Nfw { center, mass, scale } => (4, center, mass, scale),
+            ExternalPotential::Logarithmic { center, v0, core } => (5, center, v0, core),
+        };
+        write_u8(out, tag)?;
+        write_vec2d(out, center)?;
+        write_f64(out, a)?;
+        write_f64(out, b)
+    }
+    pub fn read_from<R : Read>(input : &mut R) -> io::Result<ExternalPotential> {
+        let tag = read_u8(input)?;
+        let center = read_vec2d(input)?;
+        let a = read_f64(input)?;
+        let b = read_f64(input)?;
+        Ok(match tag {
+            0 => ExternalPotential::PointMass { center, mass : a, softening : b },
+            1 => ExternalPotential::UniformField { accel : center },
+            2 => ExternalPotential::Plummer { center, mass : a, scale : b },
+            3 => ExternalPotential::Hernquist { center, mass : a, scale : b },
+            4 => ExternalPotential::Nfw { center, mass : a, scale : b },
+            5 => ExternalPotential::Logarithmic { center, v0 : a, core : b },
+            _ => return Err(invalid_data("unknown external potential")),
+        })
+    }
+
+    pub fn acceleration(&self, pos : Vec2d, G : Scalar) -> Vec2d {
+        match *self {
+            ExternalPotential::PointMass { center, mass, softening } => {
+                let diff = pos - center;
+                let d2 = diff.mag_squared() + softening * softening;
+                if d2 <= 0.0 {
+                    return Vec2d::zero();
+                }
+                diff * (-G * mass/(d2 * d2.sqrt()))
+            },
+            ExternalPotential::UniformField { accel } => accel,
+            ExternalPotential::Plummer { center, mass, scale } => {
+                let diff = pos - center;
+                let d2 = diff.mag_squared() + scale * scale;
+                diff * (-G * mass/(d2 * d2.sqrt()))
+            },
+            ExternalPotential::Hernquist { center, mass, scale } => {
+                let diff = pos - center;
+                let r = diff.mag();
+                if r <= 0.0 {
+                    return Vec2d::zero();
+                }
+                diff * (-G * mass/(r * (r + scale) * (r + scale)))
+            },
+            ExternalPotential::Nfw { center, mass, scale } => {
+                let diff = pos - center;
+                let r = diff.mag();
+                if r <= 0.0 {
+                    return Vec2d::zero();
+                }
+                let x = r/scale;
+                let enclosed = (1.0 + x).ln() - x/(1.0 + x);
+                diff * (-G * mass * enclosed/(r * r * r))
+            },
+            ExternalPotential::Logarithmic { center, v0, core } => {
+                let diff = pos - center;
+                diff * (-v0 * v0/(diff.mag_squared() + core * core))
+            },
+        }
+    }
+
+    pub fn potential(&self, pos : Vec2d, G : Scalar) -> Scalar {
+        match *self {
+            ExternalPotential::PointMass { center, mass, softening } => {
+                let d = ((pos - center).mag_squared() + softening * softening).sqrt();
+                if d <= 0.0 {
+                    return 0.0;
+                }
+                -G * mass/d
+            },
+            ExternalPotential::UniformField { accel } => -accel.dot(pos),
+            ExternalPotential::Plummer { center, mass, scale } => {
+                -G * mass/((pos - center).mag_squared() + scale * scale).sqrt()
+            },
+            ExternalPotential::Hernquist { center, mass, scale } => {
+                -G * mass/((pos - center).mag() + scale)
+            },
+            ExternalPotential::Nfw { center, mass, scale } => {
+                let r = (pos - center).mag();
+                if r <= 0.0 {
+                    return -G * mass/scale;
+                }
+                -G * mass * (1.0 + r/scale).ln()/r
+            },
+            ExternalPotential::Logarithmic { center, v0, core } => {
+                0.5 * v0 * v0 * ((pos - center).mag_squared() + core * core).ln()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profiles() -> Vec<ExternalPotential> {
+        let center = Vec2d::new(0.3, -0.2);
+        vec![
+            ExternalPotential::PointMass { center, mass : 2.0, softening : 0.1 },
+            ExternalPotential::uniform_field(Vec2d::new(0.5, -1.5)),
+            ExternalPotential::plummer(center, 3.0, 0.8),
+            ExternalPotential::hernquist(center, 3.0, 0.8),
+            ExternalPotential::nfw(center, 3.0, 0.8),
+            ExternalPotential::logarithmic(center, 1.2, 0.4),
+        ]
+    }
+
+    #[test]
+    fn acceleration_is_minus_the_potential_gradient() {
+        let (G, eps) = (1.5, 1e-6);
+        for potential in profiles() {
+            for &pos in [Vec2d::new(1.0, 0.5), Vec2d::new(-2.0, 3.0), Vec2d::new(0.35, -0.1)].iter() {
+                let dx = Vec2d::new(eps, 0.0);
+                let dy = Vec2d::new(0.0, eps);
+                let gradient = Vec2d::new(
+                    potential.potential(pos + dx, G) - potential.potential(pos - dx, G),
+                    potential.potential(pos + dy, G) - potential.potential(pos - dy, G),
+                )/(2.0 * eps);
+                let error = (potential.acceleration(pos, G) + gradient).mag();
+                assert!(error < 1e-6 * gradient.mag().max(1.0), "{:?} at {:?} off by {}", potential, pos, error);
+            }
+        }
+    }
+
+    #[test]
+    fn every_profile_round_trips() {
+        for potential in profiles() {
+            let mut buffer = Vec::new();
+            potential.write_to(&mut buffer).unwrap();
+            assert_eq!(ExternalPotential::read_from(&mut buffer.as_slice()).unwrap(), potential);
+        }
+    }
+}
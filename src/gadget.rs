@@ -0,0 +1,503 @@
+use crate::mathvec::{Scalar, Vec2d};
+use crate::particles::{Particle, GasState};
+use crate::binio::*;
+
+use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::fs::File;
+use std::path::Path;
+
+const NUM_TYPES : usize = 6;
+const HEADER_SIZE : usize = 256;
+/// Bytes taken by the named fields of `GadgetHeader`; the rest is `fill`.
+const HEADER_USED : usize = 6 * 4 + 6 * 8 + 2 * 8 + 2 * 4 + 6 * 4 + 2 * 4 + 4 * 8 + 2 * 4 + 6 * 4 + 4;
+
+/// GADGET-2 block layout: format 1 is a bare sequence of Fortran records,
+/// format 2 puts a labelled record (`"POS "` etc.) in front of each block.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GadgetFormat {
+    Format1,
+    Format2,
+}
+
+/// The 256 byte GADGET-2 header, including the trailing fill bytes, so the
+/// fields we don't use survive a read and write. `GadgetSnapshot::write_to`
+/// recomputes `npart`, `npart_total` and `mass` from the particles and
+/// clears `npart_total_high_word`; everything else is written as read.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct GadgetHeader {
+    pub npart : [u32; NUM_TYPES],
+    pub mass : [f64; NUM_TYPES],
+    pub time : f64,
+    pub redshift : f64,
+    pub flag_sfr : i32,
+    pub flag_feedback : i32,
+    pub npart_total : [u32; NUM_TYPES],
+    pub flag_cooling : i32,
+    pub num_files : i32,
+    pub box_size : f64,
+    pub omega0 : f64,
+    pub omega_lambda : f64,
+    pub hubble_param : f64,
+    pub flag_stellarage : i32,
+    pub flag_metals : i32,
+    pub npart_total_high_word : [u32; NUM_TYPES],
+    pub flag_entropy_instead_u : i32,
+    pub fill : [u8; HEADER_SIZE - HEADER_USED],
+}
+
+impl Default for GadgetHeader {
+    fn default() -> GadgetHeader {
+        GadgetHeader {
+            npart : [0; NUM_TYPES],
+            mass : [0.0; NUM_TYPES],
+            time : 0.0,
+            redshift : 0.0,
+            flag_sfr : 0,
+            flag_feedback : 0,
+            npart_total : [0; NUM_TYPES],
+            flag_cooling : 0,
+            num_files : 0,
+            box_size : 0.0,
+            omega0 : 0.0,
+            omega_lambda : 0.0,
+            hubble_param : 0.0,
+            flag_stellarage : 0,
+            flag_metals : 0,
+            npart_total_high_word : [0; NUM_TYPES],
+            flag_entropy_instead_u : 0,
+            fill : [0; HEADER_SIZE - HEADER_USED],
+        }
+    }
+}
+
+impl GadgetHeader {
+    fn write_to<W : Write>(&self, out : &mut W) -> io::Result<()> {
+        for &count in self.npart.iter() {
+            write_u32(out, count)?;
+        }
+        for &mass in self.mass.iter() {
+            write_f64(out, mass)?;
+        }
+        write_f64(out, self.time)?;
+        write_f64(out, self.redshift)?;
+        write_u32(out, self.flag_sfr as u32)?;
+        write_u32(out, self.flag_feedback as u32)?;
+        for &count in self.npart_total.iter() {
+            write_u32(out, count)?;
+        }
+        write_u32(out, self.flag_cooling as u32)?;
+        write_u32(out, self.num_files as u32)?;
+        write_f64(out, self.box_size)?;
+        write_f64(out, self.omega0)?;
+        write_f64(out, self.omega_lambda)?;
+        write_f64(out, self.hubble_param)?;
+        write_u32(out, self.flag_stellarage as u32)?;
+        write_u32(out, self.flag_metals as u32)?;
+        for &count in self.npart_total_high_word.iter() {
+            write_u32(out, count)?;
+        }
+        write_u32(out, self.flag_entropy_instead_u as u32)?;
+        out.write_all(&self.fill)
+    }
+
+    fn read_from<R : Read>(input : &mut R) -> io::Result<GadgetHeader> {
+        let mut retval = GadgetHeader::default();
+        for count in retval.npart.iter_mut() {
+            *count = read_u32(input)?;
+        }
+        for mass in retval.mass.iter_mut() {
+            *mass = read_f64(input)?;
+        }
+        retval.time = read_f64(input)?;
+        retval.redshift = read_f64(input)?;
+        retval.flag_sfr = read_u32(input)? as i32;
+        retval.flag_feedback = read_u32(input)? as i32;
+        for count in retval.npart_total.iter_mut() {
+            *count = read_u32(input)?;
+        }
+        retval.flag_cooling = read_u32(input)? as i32;
+        retval.num_files = read_u32(input)? as i32;
+        retval.box_size = read_f64(input)?;
+        retval.omega0 = read_f64(input)?;
+        retval.omega_lambda = read_f64(input)?;
+        retval.hubble_param = read_f64(input)?;
+        retval.flag_stellarage = read_u32(input)? as i32;
+        retval.flag_metals = read_u32(input)? as i32;
+        for count in retval.npart_total_high_word.iter_mut() {
+            *count = read_u32(input)?;
+        }
+        retval.flag_entropy_instead_u = read_u32(input)? as i32;
+        input.read_exact(&mut retval.fill)?;
+        Ok(retval)
+    }
+
+    fn num_particles(&self) -> usize {
+        self.npart.iter().map(|&count| count as usize).sum()
+    }
+
+    /// Particles of the types whose mass is not fixed in the header.
+    fn num_with_mass(&self) -> usize {
+        self.npart.iter().zip(self.mass.iter())
+            .filter(|&(_, &mass)| mass == 0.0)
+            .map(|(&count, _)| count as usize)
+            .sum()
+    }
+}
+
+/// Particles exchanged with codes using GADGET-2 snapshots. GADGET particle
+/// types 0 to 5 map to species tags of the same number, type 0 being SPH gas.
+/// Positions and velocities gain z = 0 on export and drop z on import; the
+/// GADGET smoothing length is the kernel support, twice our `h`. GADGET has
+/// no radii, so imported particles have radius 0.
+///
+/// Only little endian files are handled, and a snapshot split over several
+/// files has to be read one file at a time.
+#[derive(Clone, PartialEq, Debug)]
+pub struct GadgetSnapshot {
+    pub header : GadgetHeader,
+    pub particles : Vec<Particle>,
+}
+
+fn write_record<W : Write>(out : &mut W, data : &[u8]) -> io::Result<()> {
+    write_u32(out, data.len() as u32)?;
+    out.write_all(data)?;
+    write_u32(out, data.len() as u32)
+}
+
+/// The payload of the next Fortran record, or `None` at the end of the file.
+/// Records longer than `max_len` are rejected, and the payload only grows as
+/// its bytes arrive, so a corrupt marker can't force a huge allocation.
+fn read_record<R : Read>(input : &mut R, max_len : usize) -> io::Result<Option<Vec<u8>>> {
+    let mut marker = [0u8; 4];
+    match input.read(&mut marker[..1])? {
+        0 => return Ok(None),
+        _ => input.read_exact(&mut marker[1..])?,
+    }
+    let len = u32::from_le_bytes(marker);
+    if len as usize > max_len {
+        return Err(invalid_data("GADGET record longer than its block can be"));
+    }
+    let mut data = Vec::new();
+    input.by_ref().take(len as u64).read_to_end(&mut data)?;
+    if data.len() != len as usize {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated GADGET record"));
+    }
+    if read_u32(input)? != len {
+        return Err(invalid_data("mismatched GADGET record markers"));
+    }
+    Ok(Some(data))
+}
+
+/// Reads `count` values of `comps` components each, in f32 or f64 depending
+/// on the block size.
+fn read_floats(data : &[u8], count : usize, comps : usize) -> io::Result<Vec<Scalar>> {
+    let mut input = data;
+    if data.len() == 4 * count * comps {
+        (0..count * comps).map(|_| read_f32(&mut input).map(Scalar::from)).collect()
+    } else if data.len() == 8 * count * comps {
+        (0..count * comps).map(|_| read_f64(&mut input)).collect()
+    } else {
+        Err(invalid_data("GADGET block size does not match the particle count"))
+    }
+}
+
+fn read_ids(data : &[u8], count : usize) -> io::Result<Vec<u64>> {
+    let mut input = data;
+    if data.len() == 4 * count {
+        (0..count).map(|_| read_u32(&mut input).map(u64::from)).collect()
+    } else if data.len() == 8 * count {
+        (0..count).map(|_| read_u64(&mut input)).collect()
+    } else {
+        Err(invalid_data("GADGET ID block size does not match the particle count"))
+    }
+}
+
+impl GadgetSnapshot {
+    /// Species must be valid GADGET types (0 to 5).
+    pub fn new(time : Scalar, particles : Vec<Particle>) -> GadgetSnapshot {
+        GadgetSnapshot {
+            header : GadgetHeader {
+                time,
+                num_files : 1,
+                ..GadgetHeader::default()
+            },
+            particles,
+        }
+    }
+
+    /// Writes positions, velocities and masses in f32 like a default GADGET
+    /// build or, with `double_precision`, in f64 like one built with
+    /// `OUTPUT_IN_DOUBLEPRECISION`. IDs use 64 bits only if they need to.
+    pub fn write_to<W : Write>(&self, out : &mut W, format : GadgetFormat, double_precision : bool) -> io::Result<()> {
+        let mut order : Vec<&Particle> = Vec::with_capacity(self.particles.len());
+        let mut header = self.header;
+        for kind in 0..NUM_TYPES {
+            let of_type = self.particles.iter()
+                .filter(|part| part.species as usize == kind)
+                .collect::<Vec<&Particle>>();
+            header.npart[kind] = of_type.len() as u32;
+            header.npart_total[kind] = of_type.len() as u32;
+            header.npart_total_high_word[kind] = 0;
+            header.mass[kind] = match of_type.first() {
+                Some(first) if of_type.iter().all(|part| part.mass == first.mass) && first.mass != 0.0 => first.mass,
+                _ => 0.0,
+            };
+            order.extend(of_type);
+        }
+        if order.len() != self.particles.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "species outside the GADGET types 0 to 5"));
+        }
+        let gas = &order[..header.npart[0] as usize];
+
+        let float = |buf : &mut Vec<u8>, val : Scalar| if double_precision {
+            write_f64(buf, val)
+        } else {
+            write_f32(buf, val as f32)
+        };
+        let write_block = |out : &mut W, label : &[u8; 4], data : &[u8]| -> io::Result<()> {
+            if format == GadgetFormat::Format2 {
+                let mut tag = label.to_vec();
+                write_u32(&mut tag, data.len() as u32 + 8)?;
+                write_record(out, &tag)?;
+            }
+            write_record(out, data)
+        };
+
+        let mut block = Vec::with_capacity(HEADER_SIZE);
+        header.write_to(&mut block)?;
+        write_block(out, b"HEAD", &block)?;
+
+        block.clear();
+        for part in order.iter() {
+            float(&mut block, part.pos.x)?;
+            float(&mut block, part.pos.y)?;
+            float(&mut block, 0.0)?;
+        }
+        write_block(out, b"POS ", &block)?;
+
+        block.clear();
+        for part in order.iter() {
+            float(&mut block, part.vel.x)?;
+            float(&mut block, part.vel.y)?;
+            float(&mut block, 0.0)?;
+        }
+        write_block(out, b"VEL ", &block)?;
+
+        block.clear();
+        let long_ids = order.iter().any(|part| part.id > u32::MAX as u64);
+        for part in order.iter() {
+            if long_ids {
+                write_u64(&mut block, part.id)?;
+            } else {
+                write_u32(&mut block, part.id as u32)?;
+            }
+        }
+        write_block(out, b"ID  ", &block)?;
+
+        if header.num_with_mass() > 0 {
+            block.clear();
+            for part in order.iter().filter(|part| header.mass[part.species as usize] == 0.0) {
+                float(&mut block, part.mass)?;
+            }
+            write_block(out, b"MASS", &block)?;
+        }
+
+        if !gas.is_empty() {
+            let gas_state = |part : &Particle| part.gas.unwrap_or_default();
+            block.clear();
+            for part in gas.iter() {
+                float(&mut block, gas_state(part).internal_energy)?;
+            }
+            write_block(out, b"U   ", &block)?;
+            block.clear();
+            for part in gas.iter() {
+                float(&mut block, gas_state(part).density)?;
+            }
+            write_block(out, b"RHO ", &block)?;
+            block.clear();
+            for part in gas.iter() {
+                float(&mut block, 2.0 * gas_state(part).smoothing_length)?;
+            }
+            write_block(out, b"HSML", &block)?;
+        }
+        Ok(())
+    }
+
+    /// Reads either format, f32 or f64 blocks and 32 or 64 bit IDs.
+    /// Blocks other than the ones written by `write_to` are skipped.
+    pub fn read_from<R : Read>(input : &mut R) -> io::Result<GadgetSnapshot> {
+        let first = read_record(input, HEADER_SIZE)?.ok_or_else(|| invalid_data("empty GADGET file"))?;
+        let format = match first.len() {
+            8 if &first[..4] == b"HEAD" => GadgetFormat::Format2,
+            HEADER_SIZE => GadgetFormat::Format1,
+            _ => return Err(invalid_data("not a little endian GADGET-2 snapshot")),
+        };
+        let header_data = match format {
+            GadgetFormat::Format1 => first,
+            GadgetFormat::Format2 => read_record(input, HEADER_SIZE)?.ok_or_else(|| invalid_data("missing GADGET header"))?,
+        };
+        if header_data.len() != HEADER_SIZE {
+            return Err(invalid_data("GADGET header has the wrong size"));
+        }
+        let header = GadgetHeader::read_from(&mut header_data.as_slice())?;
+        let count = header.num_particles();
+        let num_gas = header.npart[0] as usize;
+        // No block holds more than three f64 per particle.
+        let max_block = count.saturating_mul(3 * 8);
+
+        // Format 1 blocks come in a fixed order, with MASS only present when
+        // some type has no header mass.
+        let mut sequence : Vec<&[u8; 4]> = vec![b"POS ", b"VEL ", b"ID  "];
+        if header.num_with_mass() > 0 {
+            sequence.push(b"MASS");
+        }
+        if num_gas > 0 {
+            sequence.extend([b"U   ", b"RHO ", b"HSML"].iter());
+        }
+        let mut sequence = sequence.into_iter();
+
+        let mut blocks = Vec::new();
+        loop {
+            let label = match format {
+                GadgetFormat::Format1 => match sequence.next() {
+                    Some(label) => *label,
+                    None => break,
+                },
+                GadgetFormat::Format2 => match read_record(input, 8)? {
+                    Some(tag) if tag.len() == 8 => [tag[0], tag[1], tag[2], tag[3]],
+                    Some(_) => return Err(invalid_data("malformed GADGET block label")),
+                    None => break,
+                },
+            };
+            match read_record(input, max_block)? {
+                Some(data) => blocks.push((label, data)),
+                None if format == GadgetFormat::Format1 => break,
+                None => return Err(invalid_data("GADGET block label without data")),
+            }
+        }
+
+        // The particles are only created once the positions for all of them
+        // have actually been read, not on the header's word alone.
+        let positions = match blocks.iter().find(|(label, _)| label == b"POS ") {
+            Some((_, data)) => read_floats(data, count, 3)?,
+            None => return Err(invalid_data("GADGET snapshot without positions")),
+        };
+        let mut particles = Vec::with_capacity(count);
+        for (kind, &num) in header.npart.iter().enumerate() {
+            for _ in 0..num {
+                let part = Particle::new(header.mass[kind], 0.0, Vec2d::zero(), Vec2d::zero())
+                    .with_species(kind as u32);
+                particles.push(if kind == 0 { Particle { gas : Some(GasState::default()), ..part } } else { part });
+            }
+        }
+        for (part, pos) in particles.iter_mut().zip(positions.chunks(3)) {
+            part.pos = Vec2d::new(pos[0], pos[1]);
+        }
+
+        for (label, data) in blocks.iter() {
+            match label {
+                b"VEL " => for (part, vel) in particles.iter_mut().zip(read_floats(data, count, 3)?.chunks(3)) {
+                    part.vel = Vec2d::new(vel[0], vel[1]);
+                },
+                b"ID  " => for (part, id) in particles.iter_mut().zip(read_ids(data, count)?) {
+                    part.id = id;
+                },
+                b"MASS" => {
+                    let masses = read_floats(data, header.num_with_mass(), 1)?;
+                    let without_mass = particles.iter_mut().filter(|part| header.mass[part.species as usize] == 0.0);
+                    for (part, mass) in without_mass.zip(masses) {
+                        part.mass = mass;
+                    }
+                },
+                b"U   " => for (gas, u) in particles.iter_mut().filter_map(|part| part.gas.as_mut()).zip(read_floats(data, num_gas, 1)?) {
+                    gas.internal_energy = u;
+                },
+                b"RHO " => for (gas, rho) in particles.iter_mut().filter_map(|part| part.gas.as_mut()).zip(read_floats(data, num_gas, 1)?) {
+                    gas.density = rho;
+                },
+                b"HSML" => for (gas, hsml) in particles.iter_mut().filter_map(|part| part.gas.as_mut()).zip(read_floats(data, num_gas, 1)?) {
+                    gas.smoothing_length = 0.5 * hsml;
+                },
+                _ => {},
+            }
+        }
+        Ok(GadgetSnapshot { header, particles })
+    }
+
+    pub fn save<T : AsRef<Path>>(&self, file_name : T, format : GadgetFormat, double_precision : bool) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(file_name)?);
+        self.write_to(&mut out, format, double_precision)?;
+        out.flush()
+    }
+
+    pub fn load<T : AsRef<Path>>(file_name : T) -> io::Result<GadgetSnapshot> {
+        let mut input = BufReader::new(File::open(file_name)?);
+        GadgetSnapshot::read_from(&mut input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two gas particles, two of a fixed mass type and two whose masses go
+    /// into the MASS block, already in type order. All values are exact in
+    /// f32.
+    fn particles(first_id : u64) -> Vec<Particle> {
+        let species = [0, 0, 1, 1, 4, 4];
+        let masses = [0.5, 0.75, 2.0, 2.0, 1.25, 3.5];
+        species.iter().zip(masses.iter()).enumerate()
+            .map(|(idx, (&kind, &mass))| {
+                let x = idx as Scalar;
+                let part = Particle::new(mass, 0.0, Vec2d::new(x, -0.5 * x), Vec2d::new(0.25 * x, 1.0))
+                    .with_species(kind);
+                let gas = if kind == 0 {
+                    Some(GasState { density : 4.0 + x, ..GasState::new(1.5 + x, 0.125) })
+                } else {
+                    None
+                };
+                Particle { id : first_id + idx as u64, gas, ..part }
+            })
+            .collect()
+    }
+
+    fn round_trip(snapshot : &GadgetSnapshot, format : GadgetFormat, double_precision : bool) -> (Vec<u8>, GadgetSnapshot) {
+        let mut buf = Vec::new();
+        snapshot.write_to(&mut buf, format, double_precision).unwrap();
+        let read = GadgetSnapshot::read_from(&mut buf.as_slice()).unwrap();
+        (buf, read)
+    }
+
+    #[test]
+    fn formats_precisions_and_id_widths_round_trip() {
+        for &first_id in [1, u32::MAX as u64].iter() {
+            let mut snapshot = GadgetSnapshot::new(0.5, particles(first_id));
+            snapshot.header.flag_metals = 3;
+            snapshot.header.fill[59] = 7;
+            for &format in [GadgetFormat::Format1, GadgetFormat::Format2].iter() {
+                for &double_precision in [false, true].iter() {
+                    let (buf, read) = round_trip(&snapshot, format, double_precision);
+                    assert_eq!(read.particles, snapshot.particles);
+                    assert_eq!(read.header.time, 0.5);
+                    assert_eq!(read.header.flag_metals, 3);
+                    assert_eq!(read.header.fill, snapshot.header.fill);
+                    assert_eq!(read.header.npart, [2, 2, 0, 0, 2, 0]);
+                    assert_eq!(read.header.mass, [0.0, 2.0, 0.0, 0.0, 0.0, 0.0]);
+
+                    let (again, _) = round_trip(&read, format, double_precision);
+                    assert_eq!(again, buf);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn header_count_beyond_the_data_is_invalid_data() {
+        let mut buf = Vec::new();
+        GadgetSnapshot::new(0.0, particles(1)).write_to(&mut buf, GadgetFormat::Format1, false).unwrap();
+        // npart[1] follows the record marker and npart[0].
+        buf[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = GadgetSnapshot::read_from(&mut buf.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,5 +1,5 @@
-use crate::mathvec::{Scalar, Vec2d};
-use crate::particles::Particle;
+use crate::mathvec::{Scalar, Vec2d, Vec3d, Float, cast};
+use crate::particles::{Particle, Particle3d};
 use std::collections::HashMap;
 use std::vec::Vec;
 
@@ -9,78 +9,357 @@ struct MapKey (PosIndex, PosIndex);
 
 impl MapKey {
     pub fn neighbors(self) -> impl Iterator<Item=MapKey> {
-        let x_adds : &[i32] = if self.0 == i32::max_value() {
+        let x_adds : &[i32] = if self.0 == i32::MAX {
             &[-1, 0]
-        } else if self.0 == i32::min_value() {
+        } else if self.0 == i32::MIN {
             &[0, 1]
         } else {
             &[-1, 0, 1]
         };
-        let y_adds : &[i32] = if self.1 == i32::max_value() {
+        let y_adds : &[i32] = if self.1 == i32::MAX {
             &[-1, 0]
-        } else if self.0 == i32::min_value() {
+        } else if self.1 == i32::MIN {
             &[0, 1]
         } else {
             &[-1, 0, 1]
         };
-        (x_adds).iter().zip(y_adds).map(move |(x_add, y_add)| {
-            MapKey(self.0 + x_add, self.1 + y_add)
+        x_adds.iter().flat_map(move |x_add| {
+            y_adds.iter().map(move |y_add| MapKey(self.0 + x_add, self.1 + y_add))
         })
     }
 }
 
-pub struct GridHandler {
-    gridmap : HashMap<MapKey, Vec<Particle>>,
-    multiplier : Scalar,
-    expected_particles : usize, 
+fn pos_to_key<S : Float>(pos : Vec2d<S>, multiplier : S) -> MapKey {
+    let x_arg = (pos.x * multiplier).floor().to_f64() as PosIndex;
+    let y_arg = (pos.y * multiplier).floor().to_f64() as PosIndex;
+    MapKey(x_arg, y_arg)
 }
 
-impl GridHandler {
-    pub fn new(multiplier : Scalar, expected_particles : usize) -> GridHandler {
+pub fn contact_force<P : Float, F : Float>(arg : &Particle<P, F>, val : &Particle<P, F>, K : F, damping : F) -> Vec2d<F> {
+    if arg.is_gas() || val.is_gas() {
+        return Vec2d::zero();
+    }
+    let diff = (val.pos - arg.pos).cast::<F>();
+    let d = diff.mag_squared();
+    if d < F::from_f64(0.00001) { 
+        return Vec2d::zero();
+    }
+    let d = d.sqrt();
+    let diff = diff/d;
+    let overlap = cast::<P, F>(arg.radius + val.radius) - d;
+    contact_law(diff, overlap, (val.vel - arg.vel).cast(), K, damping)
+}
+
+/// Elastic energy 1/2 K overlap^2 stored in the contact between two particles.
+pub fn contact_energy<P : Float, F : Float>(arg : &Particle<P, F>, val : &Particle<P, F>, K : F) -> F {
+    if arg.is_gas() || val.is_gas() {
+        return F::ZERO;
+    }
+    let d = (val.pos - arg.pos).cast::<F>().mag_squared();
+    if d < F::from_f64(0.00001) { 
+        return F::ZERO;
+    }
+    let overlap = cast::<P, F>(arg.radius + val.radius) - d.sqrt();
+    if overlap > F::ZERO {
+        F::from_f64(0.5) * K * overlap * overlap
+    } else {
+        F::ZERO
+    }
+}
+
+/// Damped linear spring along `direction` (the unit vector from the particle
+/// towards whatever it is touching).
+pub fn contact_law<F : Float>(direction : Vec2d<F>, overlap : F, relvel : Vec2d<F>, K : F, damping : F) -> Vec2d<F> {
+    if overlap > F::ZERO {
+        let force = -K * overlap + damping * direction.dot(relvel);
+        direction * force
+    }
+    else {
+        Vec2d::zero()
+    }
+}
+
+/// `contact_law` in three dimensions.
+pub fn contact_law_3d<F : Float>(direction : Vec3d<F>, overlap : F, relvel : Vec3d<F>, K : F, damping : F) -> Vec3d<F> {
+    if overlap > F::ZERO {
+        let force = -K * overlap + damping * direction.dot(relvel);
+        direction * force
+    }
+    else {
+        Vec3d::zero()
+    }
+}
+
+pub struct GridHandler<P : Float = Scalar, F : Float = P> {
+    gridmap : HashMap<MapKey, Vec<Particle<P, F>>>,
+    multiplier : P,
+}
+
+impl<P : Float, F : Float> GridHandler<P, F> {
+    pub fn new(multiplier : P, expected_particles : usize) -> GridHandler<P, F> {
         GridHandler {
             multiplier,
-            expected_particles,
             gridmap : HashMap::with_capacity(expected_particles)
         }
     }
-    fn pos_to_map_key(&self, pos : Vec2d) -> MapKey {
-        let x_arg = (pos.x * self.multiplier).floor() as PosIndex;
-        let y_arg = (pos.y * self.multiplier).floor() as PosIndex;
-        MapKey(x_arg, y_arg)
-
+    fn pos_to_map_key(&self, pos : Vec2d<P>) -> MapKey {
+        pos_to_key(pos, self.multiplier)
     }
 
-    pub fn add_particle(&mut self, arg : Particle) {
+    pub fn add_particle(&mut self, arg : Particle<P, F>) {
         let arg_key = self.pos_to_map_key(arg.pos);
-        self.gridmap.entry(arg_key).or_insert(Vec::new()).push(arg);
+        self.gridmap.entry(arg_key).or_default().push(arg);
     }
     pub fn clear_points(&mut self) {
         self.gridmap.clear();
     }
-    pub fn calculate_spring_force(&self, arg : Particle, K : Scalar, damping : Scalar ) -> Vec2d {
-        let arg_key  = self.pos_to_map_key(arg.pos);
+    pub fn neighbors<'a>(&'a self, pos : Vec2d<P>) -> impl Iterator<Item=&'a Particle<P, F>> + 'a {
+        let gridmap = &self.gridmap;
+        self.pos_to_map_key(pos).neighbors()
+            .filter_map(move |key| gridmap.get(&key))
+            .flat_map(|allvals| allvals.iter())
+    }
+    pub fn calculate_spring_force(&self, arg : Particle<P, F>, K : F, damping : F ) -> Vec2d<F> {
         let mut retval = Vec2d::zero();
-        for neighbor in arg_key.neighbors() {
-            let allvals = match self.gridmap.get(&neighbor) {
-                Some(v) => v, 
-                None => {continue;}
-            };
-            for val in allvals {
-                let diff = val.pos - arg.pos;
-                let d = diff.mag_squared();
-                if d < 0.00001 { 
-                    continue;
-                }
-                let d = d.sqrt();
-                let diff = diff/d;
-                let overlap = arg.radius + val.radius - d;
-                if overlap > 0.0 {
-                    let relvel = val.vel - arg.vel;
-                    let force = -K * overlap + damping * diff.dot(relvel);
-                    retval += force * diff;
+        for val in self.neighbors(arg.pos) {
+            retval += contact_force(&arg, val, K, damping);
+        }
+        retval
+    }
+    /// Sum of the contact energies of `arg` with its neighbours; summing this
+    /// over all particles counts every contact twice.
+    pub fn calculate_contact_energy(&self, arg : Particle<P, F>, K : F) -> F {
+        self.neighbors(arg.pos)
+            .map(|val| contact_energy(&arg, val, K))
+            .sum()
+    }
+}
+
+/// Verlet neighbor lists: each particle caches every particle within
+/// `r_i + r_j + skin` of it, and the lists are only rebuilt once some particle
+/// has moved more than half the skin since the last build.
+pub struct NeighborList<P : Float = Scalar, F : Float = P> {
+    skin : P,
+    neighbors : Vec<Vec<usize>>,
+    build_positions : Vec<Vec2d<P>>,
+    snapshot : Vec<Particle<P, F>>,
+    rebuilds : usize,
+}
+
+impl<P : Float, F : Float> NeighborList<P, F> {
+    pub fn new(skin : P) -> NeighborList<P, F> {
+        NeighborList {
+            skin, 
+            neighbors : Vec::new(),
+            build_positions : Vec::new(),
+            snapshot : Vec::new(),
+            rebuilds : 0,
+        }
+    }
+
+    pub fn skin(&self) -> P {
+        self.skin
+    }
+
+    pub fn rebuilds(&self) -> usize {
+        self.rebuilds
+    }
+
+    pub fn invalidate(&mut self) {
+        self.build_positions.clear();
+    }
+
+    pub fn needs_rebuild(&self, parts : &[Particle<P, F>]) -> bool {
+        if parts.len() != self.build_positions.len() {
+            return true;
+        }
+        let max_move = P::from_f64(0.5) * self.skin;
+        let max_move_sq = max_move * max_move;
+        parts.iter().zip(self.build_positions.iter())
+            .any(|(part, old_pos)| (part.pos - *old_pos).mag_squared() > max_move_sq)
+    }
+
+    pub fn update(&mut self, parts : Vec<Particle<P, F>>) {
+        if self.needs_rebuild(&parts) {
+            self.rebuild(&parts);
+        }
+        self.snapshot = parts;
+    }
+
+    fn rebuild(&mut self, parts : &[Particle<P, F>]) {
+        let max_rad = parts.iter().fold(P::ZERO, |acc, p| acc.max(p.radius));
+        let cutoff = P::from_f64(2.0) * max_rad + self.skin;
+        if cutoff <= P::ZERO {
+            self.neighbors = vec![Vec::new() ; parts.len()];
+            self.build_positions = parts.iter().map(|p| p.pos).collect();
+            self.rebuilds += 1;
+            return;
+        }
+        let multiplier = P::ONE/cutoff;
+
+        let mut cells : HashMap<MapKey, Vec<usize>> = HashMap::with_capacity(parts.len());
+        for (idx, part) in parts.iter().enumerate() {
+            cells.entry(pos_to_key(part.pos, multiplier)).or_default().push(idx);
+        }
+
+        let mut neighbors = Vec::with_capacity(parts.len());
+        for (idx, part) in parts.iter().enumerate() {
+            let mut cur = Vec::new();
+            for key in pos_to_key(part.pos, multiplier).neighbors() {
+                let cell = match cells.get(&key) {
+                    Some(c) => c, 
+                    None => {continue;}
+                };
+                for &other_idx in cell {
+                    if other_idx == idx {
+                        continue;
+                    }
+                    let other = &parts[other_idx];
+                    let reach = part.radius + other.radius + self.skin;
+                    if (other.pos - part.pos).mag_squared() < reach * reach {
+                        cur.push(other_idx);
+                    }
                 }
             }
+            cur.sort_unstable();
+            neighbors.push(cur);
+        }
+
+        self.neighbors = neighbors;
+        self.build_positions = parts.iter().map(|p| p.pos).collect();
+        self.rebuilds += 1;
+    }
+
+    pub fn neighbors_of(&self, idx : usize) -> &[usize] {
+        self.neighbors.get(idx).map_or(&[], |v| v.as_slice())
+    }
+
+    pub fn calculate_spring_force(&self, idx : usize, K : F, damping : F) -> Vec2d<F> {
+        let arg = match self.snapshot.get(idx) {
+            Some(p) => p, 
+            None => { return Vec2d::zero(); }
+        };
+        let mut retval = Vec2d::zero();
+        for &other_idx in self.neighbors_of(idx) {
+            retval += contact_force(arg, &self.snapshot[other_idx], K, damping);
+        }
+        retval
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Default, Hash)]
+struct MapKey3d (PosIndex, PosIndex, PosIndex);
+
+impl MapKey3d {
+    pub fn neighbors(self) -> impl Iterator<Item=MapKey3d> {
+        fn adds(coord : PosIndex) -> &'static [i32] {
+            if coord == i32::MAX {
+                &[-1, 0]
+            } else if coord == i32::MIN {
+                &[0, 1]
+            } else {
+                &[-1, 0, 1]
+            }
+        }
+        let (x_adds, y_adds, z_adds) = (adds(self.0), adds(self.1), adds(self.2));
+        x_adds.iter().flat_map(move |x_add| {
+            y_adds.iter().flat_map(move |y_add| {
+                z_adds.iter().map(move |z_add| MapKey3d(self.0 + x_add, self.1 + y_add, self.2 + z_add))
+            })
+        })
+    }
+}
+
+pub fn contact_force_3d(arg : &Particle3d, val : &Particle3d, K : Scalar, damping : Scalar) -> Vec3d {
+    let diff = val.pos - arg.pos;
+    let d = diff.mag_squared();
+    if d < 0.00001 { 
+        return Vec3d::zero();
+    }
+    let d = d.sqrt();
+    let diff = diff/d;
+    let overlap = arg.radius + val.radius - d;
+    contact_law_3d(diff, overlap, val.vel - arg.vel, K, damping)
+}
+
+/// Three-dimensional counterpart of `GridHandler`.
+pub struct GridHandler3d {
+    gridmap : HashMap<MapKey3d, Vec<Particle3d>>,
+    multiplier : Scalar,
+}
+
+impl GridHandler3d {
+    pub fn new(multiplier : Scalar, expected_particles : usize) -> GridHandler3d {
+        GridHandler3d {
+            multiplier,
+            gridmap : HashMap::with_capacity(expected_particles)
+        }
+    }
+    fn pos_to_map_key(&self, pos : Vec3d) -> MapKey3d {
+        MapKey3d(
+            (pos.x * self.multiplier).floor() as PosIndex,
+            (pos.y * self.multiplier).floor() as PosIndex,
+            (pos.z * self.multiplier).floor() as PosIndex,
+        )
+    }
+
+    pub fn add_particle(&mut self, arg : Particle3d) {
+        let arg_key = self.pos_to_map_key(arg.pos);
+        self.gridmap.entry(arg_key).or_default().push(arg);
+    }
+    pub fn clear_points(&mut self) {
+        self.gridmap.clear();
+    }
+    pub fn neighbors<'a>(&'a self, pos : Vec3d) -> impl Iterator<Item=&'a Particle3d> + 'a {
+        let gridmap = &self.gridmap;
+        self.pos_to_map_key(pos).neighbors()
+            .filter_map(move |key| gridmap.get(&key))
+            .flat_map(|allvals| allvals.iter())
+    }
+    pub fn calculate_spring_force(&self, arg : Particle3d, K : Scalar, damping : Scalar) -> Vec3d {
+        let mut retval = Vec3d::zero();
+        for val in self.neighbors(arg.pos) {
+            retval += contact_force_3d(&arg, val, K, damping);
         }
         retval
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Overlapping particles of two sizes with uneven velocities.
+    fn packed() -> Vec<Particle> {
+        (0..100).map(|i| {
+            let (x, y) = ((i % 10) as Scalar, (i / 10) as Scalar);
+            let pos = Vec2d::new(0.9 * x + 0.05 * (1.7 * y).sin(), 0.9 * y + 0.05 * (2.3 * x).cos());
+            let vel = Vec2d::new((0.3 * i as Scalar).sin(), (0.7 * i as Scalar).cos());
+            Particle::new(1.0, if i % 3 == 0 { 0.6 } else { 0.45 }, pos, vel)
+        })
+        .collect()
+    }
+
+    fn moved(parts : &[Particle], by : Scalar) -> Vec<Particle> {
+        parts.iter().enumerate()
+            .map(|(i, part)| {
+                let (sin, cos) = (i as Scalar).sin_cos();
+                Particle { pos : part.pos + Vec2d::new(cos, sin) * by, ..*part }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rebuilds_once_a_particle_moves_half_the_skin() {
+        let parts = packed();
+        let mut list = NeighborList::new(0.3);
+        list.update(parts.clone());
+        list.update(moved(&parts, 0.149));
+        assert_eq!(list.rebuilds(), 1);
+
+        let mut parts = parts;
+        parts[42].pos.x += 0.151;
+        list.update(parts);
+        assert_eq!(list.rebuilds(), 2);
+    }
+}
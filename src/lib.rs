@@ -0,0 +1,27 @@
+// G, K, N and collK follow the physics notation.
+#![allow(non_snake_case)]
+
+pub mod particles;
+pub mod mathvec;
+pub mod binio;
+pub mod physconstants;
+pub mod gridhandler;
+pub mod masstree;
+pub mod octree;
+pub mod sph;
+pub mod external;
+pub mod boundary;
+pub mod pairforce;
+pub mod chargetree;
+pub mod bonds;
+pub mod rigidbody;
+pub mod regularization;
+pub mod rng;
+pub mod physics_handler;
+pub mod monitor;
+pub mod snapshot;
+pub mod gadget;
+pub mod textformat;
+pub mod export;
+pub mod particleman;
+pub mod easytime;
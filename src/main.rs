@@ -1,32 +1,71 @@
-mod particles;
-mod mathvec;
-mod physconstants;
-mod gridhandler;
-mod masstree;
-mod physics_handler;
-mod particleman;
-mod easytime; 
+// G, K, N and collK follow the physics notation.
+#![allow(non_snake_case)]
+
 mod pointsdl;
 mod camera;
 
-use particleman::ParticleManager;
-use mathvec::{Vec2d, Scalar};
-use physics_handler::PhysicsHandler;
-use physics_handler::threaded::PhysicsHandlerThreaded;
+use rust_nbody::easytime;
+use rust_nbody::particleman::ParticleManager;
+use rust_nbody::mathvec::Vec2d;
+use rust_nbody::physics_handler::PhysicsHandler;
+use rust_nbody::physics_handler::threaded::PhysicsHandlerThreaded;
+use rust_nbody::physics_handler::threaded3d::PhysicsHandlerThreaded3d;
+use rust_nbody::monitor::ConservationMonitor;
 use pointsdl::Screen;
 
 use std::time::{Instant};
 
 
+/// `--seed <n>` from the command line, or a fresh seed that is printed so
+/// the run can be repeated.
+fn placement_seed() -> u64 {
+    let args = std::env::args().collect::<Vec<String>>();
+    if let Some(seed) = args.windows(2).find(|pair| pair[0] == "--seed").and_then(|pair| pair[1].parse().ok()) {
+        return seed;
+    }
+    let seed = rand::random();
+    println!("Placement seed: {}", seed);
+    seed
+}
+
+/// A Plummer sphere drawn through the projected 3D view; the arrow keys
+/// turn the view.
+fn run_3d() -> Result<(), String> {
+    let grav_constant = 2.0;
+    let mut man = ParticleManager::new().with_seed(placement_seed()).with_mass(20.0).with_radius(1.0);
+    man.place_plummer_3d(2000, 200.0, grav_constant);
+    let mut phys = PhysicsHandlerThreaded3d::new(grav_constant, 1000.0, 10.0);
+    phys.load_particles(man.into_inner_3d());
+    phys.zero_momentum_and_cm();
+
+    let mut screen = Screen::init()?;
+    screen.draw_context.init_draw_style_C_circles()?;
+    while screen.should_loop() {
+        for _ in 0..20 {
+            phys.update(0.01);
+        }
+        screen.draw_context.renderer.set_draw_color((0,0,0,255));
+        screen.draw_context.renderer.clear();
+        screen.draw_context.set_size((screen.cam.scale() * 10.0) as u32);
+        screen.draw_particles_3d(phys.particles())?;
+        screen.sync();
+    }
+    screen.quit();
+    Ok(())
+}
+
 fn main() -> Result<(), String> {
+    if std::env::args().any(|arg| arg == "--3d") {
+        return run_3d();
+    }
 
     let nmult = 3.0; 
-    let mut phys = PhysicsHandlerThreaded::new(2.0 * nmult, 1000.0, 10.0);
+    let mut phys : PhysicsHandlerThreaded = PhysicsHandlerThreaded::new(2.0 * nmult, 1000.0, 10.0);
     let mut _start = Instant::now();
     let mut _end = Instant::now();
     let _timer = easytime::EasyTimer::now();
 
-    let mut man = ParticleManager::new().with_mass(20.0);
+    let mut man = ParticleManager::new().with_seed(placement_seed()).with_mass(20.0);
     man.place_gaussian(100, 100.0, 2.0, 0.0000002);
 
     man = man
@@ -50,7 +89,6 @@ fn main() -> Result<(), String> {
 
     let _frame = 0;
     
-    let mut n = 0;
     let mut _create_total = 0.0;
     let mut _phy_total = 0.0;
     let mut _delete_total = 0.0;
@@ -61,10 +99,17 @@ fn main() -> Result<(), String> {
     screen.draw_context.init_draw_style_C_circles()?;
     screen.draw_context.set_color(0,0,255,200);
     phys.zero_momentum_and_cm();
-    let _a0 = phys.angular_momentum();
+    // Collisions are damped, so energy only warns; momentum should hold.
+    // `step` finishes every update, so the monitor samples whole steps.
+    let mut monitor = ConservationMonitor::new(200)
+        .with_energy_threshold(0.05)
+        .with_angular_momentum_threshold(0.01);
     while screen.should_loop() {
         for _ in 0..200 {
-            phys.update(0.01);
+            phys.step(0.01);
+            for warning in monitor.observe(&phys).map_err(|err| err.to_string())? {
+                println!("Conservation warning: {}", warning);
+            }
         }
         screen.draw_context.init_draw_style_C_circles()?;
         let mult = screen.cam.scale();
@@ -83,7 +128,6 @@ fn main() -> Result<(), String> {
         screen.draw_context.renderer.set_draw_color((255, 0, 0, 255));
         screen.draw_context.renderer.draw_point(((screen.scwidth/2) as i32, (screen.scheight/2) as i32))?;
         screen.sync();
-        n += 1;
         screen.sync();
 
     }
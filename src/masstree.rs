@@ -1,28 +1,30 @@
-use crate::mathvec::{Scalar, Vec2d};
+use crate::mathvec::{Scalar, Vec2d, Float, cast};
 use crate::particles::{Particle};
 
+use std::marker::PhantomData;
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
-pub struct Span {
-    plus_corner : Vec2d,
-    minus_corner : Vec2d,
+pub struct Span<S : Float = Scalar> {
+    plus_corner : Vec2d<S>,
+    minus_corner : Vec2d<S>,
 }
 
-impl Span {
-    fn empty() -> Span {
+impl<S : Float> Span<S> {
+    fn empty() -> Span<S> {
         Span { plus_corner : Vec2d::zero(), minus_corner : Vec2d::zero() }
     }
 
-    pub fn new(plus_corner : Vec2d, minus_corner : Vec2d) -> Span {
+    pub fn new(plus_corner : Vec2d<S>, minus_corner : Vec2d<S>) -> Span<S> {
         debug_assert!( 
             (plus_corner == Vec2d::zero() && minus_corner == Vec2d::zero()) ||
             (plus_corner.x > minus_corner.x && plus_corner.y > minus_corner.y)
         );
         Span { plus_corner, minus_corner }
     }
-    pub fn midpoint(self) -> Vec2d {
-        (self.minus_corner + self.plus_corner)/2.0
+    pub fn midpoint(self) -> Vec2d<S> {
+        (self.minus_corner + self.plus_corner)/S::from_f64(2.0)
     }
-    pub fn subspans(self) -> [Span ; 4] {
+    pub fn subspans(self) -> [Span<S> ; 4] {
         let mut retval = [Span::empty() ; 4] ;
         let mid = self.midpoint();
         let x_min = self.minus_corner.x;
@@ -47,14 +49,20 @@ impl Span {
 
         retval
     }
-    pub fn subspan_idx_for(self, part : Particle) -> (usize, usize) {
+    pub fn subspan_idx_for<F : Float>(self, part : Particle<S, F>) -> (usize, usize) {
+        self.subspan_idx_for_pos(part.pos)
+    }
+    pub fn subspan_idx_for_pos(self, pos : Vec2d<S>) -> (usize, usize) {
         let mid = self.midpoint();
-        let x_idx = if { part.pos.x < mid.x } { 0 } else { 1 };
-        let y_idx = if { part.pos.y < mid.y } { 0 } else { 1 };
+        let x_idx = if pos.x < mid.x { 0 } else { 1 };
+        let y_idx = if pos.y < mid.y { 0 } else { 1 };
         (x_idx, y_idx)
     }
+    pub fn width(self) -> S {
+        self.plus_corner.x - self.minus_corner.x
+    }
 
-    pub fn volume(self) -> Scalar {
+    pub fn volume(self) -> S {
         let shifted_spans = self.plus_corner - self.minus_corner;
         shifted_spans.x * shifted_spans.y 
     }
@@ -65,39 +73,39 @@ impl Span {
 type NodeIndex = u32;
 
 #[derive(Copy, Clone)]
-pub struct TreeNode {
-    span : Span,
-    data : NodeType,
+pub struct TreeNode<P : Float = Scalar, F : Float = P> {
+    span : Span<P>,
+    data : NodeType<P, F>,
 } 
 
 #[derive(Copy, Clone)]
-enum NodeType {
+enum NodeType<P : Float, F : Float> {
     Empty,
-    Leaf(Particle),
+    Leaf(Particle<P, F>),
     Branch {
-        masspos : Vec2d,
-        mass : Scalar,
+        masspos : Vec2d<P>,
+        mass : P,
         subnodes : [NodeIndex ; 4],
     }
 }
 
-impl TreeNode {
-    pub fn new(span : Span) -> TreeNode {
+impl<P : Float, F : Float> TreeNode<P, F> {
+    pub fn new(span : Span<P>) -> TreeNode<P, F> {
         TreeNode {
             span,
             data : NodeType::Empty,
         }
     }
-    pub fn masspos(&self) -> Vec2d {
+    pub fn masspos(&self) -> Vec2d<P> {
         match self.data {
             NodeType::Empty => Vec2d::zero(),
-            NodeType::Leaf(p) => p.mass * p.pos,
+            NodeType::Leaf(p) => p.pos * p.mass,
             NodeType::Branch{masspos, ..} => masspos
         }
     }
-    pub fn mass(&self) -> Scalar {
+    pub fn mass(&self) -> P {
         match self.data {
-            NodeType::Empty => 0.0,
+            NodeType::Empty => P::ZERO,
             NodeType::Leaf(p) => p.mass,
             NodeType::Branch{mass, ..} => mass
         }
@@ -105,20 +113,21 @@ impl TreeNode {
 }
 
 #[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
-pub struct MassTreeBuilder {
+pub struct MassTreeBuilder<P : Float = Scalar, F : Float = P> {
     node_capacity : usize, 
-    span : Span,
+    span : Span<P>,
+    force_precision : PhantomData<F>,
 }
 
-impl MassTreeBuilder {
+impl<P : Float, F : Float> MassTreeBuilder<P, F> {
 
     pub fn new() -> Self {
         MassTreeBuilder::default()
     }
 
-    pub fn with_span(self, span : Span) -> Self {
+    pub fn with_span(self, span : Span<P>) -> Self {
         MassTreeBuilder {
-            span : span, 
+            span, 
             ..self
         }
     }
@@ -135,7 +144,7 @@ impl MassTreeBuilder {
         }
     }
 
-    pub fn build(self) -> MassTree {
+    pub fn build(self) -> MassTree<P, F> {
         let mut nodes = Vec::with_capacity(self.node_capacity);
         nodes.push(TreeNode {
             span : self.span,
@@ -147,24 +156,26 @@ impl MassTreeBuilder {
     }
 }
 
-pub struct MassTree {
-    nodes : Vec<TreeNode>,
+/// Barnes-Hut quadtree. Cell centres of mass are accumulated in the storage
+/// precision `P`; the force walk itself runs in the force precision `F`.
+pub struct MassTree<P : Float = Scalar, F : Float = P> {
+    nodes : Vec<TreeNode<P, F>>,
 }
 
-impl Default for MassTree {
+impl<P : Float, F : Float> Default for MassTree<P, F> {
     fn default() -> Self {
         MassTreeBuilder::default().build()
     }
 }
 
-impl MassTree {
-    pub fn builder() -> MassTreeBuilder {
+impl<P : Float, F : Float> MassTree<P, F> {
+    pub fn builder() -> MassTreeBuilder<P, F> {
         MassTreeBuilder::new()
     }
-    fn get_node(&self, idx : NodeIndex) -> &TreeNode {
+    fn get_node(&self, idx : NodeIndex) -> &TreeNode<P, F> {
         &self.nodes[idx as usize]
     }
-    fn get_node_mut(&mut self, idx : NodeIndex) -> &mut TreeNode {
+    fn get_node_mut(&mut self, idx : NodeIndex) -> &mut TreeNode<P, F> {
         &mut self.nodes[idx as usize]
     }
     fn leaf_to_branch(&mut self, parent_idx : NodeIndex) {
@@ -184,8 +195,8 @@ impl MassTree {
                 let offset = (x_idx * 2 + y_idx) as usize;
                 self.nodes.push(TreeNode::new(spans[offset]));
                 debug_assert_eq!(self.nodes.len(), start_len + offset+1);
-                debug_assert_eq!( ((spans[offset].plus_corner.x - span.plus_corner.x).abs() < Vec2d::EPSILON), x_idx == 1);
-                debug_assert_eq!( ((spans[offset].plus_corner.y - span.plus_corner.y).abs() < Vec2d::EPSILON), y_idx == 1);
+                debug_assert_eq!( ((spans[offset].plus_corner.x - span.plus_corner.x).abs() < Vec2d::<P>::EPSILON), x_idx == 1);
+                debug_assert_eq!( ((spans[offset].plus_corner.y - span.plus_corner.y).abs() < Vec2d::<P>::EPSILON), y_idx == 1);
                 new_children[offset] = (start_len + offset) as NodeIndex;
             }
         }
@@ -196,15 +207,15 @@ impl MassTree {
             
             NodeType::Branch {
                 mass : part.mass,
-                masspos : part.mass * part.pos,
+                masspos : part.pos * part.mass,
                 subnodes : new_children
             }
         };
 
         self.get_node_mut(parent_idx).data = new_data;
     }
-    pub fn add_particle(&mut self, particle : Particle) {
-        debug_assert!(self.nodes.get(0).map_or(false, |n| n.span.volume() > 0.0001));
+    pub fn add_particle(&mut self, particle : Particle<P, F>) {
+        debug_assert!(self.nodes.first().is_some_and(|n| n.span.volume().to_f64() > 0.0001));
         let mut cur_idx : NodeIndex = 0;
         loop {
             let cur_node = self.get_node_mut(cur_idx);
@@ -221,14 +232,17 @@ impl MassTree {
                 },
                 NodeType::Branch{subnodes, ref mut mass, ref mut masspos} => {
                     *mass += particle.mass;
-                    *masspos += particle.mass * particle.pos;
+                    *masspos += particle.pos * particle.mass;
                     let (x_idx, y_idx) = cur_node.span.subspan_idx_for(particle);
                     cur_idx = subnodes[x_idx * 2 + y_idx];
                 }
             }
         }
     }
-    pub fn calculate_forces(&self, arg : Particle, G : Scalar) -> Vec2d {
+    pub fn calculate_forces(&self, arg : Particle<P, F>, G : F) -> Vec2d<F> {
+        let cutoff = F::from_f64(0.5);
+        let opening = F::from_f64(0.8);
+        let arg_mass : F = cast(arg.mass);
         let mut retval = Vec2d::zero();
         let mut to_calc : Vec<NodeIndex> = Vec::with_capacity(4);
         to_calc.push(0);
@@ -237,26 +251,26 @@ impl MassTree {
             match cur_node {
                 TreeNode{span, data : NodeType::Branch{ masspos, mass, subnodes }} => {
                     let pt = (*masspos)/(*mass);
-                    let diff = pt - arg.pos;
+                    let diff = (pt - arg.pos).cast::<F>();
                     let d = diff.mag_squared();
-                    let diffw = span.plus_corner.x - span.minus_corner.x;
-                    if diffw * diffw >= d * 0.8 {
+                    let diffw : F = cast(span.width());
+                    if diffw * diffw >= d * opening {
                         to_calc.extend_from_slice(subnodes);
                     }
                     else {
-                        let force = G * arg.mass * mass/d; 
+                        let force = G * arg_mass * cast(*mass)/d; 
                         let d = d.sqrt();
-                        if d > 0.5 {
+                        if d > cutoff {
                             retval += diff * force /d;
                         }
                     }
                 },
                 TreeNode{data : NodeType::Leaf(part), ..} => {
-                    let diff = part.pos - arg.pos; 
+                    let diff = (part.pos - arg.pos).cast::<F>(); 
                     let d = diff.mag_squared();
-                    let force = G * arg.mass * part.mass/d;
+                    let force = G * arg_mass * cast(part.mass)/d;
                     let d = d.sqrt();
-                    if d > 0.5 {
+                    if d > cutoff {
                         retval += diff * force/d; 
                     }
                 },
@@ -265,4 +279,43 @@ impl MassTree {
         }
         retval
     }
-}
\ No newline at end of file
+
+    /// Potential energy of `arg` in the field of the tree, using the same
+    /// opening angle and the same d > 0.5 cutoff as `calculate_forces` so it
+    /// is consistent with the forces actually applied.
+    pub fn calculate_potential(&self, arg : Particle<P, F>, G : F) -> F {
+        let cutoff = F::from_f64(0.5);
+        let opening = F::from_f64(0.8);
+        let arg_mass : F = cast(arg.mass);
+        let mut retval = F::ZERO;
+        let mut to_calc : Vec<NodeIndex> = Vec::with_capacity(4);
+        to_calc.push(0);
+        while let Some(cur_idx) = to_calc.pop() {
+            let cur_node = self.get_node(cur_idx);
+            match cur_node {
+                TreeNode{span, data : NodeType::Branch{ masspos, mass, subnodes }} => {
+                    let pt = (*masspos)/(*mass);
+                    let d = (pt - arg.pos).cast::<F>().mag_squared();
+                    let diffw : F = cast(span.width());
+                    if diffw * diffw >= d * opening {
+                        to_calc.extend_from_slice(subnodes);
+                    }
+                    else {
+                        let d = d.sqrt();
+                        if d > cutoff {
+                            retval -= G * arg_mass * cast(*mass)/d;
+                        }
+                    }
+                },
+                TreeNode{data : NodeType::Leaf(part), ..} => {
+                    let d = (part.pos - arg.pos).cast::<F>().mag();
+                    if d > cutoff {
+                        retval -= G * arg_mass * cast(part.mass)/d;
+                    }
+                },
+                _ => {},
+            }
+        }
+        retval
+    }
+}
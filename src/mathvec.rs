@@ -1,64 +1,186 @@
-use std::ops::{Add, AddAssign, Sub, SubAssign,Mul, MulAssign, Div};
+use std::ops::{Add, AddAssign, Sub, SubAssign,Mul, MulAssign, Div, DivAssign, Neg};
 use std::iter::Sum;
+use std::fmt::Debug;
 pub type Scalar = f64;
 
+/// The floating point types the physics core can be instantiated with.
+/// `Vec2d`, `Vec3d`, `Particle`, `MassTree`, `GridHandler` and
+/// `PhysicsHandlerThreaded` are generic over it. `Particle3d`, the 3D, Hermite
+/// and Wisdom-Holman handlers and the optional modules (SPH, electrostatics,
+/// bonds and the rest) work in `Scalar` only.
+pub trait Float : Copy + Clone + Debug + Default + PartialEq + PartialOrd + Send + Sync + 'static
+    + Add<Output=Self> + Sub<Output=Self> + Mul<Output=Self> + Div<Output=Self> + Neg<Output=Self>
+    + AddAssign + SubAssign + MulAssign + DivAssign + Sum
+{
+    const ZERO : Self;
+    const ONE : Self;
+    /// Tolerance used when comparing vectors for equality.
+    const TOLERANCE : Self;
+
+    fn from_f64(val : f64) -> Self;
+    fn to_f64(self) -> f64;
+    fn sqrt(self) -> Self;
+    fn abs(self) -> Self;
+    fn floor(self) -> Self;
+    fn exp(self) -> Self;
+    fn ln(self) -> Self;
+    fn powi(self, n : i32) -> Self;
+    fn sin_cos(self) -> (Self, Self);
+    fn atan2(self, other : Self) -> Self;
+    fn max(self, other : Self) -> Self;
+    fn min(self, other : Self) -> Self;
+}
+
+macro_rules! impl_float {
+    ($t:ident) => {
+        impl Float for $t {
+            const ZERO : $t = 0.0;
+            const ONE : $t = 1.0;
+            const TOLERANCE : $t = 0.0001;
+
+            fn from_f64(val : f64) -> $t { val as $t }
+            fn to_f64(self) -> f64 { self as f64 }
+            fn sqrt(self) -> $t { $t::sqrt(self) }
+            fn abs(self) -> $t { $t::abs(self) }
+            fn floor(self) -> $t { $t::floor(self) }
+            fn exp(self) -> $t { $t::exp(self) }
+            fn ln(self) -> $t { $t::ln(self) }
+            fn powi(self, n : i32) -> $t { $t::powi(self, n) }
+            fn sin_cos(self) -> ($t, $t) { $t::sin_cos(self) }
+            fn atan2(self, other : $t) -> $t { $t::atan2(self, other) }
+            fn max(self, other : $t) -> $t { $t::max(self, other) }
+            fn min(self, other : $t) -> $t { $t::min(self, other) }
+        }
+    }
+}
+
+impl_float!(f32);
+impl_float!(f64);
+
+pub fn cast<A : Float, B : Float>(val : A) -> B {
+    B::from_f64(val.to_f64())
+}
+
+/// Neumaier's improved Kahan summation: the round-off lost in every addition
+/// is collected separately and added back when the sum is read.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NeumaierSum<S : Float = Scalar> {
+    sum : S,
+    compensation : S,
+}
+
+impl<S : Float> NeumaierSum<S> {
+    pub fn new() -> NeumaierSum<S> {
+        NeumaierSum {sum : S::ZERO, compensation : S::ZERO}
+    }
+    pub fn add(&mut self, val : S) {
+        let t = self.sum + val;
+        if self.sum.abs() >= val.abs() {
+            self.compensation += (self.sum - t) + val;
+        } else {
+            self.compensation += (val - t) + self.sum;
+        }
+        self.sum = t;
+    }
+    pub fn value(&self) -> S {
+        self.sum + self.compensation
+    }
+}
+
+pub fn compensated_sum<S : Float, I : IntoIterator<Item=S>>(iter : I) -> S {
+    let mut acc = NeumaierSum::new();
+    for val in iter {
+        acc.add(val);
+    }
+    acc.value()
+}
+
+/// Kahan update `value += increment` for quantities that are accumulated over
+/// many steps; `compensation` carries the low order bits between calls.
+pub fn compensated_add<S : Float>(value : &mut S, compensation : &mut S, increment : S) {
+    let y = increment - *compensation;
+    let t = *value + y;
+    *compensation = (t - *value) - y;
+    *value = t;
+}
+
 #[derive(Copy, Clone, Debug, Default)]
-pub struct Vec2d {
-    pub x : Scalar,
-    pub y : Scalar,
+pub struct Vec2d<S : Float = Scalar> {
+    pub x : S,
+    pub y : S,
 }
 
-impl PartialEq for Vec2d {
-    fn eq(&self, other : &Vec2d) -> bool {
-        (self.x - other.x).abs() < Vec2d::EPSILON && (self.y - other.y).abs() < Vec2d::EPSILON
+impl<S : Float> PartialEq for Vec2d<S> {
+    fn eq(&self, other : &Vec2d<S>) -> bool {
+        (self.x - other.x).abs() < Vec2d::<S>::EPSILON && (self.y - other.y).abs() < Vec2d::<S>::EPSILON
     }
 }
 
-impl Eq for Vec2d {}
+impl<S : Float> Eq for Vec2d<S> {}
 
-impl Vec2d {
-    pub const EPSILON : Scalar = 0.0001;
-    pub fn new(x : Scalar, y : Scalar) -> Vec2d {
+impl<S : Float> Vec2d<S> {
+    pub const EPSILON : S = S::TOLERANCE;
+    pub fn new(x : S, y : S) -> Vec2d<S> {
         Vec2d {x, y}
     }
-    pub fn zero() -> Vec2d {
-        Vec2d::new(0.0, 0.0)
+    pub fn zero() -> Vec2d<S> {
+        Vec2d::new(S::ZERO, S::ZERO)
+    }
+    pub fn cast<T : Float>(self) -> Vec2d<T> {
+        Vec2d::new(cast(self.x), cast(self.y))
     }
-    pub fn dot(self, other : Vec2d) -> Scalar {
+    pub fn dot(self, other : Vec2d<S>) -> S {
         self.x * other.x + self.y * other.y 
     }
-    pub fn mag_squared(self) -> Scalar {
+    pub fn mag_squared(self) -> S {
         self.dot(self)
     }
-    pub fn mag(self) -> Scalar {
+    pub fn mag(self) -> S {
         self.mag_squared().sqrt()
     }
-    pub fn unit(self) -> Vec2d {
+    pub fn unit(self) -> Vec2d<S> {
         self/self.mag()
     }
-    pub fn flip_x(self) -> Vec2d {
+    pub fn flip_x(self) -> Vec2d<S> {
         Vec2d {
             x : -self.x,
             y :  self.y
         }
     }
-    pub fn flip_y(self) -> Vec2d {
+    pub fn flip_y(self) -> Vec2d<S> {
         Vec2d {
             x :  self.x,
             y : -self.y,
         }
     }
-    pub fn swap_xy(self) -> Vec2d {
+    pub fn compensated_add(&mut self, compensation : &mut Vec2d<S>, increment : Vec2d<S>) {
+        compensated_add(&mut self.x, &mut compensation.x, increment.x);
+        compensated_add(&mut self.y, &mut compensation.y, increment.y);
+    }
+    pub fn swap_xy(self) -> Vec2d<S> {
         Vec2d {
             x : self.y, 
             y : self.x,
         }
     }
+    pub fn perp(self) -> Vec2d<S> {
+        Vec2d {
+            x : -self.y,
+            y :  self.x,
+        }
+    }
+    pub fn rotate(self, angle : S) -> Vec2d<S> {
+        let (sin, cos) = angle.sin_cos();
+        Vec2d {
+            x : cos * self.x - sin * self.y,
+            y : sin * self.x + cos * self.y,
+        }
+    }
 }
 
-impl Add for Vec2d {
-    type Output = Vec2d;
-    fn add(self, rhs: Vec2d) -> Vec2d {
+impl<S : Float> Add for Vec2d<S> {
+    type Output = Vec2d<S>;
+    fn add(self, rhs: Vec2d<S>) -> Vec2d<S> {
         Vec2d {
             x : self.x + rhs.x,
             y : self.y + rhs.y,
@@ -66,16 +188,16 @@ impl Add for Vec2d {
     }
 }
 
-impl AddAssign for Vec2d {
-    fn add_assign(&mut self, rhs : Vec2d) {
+impl<S : Float> AddAssign for Vec2d<S> {
+    fn add_assign(&mut self, rhs : Vec2d<S>) {
         self.x += rhs.x;
         self.y += rhs.y;
     }
 }
 
-impl Sub for Vec2d {
-    type Output = Vec2d;
-    fn sub(self, rhs: Vec2d) -> Vec2d {
+impl<S : Float> Sub for Vec2d<S> {
+    type Output = Vec2d<S>;
+    fn sub(self, rhs: Vec2d<S>) -> Vec2d<S> {
         Vec2d {
             x : self.x - rhs.x,
             y : self.y - rhs.y,
@@ -83,71 +205,273 @@ impl Sub for Vec2d {
     }
 }
 
-impl SubAssign for Vec2d {
-    fn sub_assign(&mut self, rhs : Vec2d) {
+impl<S : Float> SubAssign for Vec2d<S> {
+    fn sub_assign(&mut self, rhs : Vec2d<S>) {
         self.x -= rhs.x;
         self.y -= rhs.y;
     }
 
 }
 
-impl Mul<Scalar> for Vec2d {
-    type Output = Vec2d;
-    fn mul(self, rhs : Scalar) -> Vec2d {
+impl<S : Float> Mul<S> for Vec2d<S> {
+    type Output = Vec2d<S>;
+    fn mul(self, rhs : S) -> Vec2d<S> {
         Vec2d {
             x : self.x * rhs,
             y : self.y * rhs,
         }
     }
 }
-impl Mul<Vec2d> for Scalar {
-    type Output = Vec2d;
-    fn mul(self, rhs : Vec2d) -> Vec2d {
+
+impl<S : Float> MulAssign<S> for Vec2d<S> {
+    fn mul_assign(&mut self, rhs : S) {
+        self.x *= rhs;
+        self.y *= rhs;
+    }
+}
+
+impl<S : Float> Div<S> for Vec2d<S> {
+    type Output = Vec2d<S>;
+    fn div(self, rhs : S) -> Vec2d<S> {
         Vec2d {
-            x : rhs.x * self,
-            y : rhs.y * self,
+            x : self.x / rhs,
+            y : self.y / rhs,
+        }
+    }
+}
+
+impl<S : Float> Sum for Vec2d<S> {
+    fn sum<I : Iterator<Item=Vec2d<S>>>(iter : I) -> Vec2d<S> {
+        let (mut x, mut y) = (NeumaierSum::new(), NeumaierSum::new());
+        for v in iter {
+            x.add(v.x);
+            y.add(v.y);
+        }
+        Vec2d::new(x.value(), y.value())
+    }
+}
+
+impl<S : Float> From<(S, S)> for Vec2d<S> {
+    fn from(inner : (S, S)) -> Vec2d<S> {
+        Vec2d {
+            x : inner.0, 
+            y : inner.1, 
+        }
+    }
+}
+
+impl<S : Float> From<Vec2d<S>> for (S, S) {
+    fn from(wrapped : Vec2d<S>) -> (S, S) {
+        (wrapped.x, wrapped.y)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Vec3d<S : Float = Scalar> {
+    pub x : S,
+    pub y : S,
+    pub z : S,
+}
+
+impl<S : Float> PartialEq for Vec3d<S> {
+    fn eq(&self, other : &Vec3d<S>) -> bool {
+        (self.x - other.x).abs() < Vec3d::<S>::EPSILON && (self.y - other.y).abs() < Vec3d::<S>::EPSILON && (self.z - other.z).abs() < Vec3d::<S>::EPSILON
+    }
+}
+
+impl<S : Float> Eq for Vec3d<S> {}
+
+impl<S : Float> Vec3d<S> {
+    pub const EPSILON : S = S::TOLERANCE;
+    pub fn new(x : S, y : S, z : S) -> Vec3d<S> {
+        Vec3d {x, y, z}
+    }
+    pub fn zero() -> Vec3d<S> {
+        Vec3d::new(S::ZERO, S::ZERO, S::ZERO)
+    }
+    pub fn cast<T : Float>(self) -> Vec3d<T> {
+        Vec3d::new(cast(self.x), cast(self.y), cast(self.z))
+    }
+    pub fn from_2d(plane : Vec2d<S>, z : S) -> Vec3d<S> {
+        Vec3d::new(plane.x, plane.y, z)
+    }
+    pub fn xy(self) -> Vec2d<S> {
+        Vec2d::new(self.x, self.y)
+    }
+    pub fn dot(self, other : Vec3d<S>) -> S {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+    pub fn cross(self, other : Vec3d<S>) -> Vec3d<S> {
+        Vec3d {
+            x : self.y * other.z - self.z * other.y,
+            y : self.z * other.x - self.x * other.z,
+            z : self.x * other.y - self.y * other.x,
+        }
+    }
+    pub fn mag_squared(self) -> S {
+        self.dot(self)
+    }
+    pub fn mag(self) -> S {
+        self.mag_squared().sqrt()
+    }
+    pub fn unit(self) -> Vec3d<S> {
+        self/self.mag()
+    }
+}
+
+impl<S : Float> Add for Vec3d<S> {
+    type Output = Vec3d<S>;
+    fn add(self, rhs: Vec3d<S>) -> Vec3d<S> {
+        Vec3d {
+            x : self.x + rhs.x,
+            y : self.y + rhs.y,
+            z : self.z + rhs.z,
         }
     }
 }
 
-impl MulAssign<Scalar> for Vec2d {
-    fn mul_assign(&mut self, rhs : Scalar) {
+impl<S : Float> AddAssign for Vec3d<S> {
+    fn add_assign(&mut self, rhs : Vec3d<S>) {
+        self.x += rhs.x;
+        self.y += rhs.y;
+        self.z += rhs.z;
+    }
+}
+
+impl<S : Float> Sub for Vec3d<S> {
+    type Output = Vec3d<S>;
+    fn sub(self, rhs: Vec3d<S>) -> Vec3d<S> {
+        Vec3d {
+            x : self.x - rhs.x,
+            y : self.y - rhs.y,
+            z : self.z - rhs.z,
+        }
+    }
+}
+
+impl<S : Float> SubAssign for Vec3d<S> {
+    fn sub_assign(&mut self, rhs : Vec3d<S>) {
+        self.x -= rhs.x;
+        self.y -= rhs.y;
+        self.z -= rhs.z;
+    }
+}
+
+impl<S : Float> Mul<S> for Vec3d<S> {
+    type Output = Vec3d<S>;
+    fn mul(self, rhs : S) -> Vec3d<S> {
+        Vec3d {
+            x : self.x * rhs,
+            y : self.y * rhs,
+            z : self.z * rhs,
+        }
+    }
+}
+
+impl<S : Float> MulAssign<S> for Vec3d<S> {
+    fn mul_assign(&mut self, rhs : S) {
         self.x *= rhs;
         self.y *= rhs;
+        self.z *= rhs;
     }
 }
 
-impl Div<Scalar> for Vec2d {
-    type Output = Vec2d;
-    fn div(self, rhs : Scalar) -> Vec2d {
-        Vec2d {
+impl<S : Float> Div<S> for Vec3d<S> {
+    type Output = Vec3d<S>;
+    fn div(self, rhs : S) -> Vec3d<S> {
+        Vec3d {
             x : self.x / rhs,
             y : self.y / rhs,
+            z : self.z / rhs,
         }
     }
 }
 
-impl Sum for Vec2d {
-    fn sum<I : Iterator<Item=Vec2d>>(mut iter : I) -> Vec2d {
-        let mut retval = Vec2d::zero();
-        while let Some(v) = iter.next() {
-            retval += v;
+impl<S : Float> Sum for Vec3d<S> {
+    fn sum<I : Iterator<Item=Vec3d<S>>>(iter : I) -> Vec3d<S> {
+        let (mut x, mut y, mut z) = (NeumaierSum::new(), NeumaierSum::new(), NeumaierSum::new());
+        for v in iter {
+            x.add(v.x);
+            y.add(v.y);
+            z.add(v.z);
         }
-        retval
+        Vec3d::new(x.value(), y.value(), z.value())
     }
 }
 
-impl From<(Scalar, Scalar)> for Vec2d {
-    fn from(inner : (Scalar, Scalar)) -> Vec2d {
-        Vec2d {
+impl<S : Float> From<(S, S, S)> for Vec3d<S> {
+    fn from(inner : (S, S, S)) -> Vec3d<S> {
+        Vec3d {
             x : inner.0, 
             y : inner.1, 
+            z : inner.2,
         }
     }
 }
 
-impl From<Vec2d> for (Scalar, Scalar) {
-    fn from(wrapped : Vec2d) -> (Scalar, Scalar) {
-        (wrapped.x, wrapped.y)
+impl<S : Float> From<Vec3d<S>> for (S, S, S) {
+    fn from(wrapped : Vec3d<S>) -> (S, S, S) {
+        (wrapped.x, wrapped.y, wrapped.z)
     }
-}
\ No newline at end of file
+}
+
+// `scalar * vector` has to be spelled out per type: a blanket
+// `impl<S> Mul<Vec2d<S>> for S` is not allowed by the orphan rules.
+macro_rules! impl_scalar_mul {
+    ($t:ident) => {
+        impl Mul<Vec2d<$t>> for $t {
+            type Output = Vec2d<$t>;
+            fn mul(self, rhs : Vec2d<$t>) -> Vec2d<$t> {
+                Vec2d {
+                    x : rhs.x * self,
+                    y : rhs.y * self,
+                }
+            }
+        }
+        impl Mul<Vec3d<$t>> for $t {
+            type Output = Vec3d<$t>;
+            fn mul(self, rhs : Vec3d<$t>) -> Vec3d<$t> {
+                Vec3d {
+                    x : rhs.x * self,
+                    y : rhs.y * self,
+                    z : rhs.z * self,
+                }
+            }
+        }
+    }
+}
+
+impl_scalar_mul!(f32);
+impl_scalar_mul!(f64);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compensated_sum_keeps_what_naive_summation_cancels() {
+        let vals = [1.0, 1e100, 1.0, -1e100];
+        assert_eq!(vals.iter().fold(0.0, |acc, val| acc + val), 0.0);
+        assert_eq!(compensated_sum(vals.iter().cloned()), 2.0);
+    }
+
+    #[test]
+    fn vector_sum_is_compensated() {
+        let vals = (0..10).map(|_| Vec2d::new(1.0, -1.0));
+        let naive = vals.clone().fold(Vec2d::new(1e16, -1e16), |acc, val| acc + val);
+        let summed = std::iter::once(Vec2d::new(1e16, -1e16)).chain(vals).sum::<Vec2d>();
+        assert_eq!((naive.x, naive.y), (1e16, -1e16));
+        assert_eq!((summed.x, summed.y), (1e16 + 10.0, -1e16 - 10.0));
+    }
+
+    #[test]
+    fn compensated_add_keeps_increments_below_an_ulp() {
+        let (mut naive, mut value, mut compensation) = (1.0f32, 1.0f32, 0.0f32);
+        for _ in 0..100_000 {
+            naive += 1e-8;
+            compensated_add(&mut value, &mut compensation, 1e-8);
+        }
+        assert_eq!(naive, 1.0);
+        assert!((value - 1.001).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,242 @@
+use crate::mathvec::{Scalar, Vec2d};
+use crate::physics_handler::PhysicsHandler;
+
+use std::error::Error;
+use std::fmt;
+
+/// Conserved quantities of a handler at one point of a run.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ConservationSample {
+    pub step : usize,
+    pub energy : Scalar,
+    pub momentum : Vec2d,
+    pub angular_momentum : Scalar,
+    pub center_of_mass : Vec2d,
+}
+
+impl ConservationSample {
+    pub fn of<H : PhysicsHandler>(handler : &H, step : usize) -> ConservationSample {
+        ConservationSample {
+            step,
+            energy : handler.total_energy(),
+            momentum : handler.total_momentum(),
+            angular_momentum : handler.angular_momentum(),
+            center_of_mass : handler.total_mass_pos()/handler.total_mass(),
+        }
+    }
+}
+
+/// Drift of a sample from the initial one. Energy and angular momentum are
+/// relative to their initial magnitude (absolute if that is zero), momentum
+/// is relative to the system's momentum scale sqrt(2 M T) and the centre of
+/// mass drift is a plain distance.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Drift {
+    pub energy : Scalar,
+    pub momentum : Scalar,
+    pub angular_momentum : Scalar,
+    pub center_of_mass : Scalar,
+}
+
+fn relative(value : Scalar, initial : Scalar) -> Scalar {
+    if initial != 0.0 {
+        ((value - initial)/initial).abs()
+    } else {
+        value.abs()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DriftAction {
+    Warn,
+    Halt,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct DriftError {
+    pub quantity : &'static str,
+    pub drift : Scalar,
+    pub threshold : Scalar,
+    pub step : usize,
+}
+
+impl fmt::Display for DriftError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} drifted by {:e} (threshold {:e}) at step {}", self.quantity, self.drift, self.threshold, self.step)
+    }
+}
+
+impl Error for DriftError {}
+
+/// Samples the conserved quantities of a running simulation every
+/// `interval` steps and compares them with the first sample. A drift past
+/// one of the configured thresholds is returned as a warning or, with
+/// `halt_on_drift`, as an error so the run can stop.
+pub struct ConservationMonitor {
+    interval : usize,
+    steps : usize,
+    momentum_scale : Scalar,
+    initial : Option<ConservationSample>,
+    history : Vec<(ConservationSample, Drift)>,
+    energy_threshold : Option<Scalar>,
+    momentum_threshold : Option<Scalar>,
+    angular_momentum_threshold : Option<Scalar>,
+    center_of_mass_threshold : Option<Scalar>,
+    action : DriftAction,
+}
+
+impl ConservationMonitor {
+    pub fn new(interval : usize) -> ConservationMonitor {
+        ConservationMonitor {
+            interval : interval.max(1),
+            steps : 0,
+            momentum_scale : 0.0,
+            initial : None,
+            history : Vec::new(),
+            energy_threshold : None,
+            momentum_threshold : None,
+            angular_momentum_threshold : None,
+            center_of_mass_threshold : None,
+            action : DriftAction::Warn,
+        }
+    }
+
+    pub fn with_energy_threshold(self, threshold : Scalar) -> ConservationMonitor {
+        ConservationMonitor {
+            energy_threshold : Some(threshold),
+            ..self
+        }
+    }
+
+    pub fn with_momentum_threshold(self, threshold : Scalar) -> ConservationMonitor {
+        ConservationMonitor {
+            momentum_threshold : Some(threshold),
+            ..self
+        }
+    }
+
+    pub fn with_angular_momentum_threshold(self, threshold : Scalar) -> ConservationMonitor {
+        ConservationMonitor {
+            angular_momentum_threshold : Some(threshold),
+            ..self
+        }
+    }
+
+    pub fn with_center_of_mass_threshold(self, threshold : Scalar) -> ConservationMonitor {
+        ConservationMonitor {
+            center_of_mass_threshold : Some(threshold),
+            ..self
+        }
+    }
+
+    pub fn halt_on_drift(self) -> ConservationMonitor {
+        ConservationMonitor {
+            action : DriftAction::Halt,
+            ..self
+        }
+    }
+
+    pub fn initial(&self) -> Option<&ConservationSample> {
+        self.initial.as_ref()
+    }
+
+    pub fn history(&self) -> &[(ConservationSample, Drift)] {
+        &self.history
+    }
+
+    pub fn latest_drift(&self) -> Option<Drift> {
+        self.history.last().map(|&(_, drift)| drift)
+    }
+
+    /// Forgets the initial sample, e.g. after particles were added.
+    pub fn reset(&mut self) {
+        self.initial = None;
+        self.history.clear();
+    }
+
+    /// Call once per step. The first call records the reference sample.
+    /// Thresholds exceeded, including drifts that became NaN, come back as
+    /// warnings or, with `halt_on_drift`, as the error.
+    ///
+    /// The interval counts calls, not integrated steps: the threaded
+    /// handler's `update` does nothing while its threads are busy, so call
+    /// this after its `step` to sample at exact step counts.
+    pub fn observe<H : PhysicsHandler>(&mut self, handler : &H) -> Result<Vec<DriftError>, DriftError> {
+        let step = self.steps;
+        self.steps += 1;
+        let initial = match self.initial {
+            Some(initial) => initial,
+            None => {
+                let initial = ConservationSample::of(handler, step);
+                self.momentum_scale = (2.0 * handler.total_mass() * handler.kinetic_energy()).sqrt();
+                self.initial = Some(initial);
+                self.history.push((initial, Drift::default()));
+                return Ok(Vec::new());
+            },
+        };
+        if !step.is_multiple_of(self.interval) {
+            return Ok(Vec::new());
+        }
+        let sample = ConservationSample::of(handler, step);
+        let momentum_diff = (sample.momentum - initial.momentum).mag();
+        let drift = Drift {
+            energy : relative(sample.energy, initial.energy),
+            momentum : if self.momentum_scale > 0.0 { momentum_diff/self.momentum_scale } else { momentum_diff },
+            angular_momentum : relative(sample.angular_momentum, initial.angular_momentum),
+            center_of_mass : (sample.center_of_mass - initial.center_of_mass).mag(),
+        };
+        self.history.push((sample, drift));
+
+        let checks = [
+            ("energy", drift.energy, self.energy_threshold),
+            ("momentum", drift.momentum, self.momentum_threshold),
+            ("angular momentum", drift.angular_momentum, self.angular_momentum_threshold),
+            ("centre of mass", drift.center_of_mass, self.center_of_mass_threshold),
+        ];
+        let mut warnings = Vec::new();
+        for &(quantity, value, threshold) in checks.iter() {
+            if let Some(threshold) = threshold {
+                if value.is_nan() || value > threshold {
+                    let err = DriftError { quantity, drift : value, threshold, step };
+                    match self.action {
+                        DriftAction::Warn => warnings.push(err),
+                        DriftAction::Halt => return Err(err),
+                    }
+                }
+            }
+        }
+        Ok(warnings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::particles::Particle;
+    use crate::physics_handler::hermite::PhysicsHandlerHermite;
+
+    fn pair(speed : Scalar) -> Vec<Particle> {
+        vec![
+            Particle::new(1.0, 0.0, Vec2d::zero(), Vec2d::zero()),
+            Particle::new(1.0, 0.0, Vec2d::new(1.0, 0.0), Vec2d::new(0.0, speed)),
+        ]
+    }
+
+    #[test]
+    fn nan_drift_is_reported() {
+        let mut handler = PhysicsHandlerHermite::new(1.0, 0.0, 0.0);
+        handler.load_particles(pair(1.0));
+        let mut monitor = ConservationMonitor::new(1).with_energy_threshold(0.1);
+        assert!(monitor.observe(&handler).unwrap().is_empty());
+        handler.load_particles(pair(Scalar::NAN));
+        let warnings = monitor.observe(&handler).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].quantity, "energy");
+
+        let mut monitor = ConservationMonitor::new(1).with_energy_threshold(0.1).halt_on_drift();
+        handler.load_particles(pair(1.0));
+        monitor.observe(&handler).unwrap();
+        handler.load_particles(pair(Scalar::NAN));
+        assert!(monitor.observe(&handler).is_err());
+    }
+}
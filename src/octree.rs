@@ -0,0 +1,342 @@
+use crate::mathvec::{Scalar, Vec3d};
+use crate::particles::{Particle3d};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Span3d {
+    plus_corner : Vec3d,
+    minus_corner : Vec3d,
+}
+
+impl Span3d {
+    fn empty() -> Span3d {
+        Span3d { plus_corner : Vec3d::zero(), minus_corner : Vec3d::zero() }
+    }
+
+    pub fn new(plus_corner : Vec3d, minus_corner : Vec3d) -> Span3d {
+        debug_assert!( 
+            (plus_corner == Vec3d::zero() && minus_corner == Vec3d::zero()) ||
+            (plus_corner.x > minus_corner.x && plus_corner.y > minus_corner.y && plus_corner.z > minus_corner.z)
+        );
+        Span3d { plus_corner, minus_corner }
+    }
+    pub fn midpoint(self) -> Vec3d {
+        (self.minus_corner + self.plus_corner)/2.0
+    }
+    pub fn subspans(self) -> [Span3d ; 8] {
+        let mut retval = [Span3d::empty() ; 8];
+        let mid = self.midpoint();
+        let xs = [self.minus_corner.x, mid.x, self.plus_corner.x];
+        let ys = [self.minus_corner.y, mid.y, self.plus_corner.y];
+        let zs = [self.minus_corner.z, mid.z, self.plus_corner.z];
+        for x_idx in 0..2 {
+            for y_idx in 0..2 {
+                for z_idx in 0..2 {
+                    retval[x_idx * 4 + y_idx * 2 + z_idx] = Span3d::new(
+                        Vec3d::new(xs[x_idx + 1], ys[y_idx + 1], zs[z_idx + 1]),
+                        Vec3d::new(xs[x_idx], ys[y_idx], zs[z_idx]),
+                    );
+                }
+            }
+        }
+        retval
+    }
+    pub fn subspan_idx_for(self, part : Particle3d) -> usize {
+        let mid = self.midpoint();
+        let x_idx = if part.pos.x < mid.x { 0 } else { 1 };
+        let y_idx = if part.pos.y < mid.y { 0 } else { 1 };
+        let z_idx = if part.pos.z < mid.z { 0 } else { 1 };
+        x_idx * 4 + y_idx * 2 + z_idx
+    }
+    pub fn width(self) -> Scalar {
+        self.plus_corner.x - self.minus_corner.x
+    }
+
+    pub fn volume(self) -> Scalar {
+        let shifted_spans = self.plus_corner - self.minus_corner;
+        shifted_spans.x * shifted_spans.y * shifted_spans.z
+    }
+}
+
+type NodeIndex = u32;
+
+/// Squared opening angle of the Barnes-Hut criterion unless set otherwise.
+const DEFAULT_OPENING_SQUARED : Scalar = 0.8;
+
+/// Particles that still share a leaf this deep are merged into one, so
+/// coincident positions cannot split nodes forever.
+const MAX_DEPTH : usize = 32;
+
+#[derive(Copy, Clone)]
+pub struct OctreeNode {
+    span : Span3d,
+    data : NodeType,
+} 
+
+#[derive(Copy, Clone)]
+enum NodeType {
+    Empty,
+    Leaf(Particle3d),
+    Branch {
+        masspos : Vec3d,
+        mass : Scalar,
+        subnodes : [NodeIndex ; 8],
+    }
+}
+
+impl OctreeNode {
+    pub fn new(span : Span3d) -> OctreeNode {
+        OctreeNode {
+            span,
+            data : NodeType::Empty,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct MassOctreeBuilder {
+    node_capacity : usize, 
+    span : Span3d,
+    opening_squared : Scalar,
+}
+
+impl Default for MassOctreeBuilder {
+    fn default() -> Self {
+        MassOctreeBuilder {
+            node_capacity : 0,
+            span : Span3d::default(),
+            opening_squared : DEFAULT_OPENING_SQUARED,
+        }
+    }
+}
+
+impl MassOctreeBuilder {
+
+    pub fn new() -> Self {
+        MassOctreeBuilder::default()
+    }
+
+    /// A node is opened when its width is at least `theta` times its
+    /// distance; zero opens every node and gives the direct sum.
+    pub fn with_opening_angle(self, theta : Scalar) -> Self {
+        MassOctreeBuilder {
+            opening_squared : theta * theta,
+            ..self
+        }
+    }
+
+    pub fn with_span(self, span : Span3d) -> Self {
+        MassOctreeBuilder {
+            span, 
+            ..self
+        }
+    }
+
+    pub fn with_particle_capacity(self, cap : usize) -> Self {
+        let node_cap = ( (cap as f64) * (cap as f64).log(8.0) ) as usize + 1; 
+        self.with_node_capacity(node_cap)
+    }
+
+    pub fn with_node_capacity(self, cap : usize) -> Self {
+        MassOctreeBuilder {
+            node_capacity : cap, 
+            ..self
+        }
+    }
+
+    pub fn build(self) -> MassOctree {
+        let mut nodes = Vec::with_capacity(self.node_capacity);
+        nodes.push(OctreeNode::new(self.span));
+        MassOctree {
+            nodes,
+            opening_squared : self.opening_squared,
+        }
+    }
+}
+
+/// Three-dimensional counterpart of `MassTree`.
+pub struct MassOctree {
+    nodes : Vec<OctreeNode>,
+    opening_squared : Scalar,
+}
+
+impl Default for MassOctree {
+    fn default() -> Self {
+        MassOctreeBuilder::default().build()
+    }
+}
+
+impl MassOctree {
+    pub fn builder() -> MassOctreeBuilder {
+        MassOctreeBuilder::new()
+    }
+    fn leaf_to_branch(&mut self, parent_idx : NodeIndex) {
+        let (span, part) = match self.nodes[parent_idx as usize] {
+            OctreeNode{span, data : NodeType::Leaf(p)} => (span, p),
+            _ => {
+                return;
+            }
+        };
+
+        let start_len = self.nodes.len();
+        let mut new_children = [0 ; 8];
+        let spans = span.subspans();
+        for offset in 0..8 {
+            self.nodes.push(OctreeNode::new(spans[offset]));
+            new_children[offset] = (start_len + offset) as NodeIndex;
+        }
+        let child = new_children[span.subspan_idx_for(part)];
+        self.nodes[child as usize].data = NodeType::Leaf(part);
+        self.nodes[parent_idx as usize].data = NodeType::Branch {
+            mass : part.mass,
+            masspos : part.mass * part.pos,
+            subnodes : new_children,
+        };
+    }
+    pub fn add_particle(&mut self, particle : Particle3d) {
+        debug_assert!(self.nodes.first().is_some_and(|n| n.span.volume() > 0.0));
+        let mut cur_idx : NodeIndex = 0;
+        let mut depth = 0;
+        loop {
+            let cur_node = &mut self.nodes[cur_idx as usize];
+            match cur_node.data {
+                NodeType::Empty => {
+                    cur_node.data = NodeType::Leaf(particle);
+                    return;
+                },
+                NodeType::Leaf(p) => {
+                    if p == particle {
+                        return;
+                    }
+                    if depth >= MAX_DEPTH {
+                        cur_node.data = NodeType::Leaf(merged(p, particle));
+                        return;
+                    }
+                    self.leaf_to_branch(cur_idx);
+                },
+                NodeType::Branch{subnodes, ref mut mass, ref mut masspos} => {
+                    *mass += particle.mass;
+                    *masspos += particle.mass * particle.pos;
+                    cur_idx = subnodes[cur_node.span.subspan_idx_for(particle)];
+                    depth += 1;
+                }
+            }
+        }
+    }
+    pub fn calculate_forces(&self, arg : Particle3d, G : Scalar) -> Vec3d {
+        let mut retval = Vec3d::zero();
+        let mut to_calc : Vec<NodeIndex> = Vec::with_capacity(8);
+        to_calc.push(0);
+        while let Some(cur_idx) = to_calc.pop() {
+            match self.nodes[cur_idx as usize] {
+                OctreeNode{span, data : NodeType::Branch{ masspos, mass, subnodes }} => {
+                    let pt = masspos/mass;
+                    let diff = pt - arg.pos;
+                    let d = diff.mag_squared();
+                    let diffw = span.width();
+                    if diffw * diffw >= d * self.opening_squared {
+                        to_calc.extend_from_slice(&subnodes);
+                    }
+                    else {
+                        let force = G * arg.mass * mass/d; 
+                        let d = d.sqrt();
+                        if d > 0.5 {
+                            retval += diff * force/d;
+                        }
+                    }
+                },
+                OctreeNode{data : NodeType::Leaf(part), ..} => {
+                    let diff = part.pos - arg.pos; 
+                    let d = diff.mag_squared();
+                    let force = G * arg.mass * part.mass/d;
+                    let d = d.sqrt();
+                    if d > 0.5 {
+                        retval += diff * force/d; 
+                    }
+                },
+                _ => {},
+            }
+        }
+        retval
+    }
+}
+
+/// One point mass standing in for `a` and `b` at their centre of mass.
+fn merged(a : Particle3d, b : Particle3d) -> Particle3d {
+    let mass = a.mass + b.mass;
+    let pos = if mass > 0.0 {
+        (a.mass * a.pos + b.mass * b.pos)/mass
+    } else {
+        a.pos
+    };
+    Particle3d {
+        pos,
+        mass,
+        ..a
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cloud() -> Vec<Particle3d> {
+        (0..60).map(|i| {
+            let i = i as Scalar;
+            let pos = Vec3d::new(10.0 * (1.3 * i).sin(), 10.0 * (0.7 * i).cos(), 10.0 * (2.1 * i).sin());
+            Particle3d::new(1.0 + 0.1 * (i % 7.0), 0.5, pos, Vec3d::zero())
+        })
+        .collect()
+    }
+
+    fn build(parts : &[Particle3d], theta : Scalar) -> MassOctree {
+        let span = Span3d::new(Vec3d::new(16.0, 16.0, 16.0), Vec3d::new(-16.0, -16.0, -16.0));
+        let mut octree = MassOctree::builder()
+            .with_particle_capacity(parts.len())
+            .with_span(span)
+            .with_opening_angle(theta)
+            .build();
+        for part in parts {
+            octree.add_particle(*part);
+        }
+        octree
+    }
+
+    /// Direct sum with the same d > 0.5 cutoff as the tree.
+    fn direct(parts : &[Particle3d], arg : Particle3d, G : Scalar) -> Vec3d {
+        parts.iter()
+            .map(|part| {
+                let diff = part.pos - arg.pos;
+                let d = diff.mag();
+                if d > 0.5 {
+                    diff * (G * arg.mass * part.mass/(d * d * d))
+                } else {
+                    Vec3d::zero()
+                }
+            })
+            .sum()
+    }
+
+    #[test]
+    fn zero_opening_angle_is_the_direct_sum() {
+        let parts = cloud();
+        let octree = build(&parts, 0.0);
+        for &part in parts.iter() {
+            let exact = direct(&parts, part, 2.0);
+            let error = (octree.calculate_forces(part, 2.0) - exact).mag();
+            assert!(error <= 1e-12 * exact.mag(), "error {}", error);
+        }
+    }
+
+    #[test]
+    fn coincident_particles_are_merged() {
+        let mut parts = cloud();
+        let mut twin = parts[0];
+        twin.id = 1000;
+        twin.mass = 3.0;
+        parts.push(twin);
+        let octree = build(&parts, 0.0);
+        let probe = parts[5];
+        let exact = direct(&parts, probe, 1.0);
+        assert!((octree.calculate_forces(probe, 1.0) - exact).mag() <= 1e-12 * exact.mag());
+    }
+}
@@ -0,0 +1,284 @@
+use crate::mathvec::{Scalar, Vec2d};
+use crate::particles::Particle;
+use crate::gridhandler::GridHandler;
+use crate::binio::*;
+
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+/// A short-range central force between every pair of particles closer than
+/// `cutoff`, evaluated through the neighbor grid.
+pub trait PairForce : Send + Sync {
+    fn cutoff(&self) -> Scalar;
+
+    /// Magnitude of the force along the separation, positive when repulsive
+    /// (i.e. `-dU/dr`).
+    fn radial_force(&self, r : Scalar, arg : &Particle, other : &Particle) -> Scalar;
+
+    fn potential(&self, r : Scalar, arg : &Particle, other : &Particle) -> Scalar;
+
+    /// The built-in force this is, for checkpoints. Custom forces keep the
+    /// default and cannot be checkpointed.
+    fn builtin(&self) -> Option<BuiltinPairForce> {
+        None
+    }
+
+    /// Force on `arg` due to `other`.
+    fn force(&self, arg : &Particle, other : &Particle) -> Vec2d {
+        let diff = arg.pos - other.pos;
+        let d = diff.mag_squared();
+        let cutoff = self.cutoff();
+        if d < 0.00001 || d >= cutoff * cutoff {
+            return Vec2d::zero();
+        }
+        let d = d.sqrt();
+        diff * (self.radial_force(d, arg, other)/d)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct LennardJones {
+    pub epsilon : Scalar,
+    pub sigma : Scalar,
+    pub cutoff : Scalar,
+}
+
+impl LennardJones {
+    pub fn new(epsilon : Scalar, sigma : Scalar) -> LennardJones {
+        LennardJones {
+            epsilon,
+            sigma,
+            cutoff : 2.5 * sigma,
+        }
+    }
+    pub fn with_cutoff(self, cutoff : Scalar) -> LennardJones {
+        LennardJones {
+            cutoff,
+            ..self
+        }
+    }
+}
+
+impl PairForce for LennardJones {
+    fn cutoff(&self) -> Scalar {
+        self.cutoff
+    }
+    fn builtin(&self) -> Option<BuiltinPairForce> {
+        Some(BuiltinPairForce::LennardJones(*self))
+    }
+    fn radial_force(&self, r : Scalar, _arg : &Particle, _other : &Particle) -> Scalar {
+        let sr6 = (self.sigma/r).powi(6);
+        24.0 * self.epsilon * (2.0 * sr6 * sr6 - sr6)/r
+    }
+    fn potential(&self, r : Scalar, _arg : &Particle, _other : &Particle) -> Scalar {
+        let sr6 = (self.sigma/r).powi(6);
+        4.0 * self.epsilon * (sr6 * sr6 - sr6)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Yukawa {
+    pub strength : Scalar,
+    pub screening_length : Scalar,
+    pub cutoff : Scalar,
+}
+
+impl Yukawa {
+    pub fn new(strength : Scalar, screening_length : Scalar) -> Yukawa {
+        Yukawa {
+            strength,
+            screening_length,
+            cutoff : 5.0 * screening_length,
+        }
+    }
+    pub fn with_cutoff(self, cutoff : Scalar) -> Yukawa {
+        Yukawa {
+            cutoff,
+            ..self
+        }
+    }
+}
+
+impl PairForce for Yukawa {
+    fn cutoff(&self) -> Scalar {
+        self.cutoff
+    }
+    fn builtin(&self) -> Option<BuiltinPairForce> {
+        Some(BuiltinPairForce::Yukawa(*self))
+    }
+    fn radial_force(&self, r : Scalar, _arg : &Particle, _other : &Particle) -> Scalar {
+        let screen = (-r/self.screening_length).exp();
+        self.strength * screen * (1.0/(r * r) + 1.0/(self.screening_length * r))
+    }
+    fn potential(&self, r : Scalar, _arg : &Particle, _other : &Particle) -> Scalar {
+        self.strength * (-r/self.screening_length).exp()/r
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Morse {
+    pub depth : Scalar,
+    pub width : Scalar,
+    pub equilibrium : Scalar,
+    pub cutoff : Scalar,
+}
+
+impl Morse {
+    pub fn new(depth : Scalar, width : Scalar, equilibrium : Scalar) -> Morse {
+        Morse {
+            depth,
+            width,
+            equilibrium,
+            cutoff : equilibrium + 5.0/width,
+        }
+    }
+    pub fn with_cutoff(self, cutoff : Scalar) -> Morse {
+        Morse {
+            cutoff,
+            ..self
+        }
+    }
+}
+
+impl PairForce for Morse {
+    fn cutoff(&self) -> Scalar {
+        self.cutoff
+    }
+    fn builtin(&self) -> Option<BuiltinPairForce> {
+        Some(BuiltinPairForce::Morse(*self))
+    }
+    fn radial_force(&self, r : Scalar, _arg : &Particle, _other : &Particle) -> Scalar {
+        let decay = (-self.width * (r - self.equilibrium)).exp();
+        -2.0 * self.depth * self.width * decay * (1.0 - decay)
+    }
+    fn potential(&self, r : Scalar, _arg : &Particle, _other : &Particle) -> Scalar {
+        let decay = (-self.width * (r - self.equilibrium)).exp();
+        self.depth * ((1.0 - decay) * (1.0 - decay) - 1.0)
+    }
+}
+
+/// The pair forces a checkpoint knows how to store.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BuiltinPairForce {
+    LennardJones(LennardJones),
+    Yukawa(Yukawa),
+    Morse(Morse),
+}
+
+impl BuiltinPairForce {
+    pub fn write_to<W : Write>(&self, out : &mut W) -> io::Result<()> {
+        let (tag, params) = match *self {
+            BuiltinPairForce::LennardJones(f) => (0, [f.epsilon, f.sigma, 0.0, f.cutoff]),
+            BuiltinPairForce::Yukawa(f) => (1, [f.strength, f.screening_length, 0.0, f.cutoff]),
+            BuiltinPairForce::Morse(f) => (2, [f.depth, f.width, f.equilibrium, f.cutoff]),
+        };
+        write_u8(out, tag)?;
+        for &param in params.iter() {
+            write_f64(out, param)?;
+        }
+        Ok(())
+    }
+    pub fn read_from<R : Read>(input : &mut R) -> io::Result<BuiltinPairForce> {
+        let tag = read_u8(input)?;
+        let mut params = [0.0; 4];
+        for param in params.iter_mut() {
+            *param = read_f64(input)?;
+        }
+        let [a, b, c, cutoff] = params;
+        Ok(match tag {
+            0 => BuiltinPairForce::LennardJones(LennardJones::new(a, b).with_cutoff(cutoff)),
+            1 => BuiltinPairForce::Yukawa(Yukawa::new(a, b).with_cutoff(cutoff)),
+            2 => BuiltinPairForce::Morse(Morse::new(a, b, c).with_cutoff(cutoff)),
+            _ => return Err(invalid_data("unknown pair force")),
+        })
+    }
+
+    pub fn into_pair_force(self) -> Arc<dyn PairForce> {
+        match self {
+            BuiltinPairForce::LennardJones(f) => Arc::new(f),
+            BuiltinPairForce::Yukawa(f) => Arc::new(f),
+            BuiltinPairForce::Morse(f) => Arc::new(f),
+        }
+    }
+}
+
+pub fn max_cutoff(forces : &[Arc<dyn PairForce>]) -> Scalar {
+    forces.iter().fold(0.0, |acc : Scalar, f| acc.max(f.cutoff()))
+}
+
+/// Builds a grid whose cells are one cutoff wide, so the 3x3 block around a
+/// particle holds every partner it can interact with.
+pub fn build_pair_grid(parts : &[Particle], forces : &[Arc<dyn PairForce>]) -> GridHandler {
+    let cutoff = max_cutoff(forces);
+    if cutoff <= 0.0 {
+        return GridHandler::new(0.0, 0);
+    }
+    let mut grid = GridHandler::new(1.0/cutoff, parts.len());
+    for part in parts {
+        grid.add_particle(*part);
+    }
+    grid
+}
+
+pub fn calculate_pair_forces(grid : &GridHandler, arg : Particle, forces : &[Arc<dyn PairForce>]) -> Vec2d {
+    let mut retval = Vec2d::zero();
+    for other in grid.neighbors(arg.pos) {
+        for force in forces {
+            retval += force.force(&arg, other);
+        }
+    }
+    retval
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dummy() -> Particle {
+        Particle::new(1.0, 0.5, Vec2d::zero(), Vec2d::zero())
+    }
+
+    #[test]
+    fn lennard_jones_minimum_sits_at_two_to_the_sixth_sigma() {
+        let lj = LennardJones::new(0.7, 1.3);
+        let r_min = (2.0 as Scalar).powf(1.0/6.0) * 1.3;
+        let part = dummy();
+        assert!(lj.radial_force(r_min, &part, &part).abs() < 1e-12);
+        assert!((lj.potential(r_min, &part, &part) + 0.7).abs() < 1e-12);
+        assert!(lj.radial_force(0.99 * r_min, &part, &part) > 0.0);
+        assert!(lj.radial_force(1.01 * r_min, &part, &part) < 0.0);
+    }
+
+    #[test]
+    fn radial_force_is_minus_the_potential_derivative() {
+        let forces : Vec<Box<dyn PairForce>> = vec![
+            Box::new(LennardJones::new(0.7, 1.3)),
+            Box::new(Yukawa::new(2.0, 0.8)),
+            Box::new(Morse::new(1.5, 2.0, 1.1)),
+        ];
+        let (part, eps) = (dummy(), 1e-6);
+        for force in forces.iter() {
+            for &r in [0.9, 1.1, 1.5, 2.2, 3.0].iter() {
+                let derivative = (force.potential(r + eps, &part, &part) - force.potential(r - eps, &part, &part))/(2.0 * eps);
+                let radial = force.radial_force(r, &part, &part);
+                assert!((radial + derivative).abs() < 1e-6 * radial.abs().max(1.0), "{:?} at {}", force.builtin(), r);
+            }
+        }
+    }
+
+    #[test]
+    fn builtin_forces_round_trip() {
+        let forces = [
+            BuiltinPairForce::LennardJones(LennardJones::new(0.7, 1.3).with_cutoff(4.0)),
+            BuiltinPairForce::Yukawa(Yukawa::new(2.0, 0.8)),
+            BuiltinPairForce::Morse(Morse::new(1.5, 2.0, 1.1).with_cutoff(3.5)),
+        ];
+        for force in forces.iter() {
+            let mut buffer = Vec::new();
+            force.write_to(&mut buffer).unwrap();
+            let read = BuiltinPairForce::read_from(&mut buffer.as_slice()).unwrap();
+            assert_eq!(read, *force);
+            assert_eq!(read.into_pair_force().builtin(), Some(*force));
+        }
+    }
+}
@@ -1,34 +1,99 @@
-use crate::mathvec::{Scalar, Vec2d};
-use crate::particles::Particle;
+use crate::mathvec::{Scalar, Vec2d, Vec3d};
+use crate::particles::{Particle, Particle3d, GasState};
+use crate::bonds::{Bond, AngleBond, BondList};
+use crate::rng::SimRng;
+use crate::snapshot::{Snapshot, SnapshotField};
+use crate::gadget::GadgetSnapshot;
+use crate::textformat::{self, TextColumn};
 use std::vec::Vec;
-use std::io::{self, Read, Write, BufReader, BufRead};
+use std::io::{self, Write, BufReader, BufWriter};
 use std::path::{Path};
 use std::fs::{File,OpenOptions};
 use std::iter::Iterator;
-use std::str::FromStr;
 
-use rand::distributions::{Normal, Distribution};
+use rand::Rng;
 
 const HALF_PI : Scalar = std::f64::consts::FRAC_PI_2;
-const GOLDEN : Scalar = 2.39996322972865332;
+const GOLDEN : Scalar = 2.399_963_229_728_653;
+const TWO_PI : Scalar = 2.0 * std::f64::consts::PI;
+const R2_A : Scalar = 0.7548776662466927;
+const R2_B : Scalar = 0.5698402909980532;
+
+/// Seed of `ParticleManager::new`.
+pub const DEFAULT_SEED : u64 = 0;
+
+// Placement goes through the `libm` crate rather than the platform's maths
+// library, whose transcendental functions differ in the last bit between
+// systems. Arithmetic and `sqrt` are exactly rounded everywhere already.
+
+fn sin_cos(angle : Scalar) -> (Scalar, Scalar) {
+    (libm::sin(angle), libm::cos(angle))
+}
+
+/// Standard normal deviate by the Box-Muller transform.
+fn standard_normal<R : Rng>(rng : &mut R) -> Scalar {
+    let u1 = 1.0 - rng.gen::<Scalar>();
+    let u2 : Scalar = rng.gen();
+    (-2.0 * libm::log(u1)).sqrt() * libm::cos(TWO_PI * u2)
+}
+
+fn random_direction<R : Rng>(rng : &mut R) -> Vec3d {
+    let cos_polar : Scalar = rng.gen_range(-1.0, 1.0);
+    let sin_polar = (1.0 - cos_polar * cos_polar).sqrt();
+    let (phi_sin, phi_cos) = sin_cos(rng.gen_range(0.0, TWO_PI));
+    Vec3d::new(sin_polar * phi_cos, sin_polar * phi_sin, cos_polar)
+}
 
 pub struct ParticleManager {
     pos : Vec2d,
     vel : Vec2d,
+    pos_z : Scalar,
+    vel_z : Scalar,
     particle_radius : Scalar,
     particle_mass : Scalar,
+    particle_charge : Scalar,
+    gas : Option<GasState>,
+    bond_damping : Scalar,
+    species : Option<u32>,
+    current_species : u32,
+    groups_placed : u32,
+    next_id : u64,
+    rng : SimRng,
     masses : Vec<Particle>,
+    bonds : BondList,
+    rigid_groups : Vec<Vec<usize>>,
+    masses3d : Vec<Particle3d>,
+}
+
+impl Default for ParticleManager {
+    fn default() -> ParticleManager {
+        ParticleManager::new()
+    }
 }
 
 impl ParticleManager {
 
+    /// Starts from `DEFAULT_SEED`; use `with_seed` for a different run.
     pub fn new() -> ParticleManager {
         ParticleManager {
             pos : Vec2d::zero(),
             vel : Vec2d::zero(),
+            pos_z : 0.0,
+            vel_z : 0.0,
             particle_radius : 0.0,
             particle_mass : 0.0,
+            particle_charge : 0.0,
+            gas : None,
+            bond_damping : 0.0,
+            species : None,
+            current_species : 0,
+            groups_placed : 0,
+            next_id : 0,
+            rng : SimRng::from_seed(DEFAULT_SEED),
             masses : Vec::new(),
+            bonds : BondList::new(),
+            rigid_groups : Vec::new(),
+            masses3d : Vec::new(),
         }
     }
 
@@ -44,6 +109,20 @@ impl ParticleManager {
             ..self
         }
     }
+    pub fn with_pos_3d(self, npos : Vec3d) -> ParticleManager {
+        ParticleManager {
+            pos : npos.xy(),
+            pos_z : npos.z,
+            ..self
+        }
+    }
+    pub fn with_vel_3d(self, nvel : Vec3d) -> ParticleManager {
+        ParticleManager {
+            vel : nvel.xy(),
+            vel_z : nvel.z,
+            ..self
+        }
+    }
     pub fn with_mass(self, nmass : Scalar) -> ParticleManager {
         ParticleManager {
             particle_mass : nmass,
@@ -56,13 +135,106 @@ impl ParticleManager {
             ..self
         }
     }
+    pub fn with_charge(self, ncharge : Scalar) -> ParticleManager {
+        ParticleManager {
+            particle_charge : ncharge,
+            ..self
+        }
+    }
+    pub fn with_gas(self, internal_energy : Scalar, smoothing_length : Scalar) -> ParticleManager {
+        ParticleManager {
+            gas : Some(GasState::new(internal_energy, smoothing_length)),
+            ..self
+        }
+    }
+    pub fn without_gas(self) -> ParticleManager {
+        ParticleManager {
+            gas : None,
+            ..self
+        }
+    }
+
+    pub fn with_bond_damping(self, ndamping : Scalar) -> ParticleManager {
+        ParticleManager {
+            bond_damping : ndamping,
+            ..self
+        }
+    }
+
+    /// Every placement, stochastic or not, is computed from one generator
+    /// seeded here with portable arithmetic, so the same seed and the same
+    /// sequence of calls give bit-identical particles on any machine.
+    pub fn with_seed(self, seed : u64) -> ParticleManager {
+        ParticleManager {
+            rng : SimRng::from_seed(seed),
+            ..self
+        }
+    }
+
+    /// Continues from an existing generator, e.g. a checkpointed one; `seed`
+    /// then reports the seed that generator started from.
+    pub fn with_rng(self, rng : SimRng) -> ParticleManager {
+        ParticleManager {
+            rng,
+            ..self
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.rng.seed()
+    }
+
+    pub fn rng(&self) -> &SimRng {
+        &self.rng
+    }
+
+    /// Tags the particles of the following placements with `species`
+    /// instead of the default, which is the index of the placement call.
+    pub fn with_species(self, species : u32) -> ParticleManager {
+        ParticleManager {
+            species : Some(species),
+            ..self
+        }
+    }
+
+    pub fn without_species(self) -> ParticleManager {
+        ParticleManager {
+            species : None,
+            ..self
+        }
+    }
+
+    /// Starts a new placement call and picks the species its particles get.
+    fn begin_group(&mut self) {
+        self.current_species = self.species.unwrap_or(self.groups_placed);
+        self.groups_placed += 1;
+    }
+
+    fn push_particle(&mut self, part : Particle) {
+        self.masses.push(Particle { id : self.next_id, species : self.current_species, ..part });
+        self.next_id += 1;
+    }
+
+    fn push_particle_3d(&mut self, part : Particle3d) {
+        self.masses3d.push(Particle3d { id : self.next_id, species : self.current_species, ..part });
+        self.next_id += 1;
+    }
+
+    fn make_particle(&self, pos : Vec2d, vel : Vec2d) -> Particle {
+        let part = match self.gas {
+            Some(gas) => Particle::new_gas(self.particle_mass, pos, vel, gas.internal_energy, gas.smoothing_length),
+            None => Particle::new(self.particle_mass, self.particle_radius, pos, vel),
+        };
+        part.with_charge(self.particle_charge)
+    }
 
     pub fn place_ball(&mut self, N : usize, mult : Scalar, ang_vel : Scalar) {
+        self.begin_group();
         for idx in 0..N {
             let theta = (idx as Scalar) * GOLDEN;
             let r = mult * self.particle_radius * theta.sqrt();
 
-            let (theta_sin, theta_cos) = theta.sin_cos();
+            let (theta_sin, theta_cos) = sin_cos(theta);
             let direction = Vec2d::new(theta_cos, theta_sin);
             let position_offset = r * direction;
             let position = self.pos + position_offset;
@@ -70,66 +242,298 @@ impl ParticleManager {
             let vel_dir = direction.swap_xy().flip_x();
             let vel = self.vel + (r * ang_vel * vel_dir);
             
-            let n_part = Particle::new(self.particle_mass, self.particle_radius, position, vel);
-            self.masses.push(n_part);
+            let n_part = self.make_particle(position, vel);
+            self.push_particle(n_part);
         }
     }
 
+    /// Debris disk between `inner` and `outer` on circular orbits around a
+    /// body of `central_mass` sitting at the manager's position and velocity.
+    /// Uses the same golden angle spiral as `place_ball`, uniform in area.
+    pub fn place_keplerian_disk(&mut self, N : usize, inner : Scalar, outer : Scalar, central_mass : Scalar, G : Scalar) {
+        self.begin_group();
+        for idx in 0..N {
+            let theta = (idx as Scalar) * GOLDEN;
+            let fraction = (idx as Scalar + 0.5)/(N as Scalar);
+            let r = (inner * inner + fraction * (outer * outer - inner * inner)).sqrt();
+
+            let (theta_sin, theta_cos) = sin_cos(theta);
+            let direction = Vec2d::new(theta_cos, theta_sin);
+            let position = self.pos + r * direction;
+
+            let vel_dir = direction.swap_xy().flip_x();
+            let vel = self.vel + (G * central_mass/r).sqrt() * vel_dir;
+
+            let n_part = self.make_particle(position, vel);
+            self.push_particle(n_part);
+        }
+    }
+
+    fn add_bond(&mut self, a : usize, b : usize, stiffness : Scalar, max_strain : Option<Scalar>) {
+        let rest_length = (self.masses[b].pos - self.masses[a].pos).mag();
+        let bond = Bond::new(a, b, rest_length, stiffness).with_damping(self.bond_damping);
+        let bond = match max_strain {
+            Some(strain) => bond.with_max_strain(strain),
+            None => bond,
+        };
+        self.bonds.add_bond(bond);
+    }
+
+    pub fn place_bonded_lattice(&mut self, nx : usize, ny : usize, spacing : Scalar, stiffness : Scalar, max_strain : Option<Scalar>) {
+        self.begin_group();
+        let row_height = spacing * (3.0 as Scalar).sqrt()/2.0;
+        let start = self.masses.len();
+        let corner = self.pos - Vec2d::new(0.5 * spacing * (nx as Scalar - 1.0), 0.5 * row_height * (ny as Scalar - 1.0));
+        for row in 0..ny {
+            let shift = if row % 2 == 0 { 0.0 } else { 0.5 * spacing };
+            for col in 0..nx {
+                let pos = corner + Vec2d::new(col as Scalar * spacing + shift, row as Scalar * row_height);
+                let n_part = self.make_particle(pos, self.vel);
+                self.push_particle(n_part);
+            }
+        }
+        let idx = |row : usize, col : usize| start + row * nx + col;
+        for row in 0..ny {
+            for col in 0..nx {
+                if col + 1 < nx {
+                    self.add_bond(idx(row, col), idx(row, col + 1), stiffness, max_strain);
+                }
+                if row + 1 < ny {
+                    // Odd rows sit half a spacing to the right, so the two
+                    // neighbours above are (col - 1, col) or (col, col + 1).
+                    let (left, right) = if row % 2 == 0 { (col.checked_sub(1), Some(col)) } else { (Some(col), Some(col + 1)) };
+                    for up_col in left.into_iter().chain(right) {
+                        if up_col < nx {
+                            self.add_bond(idx(row, col), idx(row + 1, up_col), stiffness, max_strain);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn place_bonded_chain(&mut self, N : usize, spacing : Scalar, stiffness : Scalar, angle_stiffness : Scalar) {
+        self.begin_group();
+        let start = self.masses.len();
+        let first = self.pos - Vec2d::new(0.5 * spacing * (N as Scalar - 1.0), 0.0);
+        for idx in 0..N {
+            let n_part = self.make_particle(first + Vec2d::new(idx as Scalar * spacing, 0.0), self.vel);
+            self.push_particle(n_part);
+        }
+        for idx in start..(start + N).saturating_sub(1) {
+            self.add_bond(idx, idx + 1, stiffness, None);
+        }
+        if angle_stiffness > 0.0 {
+            for idx in (start + 1)..(start + N).saturating_sub(1) {
+                let rest_angle = AngleBond::angle_between(self.masses[idx - 1].pos, self.masses[idx].pos, self.masses[idx + 1].pos);
+                self.bonds.add_angle(AngleBond::new(idx - 1, idx, idx + 1, rest_angle, angle_stiffness));
+            }
+        }
+    }
+
+    pub fn place_rigid_ball(&mut self, N : usize, mult : Scalar, ang_vel : Scalar) {
+        let start = self.masses.len();
+        self.place_ball(N, mult, ang_vel);
+        self.rigid_groups.push((start..self.masses.len()).collect());
+    }
+
+    pub fn rigid_groups(&self) -> &[Vec<usize>] {
+        &self.rigid_groups
+    }
+
+    pub fn bonds(&self) -> &BondList {
+        &self.bonds
+    }
+
+    fn center_3d(&self) -> (Vec3d, Vec3d) {
+        (Vec3d::from_2d(self.pos, self.pos_z), Vec3d::from_2d(self.vel, self.vel_z))
+    }
+
+    pub fn place_ball_3d(&mut self, N : usize, mult : Scalar, ang_vel : Scalar) {
+        self.begin_group();
+        let (center, center_vel) = self.center_3d();
+        for idx in 0..N {
+            // Radius grows as the cube root of the index for a uniform fill;
+            // the direction follows the R2 low-discrepancy sequence so it is
+            // uncorrelated with the radius.
+            let r = mult * self.particle_radius * libm::cbrt(2.0 * idx as Scalar);
+            let cos_polar = 1.0 - 2.0 * (0.5 + idx as Scalar * R2_A).fract();
+            let sin_polar = (1.0 - cos_polar * cos_polar).max(0.0).sqrt();
+            let (theta_sin, theta_cos) = sin_cos(TWO_PI * (0.5 + idx as Scalar * R2_B).fract());
+            let direction = Vec3d::new(sin_polar * theta_cos, sin_polar * theta_sin, cos_polar);
+            let offset = r * direction;
+            let vel = center_vel + ang_vel * Vec3d::new(0.0, 0.0, 1.0).cross(offset);
+            self.push_particle_3d(Particle3d::new(self.particle_mass, self.particle_radius, center + offset, vel));
+        }
+    }
+
+    pub fn place_gaussian_3d(&mut self, N : usize, stdev : Scalar) {
+        self.begin_group();
+        let (center, center_vel) = self.center_3d();
+        for _idx in 0..N {
+            let offset = stdev * Vec3d::new(standard_normal(&mut self.rng), standard_normal(&mut self.rng), standard_normal(&mut self.rng));
+            self.push_particle_3d(Particle3d::new(self.particle_mass, self.particle_radius, center + offset, center_vel));
+        }
+    }
+
+    /// Plummer sphere of `N` equal-mass particles with scale length `scale`,
+    /// sampled as in Aarseth, Henon & Wielen (1974).
+    pub fn place_plummer_3d(&mut self, N : usize, scale : Scalar, G : Scalar) {
+        self.begin_group();
+        let (center, center_vel) = self.center_3d();
+        let total_mass = self.particle_mass * N as Scalar;
+        for _idx in 0..N {
+            let mass_frac : Scalar = self.rng.gen_range(1e-10, 0.999);
+            let r = scale/(libm::pow(mass_frac, -2.0/3.0) - 1.0).sqrt();
+
+            let escape = (2.0 * G * total_mass).sqrt() * libm::pow(r * r + scale * scale, -0.25);
+            let q = loop {
+                let q : Scalar = self.rng.gen();
+                let g : Scalar = self.rng.gen_range(0.0, 0.1);
+                if g < q * q * libm::pow(1.0 - q * q, 3.5) {
+                    break q;
+                }
+            };
+            let pos = center + r * random_direction(&mut self.rng);
+            let vel = center_vel + (q * escape) * random_direction(&mut self.rng);
+            self.push_particle_3d(Particle3d::new(self.particle_mass, self.particle_radius, pos, vel));
+        }
+    }
+
+    pub fn particles_3d(&self) -> &[Particle3d] {
+        &self.masses3d
+    }
+
     pub fn save<P : AsRef<Path>>(&self, file_name : P) -> Result<(), io::Error> {
         let my_particle_iterator = self.masses.iter().cloned();
         ParticleManager::save_from(file_name, my_particle_iterator)
     }
     pub fn save_from<P : AsRef<Path>, T : IntoIterator<Item=Particle>>(file_name : P, data : T) -> Result<(), io::Error> {
-        let mut fl = OpenOptions::new().create(true).write(true).open(file_name)?;
-        for part in data.into_iter() {
-            write!(&mut fl, "{} {} {} {} {} {}\n", part.pos.x, part.pos.y, part.vel.x, part.vel.y, part.mass, part.radius)?;
-        }
-        Ok(())
+        let mut fl = BufWriter::new(File::create(file_name)?);
+        textformat::write_particles(&mut fl, data)?;
+        fl.flush()
     }
 
+    /// Reads a text file in the format of `textformat::read_particles`.
+    /// Particles keep the ids and species given in the file; missing ones
+    /// get new ids and the current species. A malformed file adds nothing and
+    /// fails with a `textformat::ParseError` naming the line and column.
     pub fn load<P : AsRef<Path>>(&mut self, file_name : P) -> Result<(), io::Error> {
         let fl = OpenOptions::new().read(true).open(file_name)?;
-        let mut buf_fl = BufReader::new(fl);
-        
-        let mut linebuf = String::new();
-        while buf_fl.read_line(&mut linebuf)? > 0 {
-            let without_end = linebuf.trim_end_matches("\n");
-            let components_raw = without_end.split(" ");
-            let mut components_res = components_raw.map(f64::from_str);
-            
-            let pos_x = if let Some(Ok(f)) = components_res.next() { f } else { return Err(io::Error::from(io::ErrorKind::InvalidInput));};
-            let pos_y = if let Some(Ok(f)) = components_res.next() { f } else { return Err(io::Error::from(io::ErrorKind::InvalidInput));};
-            let vel_x = if let Some(Ok(f)) = components_res.next() { f } else { return Err(io::Error::from(io::ErrorKind::InvalidInput));};
-            let vel_y = if let Some(Ok(f)) = components_res.next() { f } else { return Err(io::Error::from(io::ErrorKind::InvalidInput));};
-            let mass  = if let Some(Ok(f)) = components_res.next() { f } else { return Err(io::Error::from(io::ErrorKind::InvalidInput));};
-            let rad   = if let Some(Ok(f)) = components_res.next() { f } else { return Err(io::Error::from(io::ErrorKind::InvalidInput));};
+        let loaded = textformat::read_particles(BufReader::new(fl))?;
+        self.begin_group();
+        let has_ids = loaded.columns.contains(&TextColumn::Id);
+        let has_species = loaded.columns.contains(&TextColumn::Species);
+        for part in loaded.particles {
+            let id = if has_ids { part.id } else { self.next_id };
+            let species = if has_species { part.species } else { self.current_species };
+            self.masses.push(Particle { id, species, ..part });
+            self.next_id = self.next_id.max(id.saturating_add(1));
+        }
+        Ok(())
+    }
 
-            let npart = Particle::new(mass, rad, Vec2d::new(pos_x, pos_y), Vec2d::new(vel_x, vel_y));
-            self.masses.push(npart);
+    /// Adds the particles of a binary `Snapshot` and returns its header
+    /// time. Stored ids and species are kept; snapshots without ids get new
+    /// ones and the current species, like an old text file.
+    pub fn load_binary<P : AsRef<Path>>(&mut self, file_name : P) -> Result<Scalar, io::Error> {
+        let snapshot = Snapshot::load(file_name)?;
+        self.begin_group();
+        let has_ids = snapshot.header.fields.contains(&SnapshotField::Id);
+        for part in snapshot.particles {
+            if has_ids {
+                self.next_id = self.next_id.max(part.id.saturating_add(1));
+                self.masses.push(part);
+            } else {
+                self.push_particle(part);
+            }
+        }
+        Ok(snapshot.header.time)
+    }
 
+    /// Adds the particles of a GADGET-2 snapshot, e.g. initial conditions
+    /// from an external tool, and returns its header time. IDs are kept and
+    /// the GADGET type becomes the species. GADGET has no radii, so the
+    /// non-gas particles get the current `with_radius`.
+    pub fn load_gadget<P : AsRef<Path>>(&mut self, file_name : P) -> Result<Scalar, io::Error> {
+        let snapshot = GadgetSnapshot::load(file_name)?;
+        self.begin_group();
+        for part in snapshot.particles {
+            let radius = if part.is_gas() { part.radius } else { self.particle_radius };
+            self.next_id = self.next_id.max(part.id.saturating_add(1));
+            self.masses.push(Particle { radius, ..part });
         }
-        Ok(())
+        Ok(snapshot.header.time)
     }
 
     pub fn place_gaussian(&mut self, N : usize, stdev : Scalar, rot1 : Scalar, rot2 : Scalar) {
-        let dist = Normal::new(0.0, stdev as f64);
-        let mut rng = rand::thread_rng();
-        //TODO: how do we create a specifically mt19937 chooser?
+        self.begin_group();
         for _idx in 0..N {
-            let pos = Vec2d::new(dist.sample(&mut rng), dist.sample(&mut rng));
-            let theta = HALF_PI + pos.y.atan2(pos.x);
+            let pos = stdev * Vec2d::new(standard_normal(&mut self.rng), standard_normal(&mut self.rng));
+            let theta = HALF_PI + libm::atan2(pos.y, pos.x);
             let dist = pos.mag();
             let r = dist; 
-            let mag = (-rot1 - rot2 * r * r).exp() * r;
-            let (y_comp, x_comp) = theta.sin_cos();
+            let mag = libm::exp(-rot1 - rot2 * r * r) * r;
+            let (y_comp, x_comp) = sin_cos(theta);
             let vel = mag * Vec2d::new(x_comp, y_comp);
 
-            let npart = Particle::new(self.particle_mass, self.particle_radius, self.pos + pos, self.vel + vel);
-            self.masses.push(npart);
+            let npart = self.make_particle(self.pos + pos, self.vel + vel);
+            self.push_particle(npart);
         }
     }
 
     pub fn into_inner(self) -> Vec<Particle> {
         self.masses
     }
-}
\ No newline at end of file
+
+    pub fn into_inner_3d(self) -> Vec<Particle3d> {
+        self.masses3d
+    }
+
+    pub fn into_inner_with_bonds(self) -> (Vec<Particle>, BondList) {
+        (self.masses, self.bonds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bits(vec : Vec3d) -> [u64; 3] {
+        [vec.x.to_bits(), vec.y.to_bits(), vec.z.to_bits()]
+    }
+
+    #[test]
+    fn seeded_placements_are_pinned() {
+        let mut man = ParticleManager::new().with_seed(42).with_mass(1.0).with_radius(1.0);
+        man.place_gaussian(2, 10.0, 0.1, 0.001);
+        man.place_gaussian_3d(1, 5.0);
+        man.place_plummer_3d(1, 1.0, 1.0);
+        let planar = man.masses.iter()
+            .map(|part| (bits(Vec3d::from_2d(part.pos, 0.0)), bits(Vec3d::from_2d(part.vel, 0.0))))
+            .collect::<Vec<_>>();
+        let spatial = man.masses3d.iter()
+            .map(|part| (bits(part.pos), bits(part.vel)))
+            .collect::<Vec<_>>();
+        // Positions and velocities as f64 bits; a change here means seeded
+        // runs no longer reproduce.
+        assert_eq!(planar, vec![
+            ([13835732659955367512, 13846118027744833127, 0], [4621342259377480682, 13834189938452078918, 0]),
+            ([4623361321067872381, 4618630967131052955, 0], [13840201232632059669, 4621500721429469140, 0]),
+        ]);
+        assert_eq!(spatial, vec![
+            ([13829802241598119839, 4610415657000611612, 13832554419242565362], [0, 0, 0]),
+            ([4598845784531514067, 13829434854280277224, 4593232643520073748], [4595845062710423102, 4585737080636045493, 4596248751369219779]),
+        ]);
+    }
+
+    #[test]
+    fn with_rng_reports_the_generator_seed() {
+        let mut man = ParticleManager::new().with_seed(5);
+        man.place_gaussian(3, 1.0, 0.0, 0.0);
+        let rng = man.rng().clone();
+        let resumed = ParticleManager::new().with_rng(rng);
+        assert_eq!(resumed.seed(), 5);
+        assert_eq!(resumed.rng(), man.rng());
+    }
+}
@@ -1,20 +1,93 @@
+use crate::mathvec::{Vec2d, Vec3d, Scalar, Float, cast};
+use crate::binio::*;
 
-use crate::mathvec::{Vec2d, Scalar};
+use std::io::{self, Read, Write};
 
 #[derive(Copy, Clone, PartialEq, Debug, Default)]
-pub struct Particle {
-    pub pos : Vec2d,
-    pub vel : Vec2d,
-    pub f_grav : Vec2d,
-    pub f_spring : Vec2d,
-    pub mass : Scalar,
-    pub radius : Scalar,
+pub struct GasState<S : Float = Scalar> {
+    pub internal_energy : S,
+    pub smoothing_length : S,
+    pub density : S,
+    pub pressure : S,
+    pub du_dt : S,
+}
+
+impl<S : Float> GasState<S> {
+    pub fn new(internal_energy : S, smoothing_length : S) -> GasState<S> {
+        GasState {
+            internal_energy,
+            smoothing_length,
+            ..GasState::default()
+        }
+    }
+    pub fn cast<T : Float>(self) -> GasState<T> {
+        GasState {
+            internal_energy : cast(self.internal_energy),
+            smoothing_length : cast(self.smoothing_length),
+            density : cast(self.density),
+            pressure : cast(self.pressure),
+            du_dt : cast(self.du_dt),
+        }
+    }
+    pub fn write_to<W : Write>(&self, out : &mut W) -> io::Result<()> {
+        write_float(out, self.internal_energy)?;
+        write_float(out, self.smoothing_length)?;
+        write_float(out, self.density)?;
+        write_float(out, self.pressure)?;
+        write_float(out, self.du_dt)
+    }
+    pub fn read_from<R : Read>(input : &mut R) -> io::Result<GasState<S>> {
+        Ok(GasState {
+            internal_energy : read_float(input)?,
+            smoothing_length : read_float(input)?,
+            density : read_float(input)?,
+            pressure : read_float(input)?,
+            du_dt : read_float(input)?,
+        })
+    }
+}
+
+/// Per particle state kept by the Hermite integrator next to each `Particle`:
+/// the acceleration and jerk of the last force evaluation, the acceleration at
+/// the start of the current step and the interpolated higher derivatives used
+/// for the timestep criterion.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct HermiteState {
+    pub acc : Vec2d,
+    pub jerk : Vec2d,
+    pub prev_acc : Vec2d,
+    pub snap : Vec2d,
+    pub crackle : Vec2d,
+}
+
+/// `P` is the precision particle state (position, velocity, mass) is stored
+/// in and `F` the precision forces are evaluated and stored in, so e.g.
+/// `Particle<f64, f32>` keeps f64 positions with f32 forces.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Particle<P : Float = Scalar, F : Float = P> {
+    pub pos : Vec2d<P>,
+    pub vel : Vec2d<P>,
+    pub f_grav : Vec2d<F>,
+    pub f_spring : Vec2d<F>,
+    pub f_hydro : Vec2d<F>,
+    pub f_ext : Vec2d<F>,
+    pub f_pair : Vec2d<F>,
+    pub f_elec : Vec2d<F>,
+    pub f_bond : Vec2d<F>,
+    pub mass : P,
+    pub radius : P,
+    pub charge : P,
+    pub gas : Option<GasState<P>>,
+    /// Persistent identity, kept through loading, removal and saving.
+    pub id : u64,
+    /// Free group tag, e.g. which `ParticleManager::place_*` call made it.
+    pub species : u32,
 }
 
-impl Eq for Particle {}
+impl<P : Float, F : Float> Eq for Particle<P, F> {}
 
-impl Particle {
-    pub fn new(mass : Scalar, radius : Scalar, pos : Vec2d, vel : Vec2d) -> Particle {
+impl<P : Float, F : Float> Particle<P, F> {
+    pub fn new(mass : P, radius : P, pos : Vec2d<P>, vel : Vec2d<P>) -> Particle<P, F> {
         Particle {
             pos, 
             vel,
@@ -22,6 +95,138 @@ impl Particle {
             radius,
             f_grav : Vec2d::zero(),
             f_spring : Vec2d::zero(), 
+            f_hydro : Vec2d::zero(),
+            f_ext : Vec2d::zero(),
+            f_pair : Vec2d::zero(),
+            f_elec : Vec2d::zero(),
+            f_bond : Vec2d::zero(),
+            charge : P::ZERO,
+            gas : None,
+            id : 0,
+            species : 0,
+        }
+    }
+    pub fn new_gas(mass : P, pos : Vec2d<P>, vel : Vec2d<P>, internal_energy : P, smoothing_length : P) -> Particle<P, F> {
+        Particle {
+            gas : Some(GasState::new(internal_energy, smoothing_length)),
+            ..Particle::new(mass, P::ZERO, pos, vel)
+        }
+    }
+    pub fn with_species(self, species : u32) -> Particle<P, F> {
+        Particle {
+            species,
+            ..self
+        }
+    }
+    pub fn with_charge(self, charge : P) -> Particle<P, F> {
+        Particle {
+            charge,
+            ..self
+        }
+    }
+    pub fn cast<P2 : Float, F2 : Float>(&self) -> Particle<P2, F2> {
+        Particle {
+            pos : self.pos.cast(),
+            vel : self.vel.cast(),
+            f_grav : self.f_grav.cast(),
+            f_spring : self.f_spring.cast(),
+            f_hydro : self.f_hydro.cast(),
+            f_ext : self.f_ext.cast(),
+            f_pair : self.f_pair.cast(),
+            f_elec : self.f_elec.cast(),
+            f_bond : self.f_bond.cast(),
+            mass : cast(self.mass),
+            radius : cast(self.radius),
+            charge : cast(self.charge),
+            gas : self.gas.map(GasState::cast),
+            id : self.id,
+            species : self.species,
         }
     }
-}
\ No newline at end of file
+    pub fn is_gas(&self) -> bool {
+        self.gas.is_some()
+    }
+    pub fn force(&self) -> Vec2d<F> {
+        self.f_grav + self.f_spring + self.f_hydro + self.f_ext + self.f_pair + self.f_elec + self.f_bond
+    }
+
+    /// Every field including the force components of the last evaluation.
+    pub fn write_to<W : Write>(&self, out : &mut W) -> io::Result<()> {
+        write_vec2d(out, self.pos)?;
+        write_vec2d(out, self.vel)?;
+        for force in [self.f_grav, self.f_spring, self.f_hydro, self.f_ext, self.f_pair, self.f_elec, self.f_bond].iter() {
+            write_vec2d(out, *force)?;
+        }
+        write_float(out, self.mass)?;
+        write_float(out, self.radius)?;
+        write_float(out, self.charge)?;
+        write_bool(out, self.gas.is_some())?;
+        if let Some(gas) = self.gas {
+            gas.write_to(out)?;
+        }
+        write_u64(out, self.id)?;
+        write_u32(out, self.species)
+    }
+    pub fn read_from<R : Read>(input : &mut R) -> io::Result<Particle<P, F>> {
+        let pos = read_vec2d(input)?;
+        let vel = read_vec2d(input)?;
+        let mut forces = [Vec2d::zero(); 7];
+        for force in forces.iter_mut() {
+            *force = read_vec2d(input)?;
+        }
+        let mass = read_float(input)?;
+        let radius = read_float(input)?;
+        let charge = read_float(input)?;
+        let gas = if read_bool(input)? { Some(GasState::read_from(input)?) } else { None };
+        Ok(Particle {
+            pos,
+            vel,
+            f_grav : forces[0],
+            f_spring : forces[1],
+            f_hydro : forces[2],
+            f_ext : forces[3],
+            f_pair : forces[4],
+            f_elec : forces[5],
+            f_bond : forces[6],
+            mass,
+            radius,
+            charge,
+            gas,
+            id : read_u64(input)?,
+            species : read_u32(input)?,
+        })
+    }
+}
+
+/// Always stored in `Scalar`; the 3D code is not generic over precision.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Particle3d {
+    pub pos : Vec3d,
+    pub vel : Vec3d,
+    pub f_grav : Vec3d,
+    pub f_spring : Vec3d,
+    pub mass : Scalar,
+    pub radius : Scalar,
+    pub id : u64,
+    pub species : u32,
+}
+
+impl Eq for Particle3d {}
+
+impl Particle3d {
+    pub fn new(mass : Scalar, radius : Scalar, pos : Vec3d, vel : Vec3d) -> Particle3d {
+        Particle3d {
+            pos, 
+            vel,
+            mass,
+            radius,
+            f_grav : Vec3d::zero(),
+            f_spring : Vec3d::zero(), 
+            id : 0,
+            species : 0,
+        }
+    }
+    pub fn force(&self) -> Vec3d {
+        self.f_grav + self.f_spring
+    }
+}
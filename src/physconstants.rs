@@ -1 +1,29 @@
-//TODO: this 
+use crate::mathvec::Scalar;
+
+/// SI value of one code unit of length (m), mass (kg) and time (s). The
+/// default leaves the simulation dimensionless.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Units {
+    pub length : Scalar,
+    pub mass : Scalar,
+    pub time : Scalar,
+}
+
+impl Default for Units {
+    fn default() -> Units {
+        Units {
+            length : 1.0,
+            mass : 1.0,
+            time : 1.0,
+        }
+    }
+}
+
+impl Units {
+    pub fn new(length : Scalar, mass : Scalar, time : Scalar) -> Units {
+        Units { length, mass, time }
+    }
+    pub fn velocity(&self) -> Scalar {
+        self.length/self.time
+    }
+}
@@ -0,0 +1,499 @@
+use super::{PhysicsHandler, adopt_ids, assign_ids, split_removed, kinetic_energy_of, direct_potential_energy};
+use crate::particles::{Particle, HermiteState};
+use crate::mathvec::{Scalar, Vec2d, compensated_sum};
+use crate::regularization::{self, RegularizationError};
+
+use std::sync::Arc;
+use std::thread;
+
+/// Fourth order Hermite predictor-corrector with direct summation of
+/// acceleration and jerk and a shared timestep from Aarseth's criterion.
+/// Meant for collisional systems (star clusters) at modest N; particles are
+/// treated as gravitating points, contact forces are not evaluated.
+///
+/// With `with_regularization` tight bound pairs are taken out of the direct
+/// summation between each other: their centre of mass follows the Hermite
+/// integration while the relative orbit is integrated in Levi-Civita
+/// coordinates, so hard binaries neither limit the shared timestep nor lose
+/// energy at pericentre. Only isolated pairs are regularized: a third body
+/// approaching a pair dissolves it and the encounter is integrated directly,
+/// with the shared timestep shrinking to resolve it. That keeps the scheme
+/// simple at the cost of speed during close triple encounters; chain
+/// regularization of such subsystems is not implemented.
+pub struct PhysicsHandlerHermite {
+    data : Vec<Particle>,
+    states : Vec<HermiteState>,
+    regularization_radius : Option<Scalar>,
+    pairs : Vec<(usize, usize)>,
+    num_threads : usize,
+    time : Scalar,
+    steps : usize,
+    initialized : bool,
+    /// Set by `initialize`: snap and crackle are unknown until the next
+    /// corrected step, so the timestep falls back to the |a|/|j| estimate.
+    bootstrap : bool,
+    next_id : u64,
+
+    G : Scalar,
+    softening : Scalar,
+    accuracy : Scalar,
+    initial_accuracy : Scalar,
+}
+
+impl PhysicsHandlerHermite {
+    pub fn with_threads(self, num_threads : usize) -> Self {
+        PhysicsHandlerHermite {
+            num_threads : num_threads.max(1),
+            ..self
+        }
+    }
+
+    pub fn with_softening(self, softening : Scalar) -> Self {
+        PhysicsHandlerHermite {
+            softening,
+            ..self
+        }
+    }
+
+    /// `eta` in Aarseth's criterion, typically 0.01 - 0.03.
+    pub fn with_accuracy(self, accuracy : Scalar) -> Self {
+        PhysicsHandlerHermite {
+            accuracy,
+            ..self
+        }
+    }
+
+    /// Regularize bound pairs closer than `radius`; a pair is dissolved again
+    /// once it separates beyond twice that or becomes unbound.
+    pub fn with_regularization(self, radius : Scalar) -> Self {
+        PhysicsHandlerHermite {
+            regularization_radius : Some(radius),
+            ..self
+        }
+    }
+
+    pub fn regularized_pairs(&self) -> &[(usize, usize)] {
+        &self.pairs
+    }
+
+    pub fn particles(&self) -> &[Particle] {
+        &self.data
+    }
+
+    pub fn states(&self) -> &[HermiteState] {
+        &self.states
+    }
+
+    pub fn time(&self) -> Scalar {
+        self.time
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+
+    fn partners(&self) -> Vec<Option<usize>> {
+        let mut partners = vec![None; self.data.len()];
+        for &(a, b) in self.pairs.iter() {
+            partners[a] = Some(b);
+            partners[b] = Some(a);
+        }
+        partners
+    }
+
+    /// Keeps existing pairs while they stay close and bound, then pairs up
+    /// newly formed tight binaries among the remaining particles. Returns
+    /// whether the set of pairs changed.
+    fn update_pairs(&mut self) -> bool {
+        let radius = match self.regularization_radius {
+            Some(radius) => radius,
+            None => return false,
+        };
+        let data = &self.data;
+        let G = self.G;
+        let old_len = self.pairs.len();
+        self.pairs.retain(|&(a, b)| {
+            let rel_pos = data[a].pos - data[b].pos;
+            let mu = G * (data[a].mass + data[b].mass);
+            rel_pos.mag() < 2.0 * radius && regularization::relative_energy(rel_pos, data[a].vel - data[b].vel, mu) < 0.0
+        });
+        let mut changed = self.pairs.len() != old_len;
+        let partners = self.partners();
+        let unpaired = (0..self.data.len())
+            .filter(|&idx| partners[idx].is_none())
+            .collect::<Vec<usize>>();
+        let candidates = unpaired.iter().map(|&idx| self.data[idx]).collect::<Vec<Particle>>();
+        for (a, b) in regularization::find_tight_pairs(&candidates, radius, self.G) {
+            self.pairs.push((unpaired[a], unpaired[b]));
+            changed = true;
+        }
+        changed
+    }
+
+    fn evaluate(&self, pos : &[Vec2d], vel : &[Vec2d]) -> Vec<(Vec2d, Vec2d)> {
+        let sources = Arc::new(self.data.iter().zip(pos.iter().zip(vel.iter()))
+            .map(|(part, (&pos, &vel))| (pos, vel, part.mass))
+            .collect::<Vec<(Vec2d, Vec2d, Scalar)>>());
+        let partners = Arc::new(self.partners());
+        let step_size = (sources.len() as f64)/(self.num_threads as f64);
+        let handles = (0..self.num_threads).map(|i| {
+            let start_idx = (step_size * i as f64) as usize;
+            let end_idx = (step_size * (i + 1) as f64) as usize;
+            let sources_ref = Arc::clone(&sources);
+            let partners_ref = Arc::clone(&partners);
+            let (G, eps2) = (self.G, self.softening * self.softening);
+            thread::spawn(move || {
+                (start_idx..end_idx)
+                    .map(|idx| acc_and_jerk(idx, &sources_ref, partners_ref[idx], G, eps2))
+                    .collect::<Vec<(Vec2d, Vec2d)>>()
+            })
+        })
+        .collect::<Vec<_>>();
+        handles.into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    }
+
+    fn initialize(&mut self) {
+        let pos = self.data.iter().map(|part| part.pos).collect::<Vec<_>>();
+        let vel = self.data.iter().map(|part| part.vel).collect::<Vec<_>>();
+        let evaluated = self.evaluate(&pos, &vel);
+        self.states = evaluated.into_iter()
+            .map(|(acc, jerk)| HermiteState {acc, jerk, prev_acc : acc, ..HermiteState::default()})
+            .collect();
+        self.store_forces();
+        self.initialized = true;
+        self.bootstrap = true;
+    }
+
+    fn store_forces(&mut self) {
+        for (part, state) in self.data.iter_mut().zip(self.states.iter()) {
+            part.f_grav = part.mass * state.acc;
+        }
+        for &(a, b) in self.pairs.iter() {
+            let (part_a, part_b) = (self.data[a], self.data[b]);
+            let rel_pos = part_a.pos - part_b.pos;
+            let r2 = rel_pos.mag_squared() + self.softening * self.softening;
+            let mutual = (self.G * part_a.mass * part_b.mass/(r2 * r2.sqrt())) * rel_pos;
+            self.data[a].f_grav -= mutual;
+            self.data[b].f_grav += mutual;
+        }
+    }
+
+    /// Relative orbit of every regularized pair at the end of a step of
+    /// length `dt`, perturbed by the difference of the members' external
+    /// accelerations extrapolated with their jerks.
+    fn advance_pairs(&self, dt : Scalar) -> Result<Vec<(Vec2d, Vec2d)>, RegularizationError> {
+        self.pairs.iter()
+            .map(|&(a, b)| {
+                let (part_a, part_b) = (self.data[a], self.data[b]);
+                let (state_a, state_b) = (self.states[a], self.states[b]);
+                let mu = self.G * (part_a.mass + part_b.mass);
+                let tidal = state_a.acc - state_b.acc;
+                let tidal_jerk = state_a.jerk - state_b.jerk;
+                regularization::advance_relative(part_a.pos - part_b.pos, part_a.vel - part_b.vel, mu, dt,
+                    |t| tidal + t * tidal_jerk)
+            })
+            .collect()
+    }
+
+    /// Replaces the relative part of each pair's state, keeping its centre
+    /// of mass.
+    fn set_relative(pos : &mut [Vec2d], vel : &mut [Vec2d], data : &[Particle], pairs : &[(usize, usize)], relative : &[(Vec2d, Vec2d)]) {
+        for (&(a, b), &(rel_pos, rel_vel)) in pairs.iter().zip(relative.iter()) {
+            let (m_a, m_b) = (data[a].mass, data[b].mass);
+            let total = m_a + m_b;
+            let cm_pos = (m_a * pos[a] + m_b * pos[b])/total;
+            let cm_vel = (m_a * vel[a] + m_b * vel[b])/total;
+            pos[a] = cm_pos + (m_b/total) * rel_pos;
+            pos[b] = cm_pos - (m_a/total) * rel_pos;
+            vel[a] = cm_vel + (m_b/total) * rel_vel;
+            vel[b] = cm_vel - (m_a/total) * rel_vel;
+        }
+    }
+
+    /// Shared timestep: the minimum of Aarseth's criterion over all
+    /// particles, or the |a|/|j| estimate until the first corrected step
+    /// after (re)initialization.
+    pub fn timestep(&self) -> Scalar {
+        self.states.iter()
+            .map(|state| {
+                let (a, j) = (state.acc.mag(), state.jerk.mag());
+                if self.bootstrap {
+                    if j > 0.0 { self.initial_accuracy * a/j } else { Scalar::INFINITY }
+                } else {
+                    let (s, c) = (state.snap.mag(), state.crackle.mag());
+                    let denominator = j * c + s * s;
+                    if denominator > 0.0 {
+                        (self.accuracy * (a * s + j * j)/denominator).sqrt()
+                    } else {
+                        Scalar::INFINITY
+                    }
+                }
+            })
+            .fold(Scalar::INFINITY, Scalar::min)
+    }
+
+    /// Re-forms the regularized pairs and, if they changed or the particle
+    /// set did, re-evaluates the forces. Must run before `timestep` is read
+    /// for the coming step.
+    fn prepare(&mut self) {
+        if self.update_pairs() || !self.initialized {
+            self.initialize();
+        }
+    }
+
+    /// Advances all particles by exactly `dt` with one predict-evaluate-correct
+    /// cycle. On error nothing has moved.
+    pub fn step(&mut self, dt : Scalar) -> Result<(), RegularizationError> {
+        self.prepare();
+        self.advance(dt)
+    }
+
+    /// Advances by `dt` in as many Aarseth-limited substeps as needed. On
+    /// error the particles are left at the start of the failed substep.
+    pub fn try_update(&mut self, dt : Scalar) -> Result<(), RegularizationError> {
+        if self.data.is_empty() {
+            return Ok(());
+        }
+        let mut remaining = dt;
+        while remaining > 0.0 {
+            self.prepare();
+            let h = self.timestep().min(remaining);
+            if h.is_nan() || h <= 0.0 {
+                break;
+            }
+            self.advance(h)?;
+            remaining -= h;
+        }
+        Ok(())
+    }
+
+    fn advance(&mut self, dt : Scalar) -> Result<(), RegularizationError> {
+        let relative = self.advance_pairs(dt)?;
+        let (dt2, dt3) = (dt * dt, dt * dt * dt);
+        let (mut pos, mut vel) : (Vec<Vec2d>, Vec<Vec2d>) = self.data.iter().zip(self.states.iter())
+            .map(|(part, state)| {
+                let pos = part.pos + dt * part.vel + (dt2/2.0) * state.acc + (dt3/6.0) * state.jerk;
+                let vel = part.vel + dt * state.acc + (dt2/2.0) * state.jerk;
+                (pos, vel)
+            })
+            .unzip();
+        Self::set_relative(&mut pos, &mut vel, &self.data, &self.pairs, &relative);
+        let evaluated = self.evaluate(&pos, &vel);
+        for (idx, (acc, jerk)) in evaluated.into_iter().enumerate() {
+            let state = &mut self.states[idx];
+            let part = &mut self.data[idx];
+            let (acc0, jerk0) = (state.acc, state.jerk);
+            let snap = (-6.0 * (acc0 - acc) - dt * (4.0 * jerk0 + 2.0 * jerk))/dt2;
+            let crackle = (12.0 * (acc0 - acc) + 6.0 * dt * (jerk0 + jerk))/dt3;
+            part.pos = pos[idx] + (dt2 * dt2/24.0) * snap + (dt2 * dt3/120.0) * crackle;
+            part.vel = vel[idx] + (dt3/6.0) * snap + (dt2 * dt2/24.0) * crackle;
+            *state = HermiteState {
+                acc,
+                jerk,
+                prev_acc : acc0,
+                snap : snap + dt * crackle,
+                crackle,
+            };
+        }
+        let (mut pos, mut vel) : (Vec<Vec2d>, Vec<Vec2d>) = self.data.iter()
+            .map(|part| (part.pos, part.vel))
+            .unzip();
+        Self::set_relative(&mut pos, &mut vel, &self.data, &self.pairs, &relative);
+        for (part, (pos, vel)) in self.data.iter_mut().zip(pos.into_iter().zip(vel)) {
+            part.pos = pos;
+            part.vel = vel;
+        }
+        self.store_forces();
+        self.time += dt;
+        self.steps += 1;
+        self.bootstrap = false;
+        Ok(())
+    }
+}
+
+/// Softened acceleration and jerk on `sources[idx]` by direct summation over
+/// all other sources, given as (position, velocity, mass), skipping the
+/// regularized partner `exclude`.
+pub fn acc_and_jerk(idx : usize, sources : &[(Vec2d, Vec2d, Scalar)], exclude : Option<usize>, G : Scalar, eps2 : Scalar) -> (Vec2d, Vec2d) {
+    let (pos, vel, _) = sources[idx];
+    let mut acc = Vec2d::zero();
+    let mut jerk = Vec2d::zero();
+    for (other_idx, &(other_pos, other_vel, mass)) in sources.iter().enumerate() {
+        if other_idx == idx || Some(other_idx) == exclude {
+            continue;
+        }
+        let r = other_pos - pos;
+        let v = other_vel - vel;
+        let r2 = r.mag_squared() + eps2;
+        let inv_r3 = 1.0/(r2 * r2.sqrt());
+        let rv = r.dot(v)/r2;
+        acc += (G * mass * inv_r3) * r;
+        jerk += (G * mass * inv_r3) * (v - 3.0 * rv * r);
+    }
+    (acc, jerk)
+}
+
+impl PhysicsHandler for PhysicsHandlerHermite {
+    type Position = Scalar;
+    type Force = Scalar;
+
+    fn new(G : Scalar, _collision_spring_constant : Scalar, _collision_dampening : Scalar) -> Self {
+        PhysicsHandlerHermite {
+            data : Vec::new(),
+            states : Vec::new(),
+            regularization_radius : None,
+            pairs : Vec::new(),
+            num_threads : 4,
+            time : 0.0,
+            steps : 0,
+            initialized : false,
+            bootstrap : true,
+            next_id : 0,
+            G,
+            softening : 0.0,
+            accuracy : 0.02,
+            initial_accuracy : 0.01,
+        }
+    }
+
+    fn zero_momentum_and_cm(&mut self) {
+        let mass = self.total_mass();
+        let pos_diff = self.total_mass_pos()/mass;
+        let momentum_diff = self.total_momentum()/mass;
+        for part in self.data.iter_mut() {
+            part.vel -= momentum_diff;
+            part.pos -= pos_diff;
+        }
+        self.initialized = false;
+    }
+
+    fn angular_momentum(&self) -> Scalar {
+        compensated_sum(self.data.iter()
+            .map(|part| part.mass * (part.pos.x * part.vel.y - part.pos.y * part.vel.x)))
+    }
+
+    fn total_mass(&self) -> Scalar {
+        compensated_sum(self.data.iter().map(|part| part.mass))
+    }
+    fn total_mass_pos(&self) -> Vec2d {
+        self.data.iter().map(|part| part.mass * part.pos).sum()
+    }
+    fn total_momentum(&self) -> Vec2d {
+        self.data.iter().map(|part| part.mass * part.vel).sum()
+    }
+    fn kinetic_energy(&self) -> Scalar {
+        kinetic_energy_of(&self.data)
+    }
+
+    /// Includes the softening, so it matches the forces used.
+    fn potential_energy(&self) -> Scalar {
+        direct_potential_energy(&self.data, self.G, self.softening * self.softening)
+    }
+
+    fn num_particles(&self) -> usize {
+        self.data.len()
+    }
+
+    fn load_particles<T : AsRef<[Particle]>>(&mut self, particles : T) {
+        self.data = particles.as_ref().to_vec();
+        adopt_ids(&mut self.data, &mut self.next_id);
+        self.states.clear();
+        self.pairs.clear();
+        self.steps = 0;
+        self.initialized = false;
+    }
+
+    fn add_particles<T : AsRef<[Particle]>>(&mut self, particles : T) -> Vec<u64> {
+        let mut added = particles.as_ref().to_vec();
+        let ids = assign_ids(&mut added, &mut self.next_id);
+        self.data.extend(added);
+        self.initialized = false;
+        ids
+    }
+
+    fn remove_particles<R : FnMut(&Particle) -> bool>(&mut self, predicate : R) -> Vec<Particle> {
+        let parts = std::mem::take(&mut self.data);
+        let (kept, removed, map) = split_removed(parts, predicate);
+        self.data = kept;
+        if !removed.is_empty() {
+            self.pairs = self.pairs.iter()
+                .filter_map(|&(a, b)| match (map[a], map[b]) {
+                    (Some(a), Some(b)) => Some((a, b)),
+                    _ => None,
+                })
+                .collect();
+            self.initialized = false;
+        }
+        removed
+    }
+
+    /// `try_update`, panicking if a regularized pair cannot be advanced.
+    fn update(&mut self, dt : Scalar) {
+        if let Err(err) = self.try_update(dt) {
+            panic!("{}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kepler_pair() -> PhysicsHandlerHermite {
+        let mut handler = PhysicsHandlerHermite::new(1.0, 0.0, 0.0).with_threads(1);
+        let speed = (1.0 as Scalar + 1e-3).sqrt();
+        handler.load_particles(vec![
+            Particle::new(1.0, 0.0, Vec2d::zero(), Vec2d::zero()),
+            Particle::new(1e-3, 0.0, Vec2d::new(1.0, 0.0), Vec2d::new(0.0, speed)),
+        ]);
+        handler
+    }
+
+    #[test]
+    fn update_after_recentering_uses_bounded_substeps() {
+        let mut handler = kepler_pair();
+        handler.update(0.1);
+        handler.zero_momentum_and_cm();
+        let steps = handler.steps();
+        let energy = handler.total_energy();
+        handler.update(2.0 * std::f64::consts::PI);
+        assert!(handler.steps() - steps > 20, "one orbit took {} substeps", handler.steps() - steps);
+        assert!(((handler.total_energy() - energy)/energy).abs() < 1e-4);
+    }
+
+    /// An equal-mass binary with e = 0.99 started at apocentre, and a
+    /// distant perturber.
+    fn hard_binary(regularized : bool) -> PhysicsHandlerHermite {
+        let handler = PhysicsHandlerHermite::new(1.0, 0.0, 0.0).with_threads(1);
+        let mut handler = if regularized { handler.with_regularization(2.5) } else { handler };
+        let apo_speed = 0.5 * (2.0 * 0.01/1.99 as Scalar).sqrt();
+        handler.load_particles(vec![
+            Particle::new(1.0, 0.0, Vec2d::new(0.995, 0.0), Vec2d::new(0.0, apo_speed)),
+            Particle::new(1.0, 0.0, Vec2d::new(-0.995, 0.0), Vec2d::new(0.0, -apo_speed)),
+            Particle::new(0.1, 0.0, Vec2d::new(0.0, 30.0), Vec2d::new(0.25, 0.0)),
+        ]);
+        handler.zero_momentum_and_cm();
+        handler
+    }
+
+    #[test]
+    fn regularization_keeps_energy_through_pericentre() {
+        // One binary period is 2 pi/sqrt(2); pericentre comes halfway.
+        let period = 2.0 * std::f64::consts::PI/(2.0 as Scalar).sqrt();
+        let mut errors = Vec::new();
+        for &regularized in [false, true].iter() {
+            let mut handler = hard_binary(regularized);
+            let energy = handler.total_energy();
+            handler.try_update(period).unwrap();
+            assert_eq!(handler.regularized_pairs().len(), if regularized { 1 } else { 0 });
+            errors.push((((handler.total_energy() - energy)/energy).abs(), handler.steps()));
+        }
+        let ((direct_error, direct_steps), (reg_error, reg_steps)) = (errors[0], errors[1]);
+        assert!(reg_error < 1e-5);
+        assert!(reg_error < 0.1 * direct_error, "regularized {:e}, direct {:e}", reg_error, direct_error);
+        assert!(reg_steps < direct_steps/10, "regularized {} steps, direct {}", reg_steps, direct_steps);
+    }
+}
@@ -1,12 +1,135 @@
-use crate::mathvec::Scalar;
+use crate::mathvec::{Scalar, Vec2d, Float, compensated_sum};
 use crate::particles::Particle;
 
+use std::collections::HashSet;
+
 pub mod threaded;
+pub mod threaded3d;
+pub mod hermite;
+pub mod wisdom_holman;
 pub trait PhysicsHandler {
+    type Position : Float;
+    type Force : Float;
     fn new(G : Scalar, collision_spring_constant : Scalar, collision_dampening : Scalar) -> Self;
     fn zero_momentum_and_cm(&mut self);
     fn angular_momentum(&self) -> Scalar;
+    fn total_mass(&self) -> Scalar;
+    /// Mass weighted position sum, the centre of mass times the total mass.
+    fn total_mass_pos(&self) -> Vec2d;
+    fn total_momentum(&self) -> Vec2d;
     fn num_particles(&self) -> usize; 
-    fn load_particles<T : AsRef<[Particle]>>(&mut self, particles : T) ;
+    fn load_particles<T : AsRef<[Particle<Self::Position, Self::Force>]>>(&mut self, particles : T) ;
     fn update(&mut self, dt : Scalar);
+    /// Appends particles between steps and returns the ids given to them.
+    fn add_particles<T : AsRef<[Particle<Self::Position, Self::Force>]>>(&mut self, particles : T) -> Vec<u64>;
+    /// Removes every particle matching `predicate` between steps and returns
+    /// them. Indices of the remaining particles (and everything referring to
+    /// them) are renumbered to stay contiguous.
+    fn remove_particles<R : FnMut(&Particle<Self::Position, Self::Force>) -> bool>(&mut self, predicate : R) -> Vec<Particle<Self::Position, Self::Force>>;
+    fn kinetic_energy(&self) -> Scalar;
+    /// Gravitational potential energy W of the particles among themselves.
+    fn potential_energy(&self) -> Scalar;
+    /// Elastic energy stored in overlapping contacts.
+    fn contact_energy(&self) -> Scalar {
+        0.0
+    }
+    /// Energy kept outside the particle terms above, such as external
+    /// fields or bonds.
+    fn extra_energy(&self) -> Scalar {
+        0.0
+    }
+    fn total_energy(&self) -> Scalar {
+        self.kinetic_energy() + self.potential_energy() + self.contact_energy() + self.extra_energy()
+    }
+    /// 2T/|W|, one in virial equilibrium.
+    fn virial_ratio(&self) -> Scalar {
+        2.0 * self.kinetic_energy()/self.potential_energy().abs()
+    }
+    fn remove_by_id(&mut self, id : u64) -> Option<Particle<Self::Position, Self::Force>> {
+        self.remove_particles(|part| part.id == id).pop()
+    }
+}
+
+/// Keeps the ids of a loaded particle set where they are unique; a repeated
+/// id keeps its first holder and later holders get fresh ids past the
+/// largest one. `next_id` ends up past every id in the set.
+fn adopt_ids<P : Float, F : Float>(particles : &mut [Particle<P, F>], next_id : &mut u64) {
+    *next_id = particles.iter().map(|part| part.id.saturating_add(1)).max().unwrap_or(0);
+    let mut seen = HashSet::with_capacity(particles.len());
+    for part in particles.iter_mut() {
+        if !seen.insert(part.id) {
+            part.id = *next_id;
+            *next_id += 1;
+        }
+    }
+}
+
+fn kinetic_energy_of<P : Float, F : Float>(particles : &[Particle<P, F>]) -> Scalar {
+    compensated_sum(particles.iter()
+        .map(|part| 0.5 * part.mass.to_f64() * part.vel.cast::<Scalar>().mag_squared()))
+}
+
+/// Pairwise softened potential energy by direct summation.
+fn direct_potential_energy(particles : &[Particle], G : Scalar, eps2 : Scalar) -> Scalar {
+    compensated_sum((0..particles.len())
+        .flat_map(|i| (0..i).map(move |j| (i, j)))
+        .map(|(i, j)| {
+            let (a, b) = (&particles[i], &particles[j]);
+            -G * a.mass * b.mass/((a.pos - b.pos).mag_squared() + eps2).sqrt()
+        }))
+}
+
+/// Gives each particle the next free id.
+fn assign_ids<P : Float, F : Float>(particles : &mut [Particle<P, F>], next_id : &mut u64) -> Vec<u64> {
+    particles.iter_mut()
+        .map(|part| {
+            part.id = *next_id;
+            *next_id += 1;
+            part.id
+        })
+        .collect()
+}
+
+/// Kept particles, removed particles and the old-to-new index map.
+type Split<P, F> = (Vec<Particle<P, F>>, Vec<Particle<P, F>>, Vec<Option<usize>>);
+
+/// Splits `particles` into kept and removed ones; the returned map takes an
+/// old index to its new index, `None` for removed particles.
+fn split_removed<P : Float, F : Float, R : FnMut(&Particle<P, F>) -> bool>(particles : Vec<Particle<P, F>>, mut predicate : R) 
+    -> Split<P, F> 
+{
+    let mut kept = Vec::with_capacity(particles.len());
+    let mut removed = Vec::new();
+    let mut map = Vec::with_capacity(particles.len());
+    for part in particles {
+        if predicate(&part) {
+            map.push(None);
+            removed.push(part);
+        } else {
+            map.push(Some(kept.len()));
+            kept.push(part);
+        }
+    }
+    (kept, removed, map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mathvec::Vec2d;
+
+    #[test]
+    fn adopt_ids_renumbers_only_duplicates() {
+        let mut parts = [3, 7, 3, 0, 7].iter()
+            .map(|&id| Particle { id, ..Particle::new(1.0, 1.0, Vec2d::zero(), Vec2d::zero()) })
+            .collect::<Vec<Particle>>();
+        let mut next_id = 0;
+        adopt_ids(&mut parts, &mut next_id);
+        assert_eq!(parts.iter().map(|part| part.id).collect::<Vec<u64>>(), vec![3, 7, 8, 0, 9]);
+        assert_eq!(next_id, 10);
+
+        let mut parts : Vec<Particle> = vec![Particle { id : u64::MAX, ..Particle::new(1.0, 1.0, Vec2d::zero(), Vec2d::zero()) }];
+        adopt_ids(&mut parts, &mut next_id);
+        assert_eq!(next_id, u64::MAX);
+    }
 }
@@ -1,67 +1,447 @@
-use super::PhysicsHandler;
-use crate::particles::Particle;
-use crate::mathvec::{Scalar, Vec2d};
+use super::{PhysicsHandler, adopt_ids, assign_ids, split_removed, kinetic_energy_of};
+use crate::particles::{Particle, GasState};
+use crate::mathvec::{Scalar, Vec2d, Float, cast, compensated_sum};
 use crate::masstree::{MassTree, Span};
-use crate::gridhandler::GridHandler;
+use crate::gridhandler::{GridHandler, NeighborList};
+use crate::sph::{SphHandler, SphParams};
+use crate::external::ExternalPotential;
+use crate::boundary::Wall;
+use crate::pairforce::{self, PairForce, BuiltinPairForce};
+use crate::chargetree::{ChargeTree, ElectrostaticsParams};
+use crate::bonds::BondList;
+use crate::rigidbody::{RigidBody, RigidBodyError};
 use crate::easytime::{self, NANOS_TO_SECS, EasyTimer, PhysicsHandlerThreadedTiming};
+use crate::rng::SimRng;
+use crate::snapshot::Snapshot;
+use crate::binio::*;
 
 use std::sync::{Arc, RwLock};
 use std::thread::{self, JoinHandle};
 use std::time::{Instant};
+use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::fs::File;
+use std::path::Path;
+use std::mem;
 
+const CHECKPOINT_MAGIC : &[u8; 4] = b"NBCK";
+const CHECKPOINT_VERSION : u32 = 1;
 
-pub struct PhysicsHandlerThreaded {
-    dispatcher : PhysicsThreadDispatcher,
+
+/// `P` is the precision particle state is stored and integrated in, `F` the
+/// precision gravity and contact forces are evaluated in. The optional
+/// modules (SPH, external fields, walls, pair forces, electrostatics, bonds and
+/// rigid bodies) always work in `Scalar` on a converted copy of the particles.
+///
+/// Forces are summed per particle in a fixed order by one thread and all
+/// reductions run sequentially in particle order, so `step` reproduces the
+/// same bits for any thread count.
+pub struct PhysicsHandlerThreaded<P : Float = Scalar, F : Float = P> {
+    dispatcher : PhysicsThreadDispatcher<P, F>,
+    rigid_bodies : Vec<RigidBody>,
+    /// Per particle Kahan compensation of (position, velocity), present when
+    /// compensated integration is enabled.
+    compensation : Option<Vec<(Vec2d<P>, Vec2d<P>)>>,
+    next_id : u64,
+    /// Simulation time, the sum of all integrated `dt`.
+    time : Scalar,
     phystime : PhysicsHandlerThreadedTiming,
     timer : EasyTimer,
 }
 
 
-impl PhysicsHandlerThreaded {
+impl<P : Float, F : Float> PhysicsHandlerThreaded<P, F> {
 
-    pub fn total_mass(&self) -> Scalar {
-        self.dispatcher.data.iter()
-            .map(|locked| locked.read().unwrap().mass)
-            .sum()
+    /// Number of force threads. Each particle's force is summed by a single
+    /// thread in a fixed order, so the results do not depend on this.
+    pub fn with_threads(mut self, num_threads : usize) -> Self {
+        self.dispatcher.destruct_threads();
+        self.dispatcher.num_threads = num_threads.max(1);
+        self
     }
-    pub fn total_mass_pos(&self) -> Vec2d {
-        self.dispatcher.data.iter()
-            .map(|locked| {
-                let part = locked.read().unwrap();
-                part.mass * part.pos
-            })
-            .sum()
+
+    pub fn with_neighbor_skin(mut self, skin : Scalar) -> Self {
+        self.dispatcher.destruct_threads();
+        self.dispatcher.neighbors = Some(Arc::new(RwLock::new(NeighborList::new(cast(skin)))));
+        self
+    }
+
+    /// Accumulate position and velocity updates with Kahan summation so the
+    /// low order bits of small increments are not lost over long runs.
+    pub fn with_compensated_integration(mut self) -> Self {
+        self.compensation = Some(Vec::new());
+        self
     }
-    pub fn total_momentum(&self) -> Vec2d {
+
+    pub fn with_sph(mut self, params : SphParams) -> Self {
+        self.dispatcher.destruct_threads();
+        self.dispatcher.sph = Some(Arc::new(RwLock::new(SphHandler::new(params))));
+        self
+    }
+
+    pub fn add_external_potential(&mut self, potential : ExternalPotential) {
+        Arc::make_mut(&mut self.dispatcher.externals).push(potential);
+    }
+
+    pub fn clear_external_potentials(&mut self) {
+        Arc::make_mut(&mut self.dispatcher.externals).clear();
+    }
+
+    pub fn external_potentials(&self) -> &[ExternalPotential] {
+        self.dispatcher.externals.as_ref()
+    }
+
+    pub fn add_wall(&mut self, wall : Wall) {
+        Arc::make_mut(&mut self.dispatcher.walls).push(wall);
+    }
+
+    pub fn clear_walls(&mut self) {
+        Arc::make_mut(&mut self.dispatcher.walls).clear();
+    }
+
+    pub fn walls(&self) -> &[Wall] {
+        self.dispatcher.walls.as_ref()
+    }
+
+    pub fn with_electrostatics(mut self, params : ElectrostaticsParams) -> Self {
+        self.dispatcher.destruct_threads();
+        self.dispatcher.charge_tree = Some(Arc::new(RwLock::new(ChargeTree::new(params))));
+        self
+    }
+
+    pub fn set_bonds(&mut self, bonds : BondList) {
+        self.dispatcher.destruct_threads();
+        *self.dispatcher.bonds.write().unwrap() = bonds;
+    }
+
+    pub fn bonds(&self) -> BondList {
+        self.dispatcher.bonds.read().unwrap().clone()
+    }
+
+    /// Swaps in a new particle set. The threads are joined first and the
+    /// next update starts over from the tree build, so nothing computed for
+    /// the old indices is used.
+    fn replace_particles(&mut self, particles : Vec<Particle<P, F>>) {
+        self.dispatcher.destruct_threads();
+        self.dispatcher.data = Arc::new(particles.into_iter().map(RwLock::new).collect());
+        if let Some(neighbors) = self.dispatcher.neighbors.as_ref() {
+            neighbors.write().unwrap().invalidate();
+        }
+    }
+
+    fn particles_as_scalar(&self) -> Vec<Particle> {
         self.dispatcher.data.iter()
-            .map(|locked| {
-                let part = locked.read().unwrap();
-                part.mass * part.vel
-            })
-            .sum()
+            .map(|locked| locked.read().unwrap().cast())
+            .collect()
+    }
+
+    /// Returns the index of the new body.
+    pub fn add_rigid_body(&mut self, members : Vec<usize>) -> Result<usize, RigidBodyError> {
+        let parts = self.particles_as_scalar();
+        self.rigid_bodies.push(RigidBody::from_members(members, &parts)?);
+        Ok(self.rigid_bodies.len() - 1)
+    }
+
+    pub fn rigid_bodies(&self) -> &[RigidBody] {
+        &self.rigid_bodies
+    }
+
+    pub fn add_pair_force<T : PairForce + 'static>(&mut self, force : T) {
+        Arc::make_mut(&mut self.dispatcher.pair_forces).push(Arc::new(force));
+    }
+
+    pub fn clear_pair_forces(&mut self) {
+        Arc::make_mut(&mut self.dispatcher.pair_forces).clear();
+    }
+
+    pub fn neighbor_list_rebuilds(&self) -> usize {
+        self.dispatcher.neighbors.as_ref()
+            .map_or(0, |locked| locked.read().unwrap().rebuilds())
     }
 
     pub fn is_updating(&self) -> bool {
         self.dispatcher.is_running()
     }
 
-    pub fn particles(&self) -> impl IntoIterator<Item=Particle> {
+    pub fn particles(&self) -> impl IntoIterator<Item=Particle<P, F>> {
         let retval = self.dispatcher.data.iter()
             .map(|locked| {
                 let part_lock = locked.read().unwrap();
                 *part_lock
             })
-            .collect::<Vec<Particle>>();
+            .collect::<Vec<Particle<P, F>>>();
         retval
     }
+
+    /// Potential energy in the external fields.
+    pub fn external_energy(&self) -> Scalar {
+        let G = self.dispatcher.G;
+        compensated_sum(self.particles_as_scalar().iter()
+            .flat_map(|part| self.dispatcher.externals.iter()
+                .map(move |potential| part.mass * potential.potential(part.pos, G))))
+    }
+
+    pub fn bond_energy(&self) -> Scalar {
+        self.dispatcher.bonds.read().unwrap().energy(&self.particles_as_scalar())
+    }
+
+    /// Thermal energy of the SPH gas particles.
+    pub fn internal_energy(&self) -> Scalar {
+        compensated_sum(self.particles_as_scalar().iter()
+            .filter_map(|part| part.gas.map(|gas| part.mass * gas.internal_energy)))
+    }
+
+    /// Advances exactly one step of `dt`, waiting for the tree build and the
+    /// force threads. `update` skips calls while threads are busy, so how far
+    /// it gets depends on timing; a sequence of `step` calls depends only on
+    /// its inputs and gives the same bits for any thread count.
+    pub fn step(&mut self, dt : Scalar) {
+        if self.dispatcher.data.is_empty() {
+            return;
+        }
+        self.timer.tick();
+        self.dispatcher.destruct_threads();
+        self.dispatcher.dispatch_quad_grid();
+        self.dispatcher.destruct_threads();
+        self.dispatcher.spawn_force_threads();
+        self.dispatcher.destruct_threads();
+        self.phystime.real_time = self.timer.tick();
+        self.integrate(dt);
+        self.phystime.time_stepping = self.timer.tick();
+    }
+
+    /// Wall clock seconds spent on the last force evaluation and integration.
+    pub fn timing(&self) -> PhysicsHandlerThreadedTiming {
+        self.phystime
+    }
+
+    fn integrate(&mut self, dt : Scalar) {
+        let dt_p : P = cast(dt);
+        let half_dt_p = P::from_f64(0.5) * dt_p;
+        if let Some(compensation) = self.compensation.as_mut() {
+            compensation.resize(self.dispatcher.data.len(), (Vec2d::zero(), Vec2d::zero()));
+        }
+        let part_iter = self.dispatcher.data.iter();
+        for (idx, part_lock) in part_iter.enumerate() {
+            let mut part = part_lock.write().unwrap();
+            let accel = part.force().cast::<P>()/part.mass;
+            let dx = (part.vel + accel * half_dt_p) * dt_p;
+            let dv = accel * dt_p; 
+            match self.compensation.as_mut() {
+                Some(compensation) => {
+                    let (pos_comp, vel_comp) = &mut compensation[idx];
+                    part.pos.compensated_add(pos_comp, dx);
+                    part.vel.compensated_add(vel_comp, dv);
+                },
+                None => {
+                    part.pos += dx; 
+                    part.vel += dv; 
+                },
+            }
+            if let Some(gas) = part.gas.as_mut() {
+                gas.internal_energy = (gas.internal_energy + dt_p * gas.du_dt).max(P::ZERO);
+            }
+        }
+        if !self.rigid_bodies.is_empty() {
+            let parts = self.particles_as_scalar();
+            for body in self.rigid_bodies.iter_mut() {
+                let (force, torque) = body.force_and_torque(&parts);
+                body.step(force, torque, dt);
+                for (idx, pos, vel) in body.member_states() {
+                    let mut part = self.dispatcher.data[idx].write().unwrap();
+                    part.pos = pos.cast();
+                    part.vel = vel.cast();
+                    if let Some(compensation) = self.compensation.as_mut() {
+                        compensation[idx] = (Vec2d::zero(), Vec2d::zero());
+                    }
+                }
+            }
+        }
+        for wall in Arc::make_mut(&mut self.dispatcher.walls).iter_mut() {
+            wall.advance(dt);
+        }
+        self.time += dt;
+    }
+
+    pub fn time(&self) -> Scalar {
+        self.time
+    }
+
+    /// The particles with the current time and G, for `Snapshot::save`.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.time, self.dispatcher.G, self.particles_as_scalar())
+    }
+
+    /// Writes the complete handler state: every particle field including the
+    /// forces, the simulation time, G, collK, collDampening, the integrator
+    /// compensation terms, all optional modules with their state and, if
+    /// given, the state of the generator driving the run. An update in
+    /// progress is joined first and redone from the tree build afterwards.
+    ///
+    /// Only the built-in pair forces can be stored; a custom one makes this
+    /// fail before anything is written.
+    pub fn write_checkpoint<W : Write>(&mut self, out : &mut W, rng : Option<&SimRng>) -> io::Result<()> {
+        let pair_forces = self.dispatcher.pair_forces.iter()
+            .map(|force| force.builtin())
+            .collect::<Option<Vec<BuiltinPairForce>>>()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "custom pair forces cannot be checkpointed"))?;
+        self.dispatcher.destruct_threads();
+        write_magic(out, CHECKPOINT_MAGIC, CHECKPOINT_VERSION)?;
+        write_u8(out, mem::size_of::<P>() as u8)?;
+        write_u8(out, mem::size_of::<F>() as u8)?;
+        write_f64(out, self.time)?;
+        write_f64(out, self.dispatcher.G)?;
+        write_f64(out, self.dispatcher.collK)?;
+        write_f64(out, self.dispatcher.collDampening)?;
+        write_len(out, self.dispatcher.num_threads)?;
+        write_u64(out, self.next_id)?;
+
+        let skin = self.dispatcher.neighbors.as_ref().map(|locked| locked.read().unwrap().skin());
+        write_option_float(out, skin)?;
+        let sph_params = self.dispatcher.sph.as_ref().map(|locked| locked.read().unwrap().params());
+        write_bool(out, sph_params.is_some())?;
+        if let Some(params) = sph_params {
+            params.write_to(out)?;
+        }
+        let elec_params = self.dispatcher.charge_tree.as_ref().map(|locked| locked.read().unwrap().params());
+        write_bool(out, elec_params.is_some())?;
+        if let Some(params) = elec_params {
+            params.write_to(out)?;
+        }
+        write_bool(out, self.compensation.is_some())?;
+        if let Some(compensation) = self.compensation.as_ref() {
+            write_len(out, compensation.len())?;
+            for &(pos_comp, vel_comp) in compensation.iter() {
+                write_vec2d(out, pos_comp)?;
+                write_vec2d(out, vel_comp)?;
+            }
+        }
+
+        write_len(out, self.dispatcher.data.len())?;
+        for locked in self.dispatcher.data.iter() {
+            locked.read().unwrap().write_to(out)?;
+        }
+        write_len(out, self.dispatcher.externals.len())?;
+        for potential in self.dispatcher.externals.iter() {
+            potential.write_to(out)?;
+        }
+        write_len(out, self.dispatcher.walls.len())?;
+        for wall in self.dispatcher.walls.iter() {
+            wall.write_to(out)?;
+        }
+        write_len(out, pair_forces.len())?;
+        for force in pair_forces.iter() {
+            force.write_to(out)?;
+        }
+        self.dispatcher.bonds.read().unwrap().write_to(out)?;
+        write_len(out, self.rigid_bodies.len())?;
+        for body in self.rigid_bodies.iter() {
+            body.write_to(out)?;
+        }
+        write_bool(out, rng.is_some())?;
+        write_u64(out, rng.map_or(0, SimRng::seed))?;
+        write_u128(out, rng.map_or(0, SimRng::draws))
+    }
+
+    /// Rebuilds a handler from `write_checkpoint` output, together with the
+    /// stored generator if there was one. The checkpoint must have been
+    /// written with the same `P` and `F`.
+    pub fn read_checkpoint<R : Read>(input : &mut R) -> io::Result<(Self, Option<SimRng>)> {
+        read_magic(input, CHECKPOINT_MAGIC, CHECKPOINT_VERSION)?;
+        let size_p = read_u8(input)? as usize;
+        let size_f = read_u8(input)? as usize;
+        if size_p != mem::size_of::<P>() || size_f != mem::size_of::<F>() {
+            return Err(invalid_data("checkpoint was written with a different precision"));
+        }
+        let time = read_f64(input)?;
+        let G = read_f64(input)?;
+        let collK = read_f64(input)?;
+        let collDampening = read_f64(input)?;
+        let mut retval = Self::new(G, collK, collDampening).with_threads(read_len(input)?);
+        retval.time = time;
+        retval.next_id = read_u64(input)?;
+
+        if let Some(skin) = read_option_float::<_, Scalar>(input)? {
+            retval = retval.with_neighbor_skin(skin);
+        }
+        if read_bool(input)? {
+            retval = retval.with_sph(SphParams::read_from(input)?);
+        }
+        if read_bool(input)? {
+            retval = retval.with_electrostatics(ElectrostaticsParams::read_from(input)?);
+        }
+        if read_bool(input)? {
+            let mut compensation = Vec::new();
+            for _ in 0..read_len(input)? {
+                let pos_comp = read_vec2d(input)?;
+                let vel_comp = read_vec2d(input)?;
+                compensation.push((pos_comp, vel_comp));
+            }
+            retval.compensation = Some(compensation);
+        }
+
+        let mut particles = Vec::new();
+        for _ in 0..read_len(input)? {
+            particles.push(Particle::read_from(input)?);
+        }
+        retval.replace_particles(particles);
+        for _ in 0..read_len(input)? {
+            retval.add_external_potential(ExternalPotential::read_from(input)?);
+        }
+        for _ in 0..read_len(input)? {
+            retval.add_wall(Wall::read_from(input)?);
+        }
+        for _ in 0..read_len(input)? {
+            let force = BuiltinPairForce::read_from(input)?.into_pair_force();
+            Arc::make_mut(&mut retval.dispatcher.pair_forces).push(force);
+        }
+        retval.set_bonds(BondList::read_from(input)?);
+        for _ in 0..read_len(input)? {
+            retval.rigid_bodies.push(RigidBody::read_from(input)?);
+        }
+        let has_rng = read_bool(input)?;
+        let seed = read_u64(input)?;
+        let draws = read_u128(input)?;
+        let rng = if has_rng { Some(SimRng::resume(seed, draws)) } else { None };
+        Ok((retval, rng))
+    }
+
+    pub fn save_checkpoint<T : AsRef<Path>>(&mut self, file_name : T, rng : Option<&SimRng>) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(file_name)?);
+        self.write_checkpoint(&mut out, rng)?;
+        out.flush()
+    }
+
+    pub fn load_checkpoint<T : AsRef<Path>>(file_name : T) -> io::Result<(Self, Option<SimRng>)> {
+        let mut input = BufReader::new(File::open(file_name)?);
+        Self::read_checkpoint(&mut input)
+    }
+
+    pub fn particle_by_id(&self, id : u64) -> Option<Particle<P, F>> {
+        self.dispatcher.data.iter()
+            .map(|locked| *locked.read().unwrap())
+            .find(|part| part.id == id)
+    }
+
+    pub fn particles_of_species(&self, species : u32) -> Vec<Particle<P, F>> {
+        self.dispatcher.data.iter()
+            .map(|locked| *locked.read().unwrap())
+            .filter(|part| part.species == species)
+            .collect()
+    }
 }
 
-impl PhysicsHandler for PhysicsHandlerThreaded {
+impl<P : Float, F : Float> PhysicsHandler for PhysicsHandlerThreaded<P, F> {
+    type Position = P;
+    type Force = F;
 
     fn new(grav_constant: Scalar, collision_spring_constant: Scalar, collision_dampening: Scalar) -> Self {
         let dispatcher = PhysicsThreadDispatcher::new(grav_constant, collision_spring_constant, collision_dampening);
         PhysicsHandlerThreaded {
             dispatcher,
+            rigid_bodies : Vec::new(),
+            compensation : None,
+            next_id : 0,
+            time : 0.0,
             phystime : PhysicsHandlerThreadedTiming::default(),
             timer : EasyTimer::now(),
         }
@@ -73,11 +453,17 @@ impl PhysicsHandler for PhysicsHandlerThreaded {
         let momentum_diff = self.total_momentum()/mass;
         for locked_part in self.dispatcher.data.iter() {
             let mut part = locked_part.write().unwrap();
-            part.vel -= momentum_diff;
-            part.pos -= pos_diff;
+            part.vel -= momentum_diff.cast();
+            part.pos -= pos_diff.cast();
+        }
+        for body in self.rigid_bodies.iter_mut() {
+            body.shift(pos_diff, momentum_diff);
         }
     }
     fn update(&mut self, dt: Scalar) {
+        if self.dispatcher.data.is_empty() {
+            return;
+        }
         self.dispatcher.set_present(easytime::get_present());
         if self.is_updating() {
             return;
@@ -97,20 +483,16 @@ impl PhysicsHandler for PhysicsHandlerThreaded {
             timer2.tick();
 
             let threadtimes = self.dispatcher.get_timing();
-            let _timingsum : f64 = threadtimes.iter().sum();
+            if !threadtimes.is_empty() {
+                self.phystime.max_thread_time = threadtimes.iter().cloned().fold(0.0, f64::max);
+                self.phystime.min_thread_time = threadtimes.iter().cloned().fold(f64::INFINITY, f64::min);
+            }
 
             self.dispatcher.next_computation();
             self.phystime.real_time = self.timer.tick();
 
             self.timer.tick();
-            let part_iter = self.dispatcher.data.iter();
-            for part_lock in part_iter {
-                let mut part = part_lock.write().unwrap();
-                let dx = dt * (part.vel + 0.5 * dt * (part.f_spring + part.f_grav)/part.mass);
-                let dv = dt * (part.f_spring + part.f_grav)/part.mass; 
-                part.pos += dx; 
-                part.vel += dv; 
-            }
+            self.integrate(dt);
             let _dist = 10000000;
             self.phystime.time_stepping = self.timer.tick();
             println!("Finish Computation Timing: {}", timer3.tick());
@@ -121,39 +503,127 @@ impl PhysicsHandler for PhysicsHandlerThreaded {
             self.dispatcher.dispatch_quad_grid();
         }
     }
-    fn num_particles(&self) -> usize {
-        self.dispatcher.data.len()
+    fn total_mass(&self) -> Scalar {
+        compensated_sum(self.dispatcher.data.iter()
+            .map(|locked| cast::<P, Scalar>(locked.read().unwrap().mass)))
     }
-    fn angular_momentum(&self) -> Scalar {
+    fn total_mass_pos(&self) -> Vec2d {
         self.dispatcher.data.iter()
             .map(|locked| {
                 let part = locked.read().unwrap();
-                part.mass * (part.pos.x * part.vel.y - part.pos.y * part.vel.x)
+                cast::<P, Scalar>(part.mass) * part.pos.cast()
+            })
+            .sum()
+    }
+    fn total_momentum(&self) -> Vec2d {
+        self.dispatcher.data.iter()
+            .map(|locked| {
+                let part = locked.read().unwrap();
+                cast::<P, Scalar>(part.mass) * part.vel.cast()
             })
             .sum()
     }
-    fn load_particles<T : AsRef<[Particle]>>(&mut self, particles: T) {
+    fn num_particles(&self) -> usize {
+        self.dispatcher.data.len()
+    }
+    fn kinetic_energy(&self) -> Scalar {
+        kinetic_energy_of(&self.particles().into_iter().collect::<Vec<Particle<P, F>>>())
+    }
+    /// Evaluated through a freshly built `MassTree`, with the same opening
+    /// angle and close range cutoff as the forces.
+    fn potential_energy(&self) -> Scalar {
+        let parts = self.particles().into_iter().collect::<Vec<Particle<P, F>>>();
+        if parts.is_empty() {
+            return 0.0;
+        }
+        let (_, quad) = quad_grid_filler(&parts, false);
+        let G : F = cast(self.dispatcher.G);
+        0.5 * compensated_sum(parts.iter().map(|part| quad.calculate_potential(*part, G).to_f64()))
+    }
+    fn contact_energy(&self) -> Scalar {
+        let parts = self.particles().into_iter().collect::<Vec<Particle<P, F>>>();
+        if parts.is_empty() {
+            return 0.0;
+        }
+        let (grid, _) = quad_grid_filler(&parts, true);
+        let K : F = cast(self.dispatcher.collK);
+        0.5 * compensated_sum(parts.iter().map(|part| grid.calculate_contact_energy(*part, K).to_f64()))
+    }
+    /// The external field, bond and gas thermal energy.
+    fn extra_energy(&self) -> Scalar {
+        self.external_energy() + self.bond_energy() + self.internal_energy()
+    }
+    fn angular_momentum(&self) -> Scalar {
+        compensated_sum(self.dispatcher.data.iter()
+            .map(|locked| {
+                let part = locked.read().unwrap().cast::<Scalar, Scalar>();
+                part.mass * (part.pos.x * part.vel.y - part.pos.y * part.vel.x)
+            }))
+    }
+    fn load_particles<T : AsRef<[Particle<P, F>]>>(&mut self, particles: T) {
+        let mut particles = particles.as_ref().to_vec();
+        adopt_ids(&mut particles, &mut self.next_id);
+        self.replace_particles(particles);
+        self.rigid_bodies.clear();
+        if let Some(compensation) = self.compensation.as_mut() {
+            compensation.clear();
+        }
+    }
+    fn add_particles<T : AsRef<[Particle<P, F>]>>(&mut self, particles : T) -> Vec<u64> {
+        let mut added = particles.as_ref().to_vec();
+        let ids = assign_ids(&mut added, &mut self.next_id);
+        self.dispatcher.destruct_threads();
+        let mut parts = self.particles().into_iter().collect::<Vec<Particle<P, F>>>();
+        parts.extend(added);
+        self.replace_particles(parts);
+        ids
+    }
+    fn remove_particles<R : FnMut(&Particle<P, F>) -> bool>(&mut self, predicate : R) -> Vec<Particle<P, F>> {
         self.dispatcher.destruct_threads();
-        let data_lock = Arc::get_mut(&mut self.dispatcher.data).unwrap();
-        *data_lock = particles.as_ref().iter().cloned().map(RwLock::new).collect();
+        let parts = self.particles().into_iter().collect::<Vec<Particle<P, F>>>();
+        let (kept, removed, map) = split_removed(parts, predicate);
+        if removed.is_empty() {
+            return removed;
+        }
+        self.dispatcher.bonds.write().unwrap().remap(&map);
+        if let Some(compensation) = self.compensation.as_mut() {
+            *compensation = compensation.iter().zip(map.iter())
+                .filter(|(_, new_idx)| new_idx.is_some())
+                .map(|(comp, _)| *comp)
+                .collect();
+        }
+        self.replace_particles(kept);
+        let scalar_parts = self.particles_as_scalar();
+        self.rigid_bodies = self.rigid_bodies.iter()
+            .filter_map(|body| body.remap(&map, &scalar_parts))
+            .collect();
+        removed
     }
 }
 
-pub struct PhysicsThreadDispatcher {
+pub struct PhysicsThreadDispatcher<P : Float = Scalar, F : Float = P> {
     present : Arc<RwLock<Instant>>,
     quad_grid_thread : Option<JoinHandle<()>>,
-    force_threads : Vec<ForceThreadData>,
+    force_threads : Vec<ForceThreadData<P, F>>,
     num_threads : usize, 
-    quad : Arc<RwLock<MassTree>>,
-    grid : Arc<RwLock<GridHandler>>,
-    data : Arc<Vec<RwLock<Particle>>>,
+    quad : Arc<RwLock<MassTree<P, F>>>,
+    grid : Arc<RwLock<GridHandler<P, F>>>,
+    neighbors : Option<Arc<RwLock<NeighborList<P, F>>>>,
+    sph : Option<Arc<RwLock<SphHandler>>>,
+    externals : Arc<Vec<ExternalPotential>>,
+    walls : Arc<Vec<Wall>>,
+    pair_forces : Arc<Vec<Arc<dyn PairForce>>>,
+    pair_grid : Arc<RwLock<GridHandler>>,
+    charge_tree : Option<Arc<RwLock<ChargeTree>>>,
+    bonds : Arc<RwLock<BondList>>,
+    data : Arc<Vec<RwLock<Particle<P, F>>>>,
 
     G : Scalar,
     collK : Scalar,
     collDampening : Scalar,
 }
 
-impl PhysicsThreadDispatcher {
+impl<P : Float, F : Float> PhysicsThreadDispatcher<P, F> {
 
     pub fn new(G : Scalar, collK : Scalar, collDampening : Scalar) -> Self {
         let num_threads = 4;
@@ -163,7 +633,15 @@ impl PhysicsThreadDispatcher {
             force_threads : Vec::with_capacity(num_threads),
             num_threads,
             quad : Arc::new(RwLock::new(MassTree::default())),
-            grid : Arc::new(RwLock::new(GridHandler::new(0.0, 0))),
+            grid : Arc::new(RwLock::new(GridHandler::new(P::ZERO, 0))),
+            neighbors : None,
+            sph : None,
+            externals : Arc::new(Vec::new()),
+            walls : Arc::new(Vec::new()),
+            pair_forces : Arc::new(Vec::new()),
+            pair_grid : Arc::new(RwLock::new(GridHandler::new(0.0, 0))),
+            charge_tree : None,
+            bonds : Arc::new(RwLock::new(BondList::new())),
             data : Arc::new(Vec::new()),
             G, 
             collK, 
@@ -188,23 +666,34 @@ impl PhysicsThreadDispatcher {
             panic!("PhysicsThreadDispatcher::dispatch_forces called when threads already running!");
         }
         self.destruct_threads();
+        self.spawn_force_threads();
+    }
+
+    /// Splits the particles over `num_threads` force threads. Every particle
+    /// is handled by exactly one thread and only reads shared snapshots, so
+    /// the split does not change any result.
+    fn spawn_force_threads(&mut self) {
+        let inputs = ForceInputs {
+            data : Arc::clone(&self.data),
+            grid : Arc::clone(&self.grid),
+            quad : Arc::clone(&self.quad),
+            neighbors : self.neighbors.as_ref().map(Arc::clone),
+            sph : self.sph.as_ref().map(Arc::clone),
+            externals : Arc::clone(&self.externals),
+            walls : Arc::clone(&self.walls),
+            pair_forces : Arc::clone(&self.pair_forces),
+            pair_grid : Arc::clone(&self.pair_grid),
+            charge_tree : self.charge_tree.as_ref().map(Arc::clone),
+            bonds : Arc::clone(&self.bonds),
+            G : self.G,
+            collK : self.collK,
+            collDampening : self.collDampening,
+        };
         let step_size = (self.data.len() as f64)/(self.num_threads as f64);
         for i in 0..self.num_threads {
-            let clock = Arc::clone(&self.present);
-            let data = Arc::clone(&self.data);
-            let grid = Arc::clone(&self.grid);
-            let quad = Arc::clone(&self.quad);
             let start_idx = (step_size * i as f64) as usize; 
             let end_idx = (step_size * (i + 1) as f64) as usize; 
-            let new_thread = ForceThreadData::new(
-                clock,
-                data, 
-                start_idx, 
-                end_idx, 
-                grid, 
-                quad, 
-                self.G, self.collK, self.collDampening
-            );
+            let new_thread = ForceThreadData::new(Arc::clone(&self.present), inputs.clone(), start_idx, end_idx);
             self.force_threads.push(new_thread);
         }
     }
@@ -218,12 +707,51 @@ impl PhysicsThreadDispatcher {
         let quad_ref = Arc::clone(&self.quad);
         let grid_ref = Arc::clone(&self.grid);
         let data_ref = Arc::clone(&self.data);
+        let neighbors_ref = self.neighbors.as_ref().map(Arc::clone);
+        let sph_ref = self.sph.as_ref().map(Arc::clone);
+        let pair_forces_ref = Arc::clone(&self.pair_forces);
+        let pair_grid_ref = Arc::clone(&self.pair_grid);
+        let charge_tree_ref = self.charge_tree.as_ref().map(Arc::clone);
+        let bonds_ref = Arc::clone(&self.bonds);
         let handle = thread::spawn(move || {
             let mut grid_writer = grid_ref.write().unwrap();
             let mut quad_writer = quad_ref.write().unwrap();
-            let(grid, quad) = quad_grid_filler(data_ref);
+            let mut parts = data_ref.iter()
+                .map(|locked| *locked.read().unwrap())
+                .collect::<Vec<Particle<P, F>>>();
+            let mut bonds_writer = bonds_ref.write().unwrap();
+            let needs_scalar = sph_ref.is_some() || !pair_forces_ref.is_empty() 
+                || charge_tree_ref.is_some() || !bonds_writer.is_empty();
+            let mut scalar_parts = if needs_scalar {
+                parts.iter().map(Particle::cast).collect::<Vec<Particle>>()
+            } else {
+                Vec::new()
+            };
+            if let Some(sph) = sph_ref {
+                let mut sph_writer = sph.write().unwrap();
+                (*sph_writer) = SphHandler::build(sph_writer.params(), &mut scalar_parts);
+                for ((locked, part), scalar_part) in data_ref.iter().zip(parts.iter_mut()).zip(scalar_parts.iter()) {
+                    part.gas = scalar_part.gas.map(GasState::cast);
+                    locked.write().unwrap().gas = part.gas;
+                }
+            }
+            let(grid, quad) = quad_grid_filler(&parts, neighbors_ref.is_none());
             (*grid_writer) = grid;
             (*quad_writer) = quad;
+            if !pair_forces_ref.is_empty() {
+                let mut pair_grid_writer = pair_grid_ref.write().unwrap();
+                (*pair_grid_writer) = pairforce::build_pair_grid(&scalar_parts, &pair_forces_ref);
+            }
+            if let Some(charge_tree) = charge_tree_ref {
+                let mut charge_tree_writer = charge_tree.write().unwrap();
+                (*charge_tree_writer) = ChargeTree::build(charge_tree_writer.params(), &scalar_parts);
+            }
+            if !bonds_writer.is_empty() {
+                bonds_writer.update(scalar_parts);
+            }
+            if let Some(neighbors) = neighbors_ref {
+                neighbors.write().unwrap().update(parts);
+            }
         });
         self.quad_grid_thread = Some(handle);
     }
@@ -284,18 +812,14 @@ impl PhysicsThreadDispatcher {
         self.mode() == 1 || self.mode() == 3
     }
 }
-fn quad_grid_filler(data : Arc<Vec<RwLock<Particle>>>) -> (GridHandler, MassTree) {
-
-    let data_slice = data.as_ref();
+fn quad_grid_filler<P : Float, F : Float>(data_slice : &[Particle<P, F>], with_grid : bool) -> (GridHandler<P, F>, MassTree<P, F>) {
 
-
-    let mut max_rad = 0.0;
+    let mut max_rad = P::ZERO;
     let ((mut left, mut top), (mut right, mut bottom)) = {
-        let placeholder = data_slice[0].read().unwrap();
+        let placeholder = data_slice[0];
         (placeholder.pos.into(), placeholder.pos.into())
     };
     for part in data_slice {
-        let part = part.read().unwrap();
         if part.pos.x > right {
             right = part.pos.x;
         }
@@ -313,52 +837,65 @@ fn quad_grid_filler(data : Arc<Vec<RwLock<Particle>>>) -> (GridHandler, MassTree
         }
     }
 
-    let mut grid = GridHandler::new(0.706/max_rad,data_slice.len());
+    // Cells two radii wide, so every particle touching another one sits in
+    // the 3x3 block around it.
+    let mut grid = if with_grid {
+        GridHandler::new(P::from_f64(0.5)/max_rad,data_slice.len())
+    } else {
+        GridHandler::new(P::ZERO, 0)
+    };
     let mut quad = MassTree::builder()
         .with_particle_capacity(data_slice.len())
         .with_span(Span::new((right, top).into(), (left, bottom).into()))
         .build();
     
     for part in data_slice {
-        let part = *part.read().unwrap();
-        grid.add_particle(part);
-        quad.add_particle(part);
+        if with_grid {
+            grid.add_particle(*part);
+        }
+        quad.add_particle(*part);
     }
     (grid, quad)
 }
 
 
 
-struct ForceThreadData {
+/// The shared state a force thread reads, handed out by the dispatcher.
+#[derive(Clone)]
+struct ForceInputs<P : Float, F : Float> {
+    data : Arc<Vec<RwLock<Particle<P, F>>>>,
+    grid : Arc<RwLock<GridHandler<P, F>>>,
+    quad : Arc<RwLock<MassTree<P, F>>>,
+    neighbors : Option<Arc<RwLock<NeighborList<P, F>>>>,
+    sph : Option<Arc<RwLock<SphHandler>>>,
+    externals : Arc<Vec<ExternalPotential>>,
+    walls : Arc<Vec<Wall>>,
+    pair_forces : Arc<Vec<Arc<dyn PairForce>>>,
+    pair_grid : Arc<RwLock<GridHandler>>,
+    charge_tree : Option<Arc<RwLock<ChargeTree>>>,
+    bonds : Arc<RwLock<BondList>>,
+
+    G : Scalar,
+    collK : Scalar, 
+    collDampening : Scalar,
+}
+
+struct ForceThreadData<P : Float, F : Float> {
     time_started : Option<Instant>,
     time_ended : Arc<RwLock<Option<Instant>>>,
     clock : Arc<RwLock<Instant>>,
 
-    data : Arc<Vec<RwLock<Particle>>>,
+    inputs : ForceInputs<P, F>,
     start_idx : usize, 
     end_idx : usize, 
 
-    grid : Arc<RwLock<GridHandler>>,
-    quad : Arc<RwLock<MassTree>>,
-
-    G : Scalar,
-    collK : Scalar, 
-    collDampening : Scalar,
-
     thread : Option<JoinHandle<()>>,
 }
 
-impl ForceThreadData {
-    pub fn new(
-        clock : Arc<RwLock<Instant>>,
-        data : Arc<Vec<RwLock<Particle>>>, 
-        start_idx : usize, end_idx : usize, 
-        grid : Arc<RwLock<GridHandler>>, quad : Arc<RwLock<MassTree>>,  
-        G : Scalar, collK : Scalar, collDampening : Scalar
-    ) -> ForceThreadData {
+impl<P : Float, F : Float> ForceThreadData<P, F> {
+    pub fn new(clock : Arc<RwLock<Instant>>, inputs : ForceInputs<P, F>, start_idx : usize, end_idx : usize) -> ForceThreadData<P, F> {
         let time_started = {
             let guard = clock.read().unwrap();
-            let now : Instant = *guard;
             Some(*guard)
         };
 
@@ -366,14 +903,9 @@ impl ForceThreadData {
             time_started,
             time_ended : Arc::new(RwLock::new(None)),
             clock,
-            data, 
+            inputs,
             start_idx, 
             end_idx, 
-            grid, 
-            quad, 
-            G, 
-            collK,
-            collDampening, 
             thread : None,
         };
 
@@ -385,48 +917,353 @@ impl ForceThreadData {
 
         let start_idx = self.start_idx;
         let end_idx = self.end_idx;
-        let G = self.G;
-        let collK = self.collK;
-        let collDampening = self.collDampening;
-        let data_ref = Arc::clone(&self.data);
-        let quad_ref = Arc::clone(&self.quad);
-        let grid_ref = Arc::clone(&self.grid);
+        let inputs = self.inputs.clone();
+        let G = inputs.G;
+        let (G_f, collK, collDampening) = (cast::<Scalar, F>(inputs.G), cast::<Scalar, F>(inputs.collK), cast::<Scalar, F>(inputs.collDampening));
+        let (collK_s, collDampening_s) = (inputs.collK, inputs.collDampening);
         let time_end_ref = Arc::clone(&self.time_ended);
         let clock_ref = Arc::clone(&self.clock);
-        let new_handle = thread::spawn(move || {
+        thread::spawn(move || {
             for idx in start_idx..end_idx {
-                let mut part = data_ref[idx].write().unwrap();
-                let grav = quad_ref.read().unwrap().calculate_forces(*part, G);
-                let spring = grid_ref.read().unwrap().calculate_spring_force(*part, collK, collDampening);
+                let mut part = inputs.data[idx].write().unwrap();
+                let scalar_part : Particle = part.cast();
+                let grav = inputs.quad.read().unwrap().calculate_forces(*part, G_f);
+                let spring = match inputs.neighbors.as_ref() {
+                    Some(neighbors) => neighbors.read().unwrap().calculate_spring_force(idx, collK, collDampening),
+                    None => inputs.grid.read().unwrap().calculate_spring_force(*part, collK, collDampening),
+                };
+                let wall_contacts : Vec2d = inputs.walls.iter()
+                    .map(|wall| wall.calculate_contact_force(scalar_part, collK_s, collDampening_s))
+                    .sum();
                 part.f_grav = grav;
-                part.f_spring = spring;
+                part.f_spring = spring + wall_contacts.cast();
+                part.f_pair = if inputs.pair_forces.is_empty() {
+                    Vec2d::zero()
+                } else {
+                    pairforce::calculate_pair_forces(&inputs.pair_grid.read().unwrap(), scalar_part, &inputs.pair_forces).cast()
+                };
+                if let Some(charge_tree) = inputs.charge_tree.as_ref() {
+                    part.f_elec = charge_tree.read().unwrap().calculate_forces(scalar_part).cast();
+                }
+                part.f_bond = inputs.bonds.read().unwrap().calculate_forces(idx).cast();
+                part.f_ext = inputs.externals.iter()
+                    .map(|potential| scalar_part.mass * potential.acceleration(scalar_part.pos, G))
+                    .sum::<Vec2d>()
+                    .cast();
+                if let Some(sph) = inputs.sph.as_ref() {
+                    let (hydro, du_dt) = sph.read().unwrap().calculate_hydro_force(scalar_part);
+                    part.f_hydro = hydro.cast();
+                    if let Some(gas) = part.gas.as_mut() {
+                        gas.du_dt = cast(du_dt);
+                    }
+                }
             }
             let mut time_ended = time_end_ref.write().unwrap();
             *time_ended = {
                 let guard = clock_ref.read().unwrap();
                 Some(*guard)
             };
-        });
-        new_handle
+        })
+    }
+
+    pub fn wait(&mut self) {
+        if let Some(h) = self.thread.take() {
+            h.join().unwrap();
+        }
     }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bonds::Bond;
+    use crate::pairforce::{LennardJones, Yukawa, Morse};
+    use rand::Rng;
 
-    pub fn started(&self) -> bool {
-        self.time_started.is_some()
+    #[test]
+    fn update_after_removing_everything_is_a_no_op() {
+        let mut handler : PhysicsHandlerThreaded = PhysicsHandlerThreaded::new(1.0, 1000.0, 10.0);
+        handler.load_particles(vec![
+            Particle::new(1.0, 1.0, Vec2d::zero(), Vec2d::zero()),
+            Particle::new(1.0, 1.0, Vec2d::new(10.0, 10.0), Vec2d::zero()),
+        ]);
+        handler.update(0.01);
+        handler.remove_particles(|_| true);
+        for _ in 0..5 {
+            handler.update(0.01);
+        }
+        assert_eq!(handler.num_particles(), 0);
     }
 
-    pub fn ended(&self) -> bool {
-        let read_lock = self.time_ended.try_read();
-        let has_end_time = read_lock.map(|l| l.is_some());
-        has_end_time.unwrap_or(false)
+    /// A lattice close enough for some contacts, with uneven masses and
+    /// velocities so the tree and force sums have something to round.
+    fn lattice() -> Vec<Particle> {
+        (0..64).map(|i| {
+            let (x, y) = ((i % 8) as Scalar, (i / 8) as Scalar);
+            let pos = Vec2d::new(1.9 * x + 0.1 * y, 1.9 * y - 0.07 * x);
+            let vel = Vec2d::new((0.3 * i as Scalar).sin(), (0.7 * i as Scalar).cos());
+            Particle::new(1.0 + 0.01 * i as Scalar, 1.0, pos, vel)
+        })
+        .collect()
     }
 
-    pub fn is_running(&self) -> bool {
-        self.started() && !self.ended()
+    /// The lattice with alternating charges and a block of gas beside it.
+    fn scene() -> Vec<Particle> {
+        let mut parts = lattice().into_iter()
+            .enumerate()
+            .map(|(i, part)| part.with_charge(if i % 2 == 0 { 1.0 } else { -1.0 }))
+            .collect::<Vec<Particle>>();
+        for i in 0..16 {
+            let pos = Vec2d::new(20.0 + 0.5 * (i % 4) as Scalar, 0.5 * (i / 4) as Scalar);
+            parts.push(Particle::new_gas(0.1, pos, Vec2d::zero(), 1.0, 0.6));
+        }
+        parts
     }
 
-    pub fn wait(&mut self) {
-        if let Some(h) = self.thread.take() {
-            h.join().unwrap();
+    /// Breakable bonds along the bottom row of the lattice.
+    fn chain_bonds() -> BondList {
+        let mut bonds = BondList::new();
+        for i in 0..7 {
+            bonds.add_bond(Bond::new(i, i + 1, 1.9, 50.0).with_max_strain(0.5));
+        }
+        bonds
+    }
+
+    fn positions_after_steps<P : Float, F : Float>() -> Vec<Vec2d> {
+        let mut handler : PhysicsHandlerThreaded<P, F> = PhysicsHandlerThreaded::new(1.0, 1000.0, 10.0)
+            .with_sph(SphParams::default());
+        handler.load_particles(scene().iter().map(Particle::cast).collect::<Vec<Particle<P, F>>>());
+        handler.set_bonds(chain_bonds());
+        for _ in 0..20 {
+            handler.step(0.001);
+        }
+        handler.particles().into_iter().map(|part| part.pos.cast()).collect()
+    }
+
+    #[test]
+    fn single_and_mixed_precision_steps_follow_double() {
+        let double = positions_after_steps::<f64, f64>();
+        for (name, positions) in [
+            ("f32", positions_after_steps::<f32, f32>()),
+            ("f64 with f32 forces", positions_after_steps::<f64, f32>()),
+        ] {
+            let error = positions.iter().zip(double.iter())
+                .map(|(&pos, &exact)| (pos - exact).mag())
+                .fold(0.0, Scalar::max);
+            assert!(error > 0.0 && error < 1e-4, "{} is off by {}", name, error);
+        }
+    }
+
+    fn bits(handler : &PhysicsHandlerThreaded) -> Vec<Vec<u64>> {
+        handler.particles().into_iter()
+            .map(|part| {
+                let gas = part.gas.unwrap_or_else(|| GasState::new(0.0, 0.0));
+                vec![part.pos.x, part.pos.y, part.vel.x, part.vel.y, part.force().x, part.force().y,
+                    gas.internal_energy, gas.density]
+                    .into_iter()
+                    .map(Scalar::to_bits)
+                    .collect()
+            })
+            .collect()
+    }
+
+    type Setup = fn(PhysicsHandlerThreaded) -> PhysicsHandlerThreaded;
+
+    fn state_after_steps(num_threads : usize, setup : Setup) -> Vec<Vec<u64>> {
+        let mut handler : PhysicsHandlerThreaded = PhysicsHandlerThreaded::new(1.0, 1000.0, 10.0).with_threads(num_threads);
+        handler.load_particles(scene());
+        let mut handler = setup(handler);
+        for _ in 0..20 {
+            handler.step(0.001);
+        }
+        bits(&handler)
+    }
+
+    #[test]
+    fn neighbor_list_gives_the_grid_contact_forces() {
+        let with_list = |skin : Option<Scalar>| {
+            let handler : PhysicsHandlerThreaded = PhysicsHandlerThreaded::new(0.0, 1000.0, 10.0);
+            let mut handler = match skin {
+                Some(skin) => handler.with_neighbor_skin(skin),
+                None => handler,
+            };
+            handler.load_particles(lattice());
+            handler
+        };
+        let (mut grid, mut list) = (with_list(None), with_list(Some(0.5)));
+        for _ in 0..10 {
+            grid.step(0.001);
+            list.step(0.001);
+            for (expected, part) in grid.particles().into_iter().zip(list.particles()) {
+                let error = (part.f_spring - expected.f_spring).mag();
+                assert!(error <= 1e-9 * expected.f_spring.mag().max(1.0), "off by {}", error);
+            }
+        }
+        assert!(grid.particles().into_iter().any(|part| part.f_spring.mag() > 0.0));
+        assert!(list.neighbor_list_rebuilds() < 10);
+    }
+
+    #[test]
+    fn two_body_energies_are_analytic() {
+        let mut handler : PhysicsHandlerThreaded = PhysicsHandlerThreaded::new(2.0, 1000.0, 10.0);
+        handler.load_particles(vec![
+            Particle::new(3.0, 1.0, Vec2d::new(0.0, 0.0), Vec2d::new(1.0, 0.0)),
+            Particle::new(5.0, 1.0, Vec2d::new(3.0, 4.0), Vec2d::new(0.0, -2.0)),
+        ]);
+        assert_eq!(handler.kinetic_energy(), 0.5 * 3.0 + 0.5 * 5.0 * 4.0);
+        assert!((handler.potential_energy() + 2.0 * 3.0 * 5.0/5.0).abs() < 1e-12);
+        assert_eq!(handler.contact_energy(), 0.0);
+    }
+
+    #[test]
+    fn overlap_stores_half_k_delta_squared() {
+        let mut handler : PhysicsHandlerThreaded = PhysicsHandlerThreaded::new(0.0, 1000.0, 10.0);
+        handler.load_particles(vec![
+            Particle::new(1.0, 1.0, Vec2d::new(0.0, 0.0), Vec2d::zero()),
+            Particle::new(1.0, 1.0, Vec2d::new(1.08, 1.44), Vec2d::zero()),
+        ]);
+        assert!((handler.contact_energy() - 0.5 * 1000.0 * 0.04).abs() < 1e-9);
+    }
+
+    /// A Plummer disc laid out on a Vogel spiral, smooth enough for the net
+    /// force to point inwards everywhere, with every particle on the circular
+    /// orbit of the force it feels. That is virial equilibrium.
+    #[test]
+    fn circular_plummer_disc_is_virialized() {
+        let (G, num, scale) = (1.0, 400, 20.0);
+        let golden_angle = std::f64::consts::PI * (3.0 - (5.0 as Scalar).sqrt());
+        let mut parts = (0..num).map(|k| {
+            let enclosed = (k as Scalar + 0.5)/num as Scalar;
+            let r = scale * (enclosed/(1.0 - enclosed)).sqrt();
+            let (sin, cos) = (golden_angle * k as Scalar).sin_cos();
+            Particle::new(1.0, 0.01, Vec2d::new(r * cos, r * sin), Vec2d::zero())
+        })
+        .collect::<Vec<Particle>>();
+        let forces = parts.iter()
+            .map(|part| parts.iter()
+                .map(|other| {
+                    let diff = other.pos - part.pos;
+                    let d = diff.mag();
+                    if d > 0.5 { diff * (G * part.mass * other.mass/(d * d * d)) } else { Vec2d::zero() }
+                })
+                .sum::<Vec2d>())
+            .collect::<Vec<Vec2d>>();
+        for (part, force) in parts.iter_mut().zip(forces) {
+            let inwards = -part.pos.dot(force);
+            assert!(inwards > 0.0);
+            part.vel = Vec2d::new(-part.pos.y, part.pos.x) * ((inwards/part.mass).sqrt()/part.pos.mag());
+        }
+        let mut handler : PhysicsHandlerThreaded = PhysicsHandlerThreaded::new(G, 1000.0, 10.0);
+        handler.load_particles(parts);
+        let ratio = handler.virial_ratio();
+        assert!((ratio - 1.0).abs() < 0.02, "virial ratio {}", ratio);
+    }
+
+    /// Displacements far below an ulp of the f32 positions: plain updates
+    /// drop them, compensated ones add up.
+    fn drift_in_f32(compensated : bool) -> Scalar {
+        let handler : PhysicsHandlerThreaded<f32> = PhysicsHandlerThreaded::new(0.0, 1000.0, 10.0);
+        let mut handler = if compensated { handler.with_compensated_integration() } else { handler };
+        handler.load_particles(vec![
+            Particle::new(1.0, 1.0, Vec2d::new(1000.0, 1000.0), Vec2d::new(0.001, 0.0)),
+            Particle::new(1.0, 1.0, Vec2d::new(-1000.0, -1000.0), Vec2d::new(0.001, 0.0)),
+        ]);
+        for _ in 0..1000 {
+            handler.step(0.001);
+        }
+        handler.particles().into_iter().next().unwrap().pos.x as Scalar - 1000.0
+    }
+
+    #[test]
+    fn compensated_integration_keeps_sub_ulp_steps() {
+        assert_eq!(drift_in_f32(false), 0.0);
+        // Within an ulp of 1000.0f32, which is 2^-14.
+        assert!((drift_in_f32(true) - 0.001).abs() < (2.0 as Scalar).powi(-14));
+    }
+
+    #[test]
+    fn steps_are_bit_identical_across_thread_counts() {
+        let setups : Vec<(&str, Setup)> = vec![
+            ("plain", |handler| handler),
+            ("neighbor list", |handler| handler.with_neighbor_skin(0.5)),
+            ("sph", |handler| handler.with_sph(SphParams::default())),
+            ("bonds", |mut handler| {
+                handler.set_bonds(chain_bonds());
+                handler
+            }),
+            ("pair forces", |mut handler| {
+                handler.add_pair_force(LennardJones::new(0.1, 1.0));
+                handler.add_pair_force(Yukawa::new(0.5, 2.0));
+                handler
+            }),
+            ("charges", |handler| handler.with_electrostatics(ElectrostaticsParams::default())),
+            ("walls", |mut handler| {
+                handler.add_wall(Wall::bounding_box(Vec2d::new(23.0, 15.0), Vec2d::new(-1.0, -1.0)));
+                handler.add_wall(Wall::drum(Vec2d::new(7.0, 7.0), 12.0).with_rotation(Vec2d::new(7.0, 7.0), 0.5));
+                handler
+            }),
+        ];
+        for (name, setup) in setups {
+            assert_eq!(state_after_steps(1, setup), state_after_steps(4, setup), "{} differs", name);
         }
     }
-}
\ No newline at end of file
+
+    /// Steps with a time step drawn from `rng`, so the generator is part of
+    /// the state a checkpoint has to carry.
+    fn run(handler : &mut PhysicsHandlerThreaded, rng : &mut SimRng, steps : usize) {
+        for _ in 0..steps {
+            handler.step(0.001 * (1.0 + rng.gen::<Scalar>()));
+        }
+    }
+
+    #[test]
+    fn restored_checkpoint_steps_bit_identically() {
+        let mut handler : PhysicsHandlerThreaded = PhysicsHandlerThreaded::new(1.0, 1000.0, 10.0)
+            .with_threads(2)
+            .with_neighbor_skin(0.5)
+            .with_sph(SphParams::default());
+        handler.load_particles(scene());
+        handler.set_bonds(chain_bonds());
+        handler.add_pair_force(LennardJones::new(0.1, 1.0));
+        handler.add_pair_force(Morse::new(0.05, 2.0, 1.5));
+        let mut rng = SimRng::from_seed(3);
+        run(&mut handler, &mut rng, 10);
+        assert!(handler.neighbor_list_rebuilds() > 0);
+        assert!(handler.particles().into_iter().any(|part| part.gas.is_some_and(|gas| gas.density > 0.0)));
+
+        let mut buffer = Vec::new();
+        handler.write_checkpoint(&mut buffer, Some(&rng)).unwrap();
+        let (mut restored, restored_rng) = PhysicsHandlerThreaded::read_checkpoint(&mut buffer.as_slice()).unwrap();
+        let mut restored_rng = restored_rng.unwrap();
+        assert_eq!(restored_rng, rng);
+        assert_eq!(bits(&restored), bits(&handler));
+
+        run(&mut handler, &mut rng, 10);
+        run(&mut restored, &mut restored_rng, 10);
+        assert_eq!(bits(&restored), bits(&handler));
+        assert_eq!(restored.time().to_bits(), handler.time().to_bits());
+    }
+
+    struct Custom;
+
+    impl PairForce for Custom {
+        fn cutoff(&self) -> Scalar {
+            1.0
+        }
+        fn radial_force(&self, _r : Scalar, _arg : &Particle, _other : &Particle) -> Scalar {
+            0.0
+        }
+        fn potential(&self, _r : Scalar, _arg : &Particle, _other : &Particle) -> Scalar {
+            0.0
+        }
+    }
+
+    #[test]
+    fn custom_pair_forces_refuse_to_checkpoint() {
+        let mut handler : PhysicsHandlerThreaded = PhysicsHandlerThreaded::new(1.0, 1000.0, 10.0);
+        handler.load_particles(lattice());
+        handler.add_pair_force(Custom);
+        let mut buffer = Vec::new();
+        assert!(handler.write_checkpoint(&mut buffer, None).is_err());
+        assert!(buffer.is_empty());
+    }
+}
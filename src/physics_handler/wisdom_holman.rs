@@ -0,0 +1,374 @@
+use super::{PhysicsHandler, adopt_ids, assign_ids, split_removed, kinetic_energy_of, direct_potential_energy};
+use crate::particles::Particle;
+use crate::mathvec::{Scalar, Vec2d, compensated_sum};
+
+use std::f64::consts::PI;
+
+const KEPLER_TOLERANCE : Scalar = 1e-15;
+const MAX_KEPLER_ITERATIONS : usize = 64;
+const LAGUERRE_ORDER : Scalar = 5.0;
+
+/// Wisdom-Holman symplectic map in democratic heliocentric coordinates for
+/// systems dominated by one central mass (the most massive particle at load
+/// time). Each `update(dt)` is one kick-drift-kick step: interaction kicks
+/// between the orbiting bodies, the linear drift from the central body's
+/// recoil and an exact Kepler drift around the central mass. Particles of
+/// zero mass are test particles; contact forces are not evaluated.
+pub struct PhysicsHandlerWisdomHolman {
+    data : Vec<Particle>,
+    central : usize,
+    /// Positions relative to the central body.
+    helio_pos : Vec<Vec2d>,
+    /// Velocities relative to the barycentre.
+    bary_vel : Vec<Vec2d>,
+    cm_pos : Vec2d,
+    cm_vel : Vec2d,
+    time : Scalar,
+    next_id : u64,
+    G : Scalar,
+}
+
+impl PhysicsHandlerWisdomHolman {
+    pub fn particles(&self) -> &[Particle] {
+        &self.data
+    }
+
+    pub fn central(&self) -> usize {
+        self.central
+    }
+
+    pub fn time(&self) -> Scalar {
+        self.time
+    }
+
+
+    fn enter_democratic(&mut self) {
+        if self.data.is_empty() {
+            return;
+        }
+        self.central = self.data.iter().enumerate()
+            .fold(0, |best, (idx, part)| if part.mass > self.data[best].mass { idx } else { best });
+        let mass = self.total_mass();
+        self.cm_pos = self.total_mass_pos()/mass;
+        self.cm_vel = self.total_momentum()/mass;
+        let central_pos = self.data[self.central].pos;
+        self.helio_pos = self.data.iter().map(|part| part.pos - central_pos).collect();
+        self.bary_vel = self.data.iter().map(|part| part.vel - self.cm_vel).collect();
+    }
+
+    fn leave_democratic(&mut self) {
+        let mass = self.total_mass();
+        let central_mass = self.data[self.central].mass;
+        let mut weighted_pos = Vec::with_capacity(self.data.len());
+        let mut momentum = Vec::with_capacity(self.data.len());
+        for (idx, part) in self.data.iter().enumerate() {
+            if idx != self.central {
+                weighted_pos.push(part.mass * self.helio_pos[idx]);
+                momentum.push(part.mass * self.bary_vel[idx]);
+            }
+        }
+        let central_pos = self.cm_pos - weighted_pos.into_iter().sum::<Vec2d>()/mass;
+        let central_vel = self.cm_vel - momentum.into_iter().sum::<Vec2d>()/central_mass;
+        for (idx, part) in self.data.iter_mut().enumerate() {
+            if idx == self.central {
+                part.pos = central_pos;
+                part.vel = central_vel;
+            } else {
+                part.pos = central_pos + self.helio_pos[idx];
+                part.vel = self.cm_vel + self.bary_vel[idx];
+            }
+        }
+    }
+
+    /// Mutual gravity between the orbiting bodies, stored as `f_grav`.
+    fn interaction_kick(&mut self, dt : Scalar) {
+        let sources = self.data.iter().enumerate()
+            .filter(|&(idx, part)| idx != self.central && part.mass > 0.0)
+            .map(|(idx, part)| (idx, part.mass))
+            .collect::<Vec<(usize, Scalar)>>();
+        for idx in 0..self.data.len() {
+            if idx == self.central {
+                self.data[idx].f_grav = Vec2d::zero();
+                continue;
+            }
+            let pos = self.helio_pos[idx];
+            let acc = sources.iter()
+                .filter(|&&(other, _)| other != idx)
+                .map(|&(other, mass)| {
+                    let r = self.helio_pos[other] - pos;
+                    let r2 = r.mag_squared();
+                    (self.G * mass/(r2 * r2.sqrt())) * r
+                })
+                .sum::<Vec2d>();
+            self.bary_vel[idx] += dt * acc;
+            self.data[idx].f_grav = self.data[idx].mass * acc;
+        }
+    }
+
+    /// Drift from the kinetic energy of the central body.
+    fn jump(&mut self, dt : Scalar) {
+        let central_mass = self.data[self.central].mass;
+        let momentum = self.data.iter().enumerate()
+            .filter(|&(idx, _)| idx != self.central)
+            .map(|(idx, part)| part.mass * self.bary_vel[idx])
+            .sum::<Vec2d>();
+        let shift = (dt/central_mass) * momentum;
+        for idx in 0..self.data.len() {
+            if idx != self.central {
+                self.helio_pos[idx] += shift;
+            }
+        }
+    }
+
+    fn kepler_drift(&mut self, dt : Scalar) {
+        let mu = self.G * self.data[self.central].mass;
+        for idx in 0..self.data.len() {
+            if idx != self.central {
+                let (pos, vel) = kepler_step(self.helio_pos[idx], self.bary_vel[idx], mu, dt);
+                self.helio_pos[idx] = pos;
+                self.bary_vel[idx] = vel;
+            }
+        }
+    }
+}
+
+/// Stumpff functions c2 and c3 of `psi`.
+fn stumpff(psi : Scalar) -> (Scalar, Scalar) {
+    if psi.abs() < 1e-4 {
+        let c2 = 0.5 - psi/24.0 + psi * psi/720.0;
+        let c3 = 1.0/6.0 - psi/120.0 + psi * psi/5040.0;
+        (c2, c3)
+    } else if psi > 0.0 {
+        let sqrt_psi = psi.sqrt();
+        ((1.0 - sqrt_psi.cos())/psi, (sqrt_psi - sqrt_psi.sin())/(psi * sqrt_psi))
+    } else {
+        let sqrt_psi = (-psi).sqrt();
+        ((sqrt_psi.cosh() - 1.0)/(-psi), (sqrt_psi.sinh() - sqrt_psi)/(-psi * sqrt_psi))
+    }
+}
+
+/// Advances a Kepler orbit around gravitational parameter `mu` by `dt` with
+/// the universal variable formulation, valid for elliptic, parabolic and
+/// hyperbolic orbits. The universal anomaly is found with Laguerre-Conway
+/// iteration.
+pub fn kepler_step(pos : Vec2d, vel : Vec2d, mu : Scalar, dt : Scalar) -> (Vec2d, Vec2d) {
+    if mu <= 0.0 || dt == 0.0 {
+        return (pos + dt * vel, vel);
+    }
+    let sqrt_mu = mu.sqrt();
+    let r0 = pos.mag();
+    let sigma0 = pos.dot(vel)/sqrt_mu;
+    let alpha = 2.0/r0 - vel.mag_squared()/mu;
+
+    // Whole periods of bound orbits leave the state unchanged.
+    let dt = if alpha > 0.0 {
+        let period = 2.0 * PI/(sqrt_mu * alpha * alpha.sqrt());
+        dt - period * (dt/period).trunc()
+    } else {
+        dt
+    };
+
+    let mut chi = if alpha > 0.0 {
+        sqrt_mu * dt * alpha
+    } else {
+        sqrt_mu * dt/r0
+    };
+    for _ in 0..MAX_KEPLER_ITERATIONS {
+        let psi = alpha * chi * chi;
+        let (c2, c3) = stumpff(psi);
+        let f = sigma0 * chi * chi * c2 + (1.0 - alpha * r0) * chi * chi * chi * c3 + r0 * chi - sqrt_mu * dt;
+        let df = chi * chi * c2 + sigma0 * chi * (1.0 - psi * c3) + r0 * (1.0 - psi * c2);
+        let ddf = sigma0 * (1.0 - psi * c2) + (1.0 - alpha * r0) * chi * (1.0 - psi * c3);
+        let n = LAGUERRE_ORDER;
+        let discriminant = ((n - 1.0) * (n - 1.0) * df * df - n * (n - 1.0) * f * ddf).abs().sqrt();
+        let denominator = if df >= 0.0 { df + discriminant } else { df - discriminant };
+        let delta = n * f/denominator;
+        chi -= delta;
+        if delta.abs() <= KEPLER_TOLERANCE * chi.abs().max(1.0) {
+            break;
+        }
+    }
+
+    let psi = alpha * chi * chi;
+    let (c2, c3) = stumpff(psi);
+    let r = chi * chi * c2 + sigma0 * chi * (1.0 - psi * c3) + r0 * (1.0 - psi * c2);
+    let f = 1.0 - chi * chi * c2/r0;
+    let g = dt - chi * chi * chi * c3/sqrt_mu;
+    let df = sqrt_mu * chi * (psi * c3 - 1.0)/(r * r0);
+    let dg = 1.0 - chi * chi * c2/r;
+    (f * pos + g * vel, df * pos + dg * vel)
+}
+
+impl PhysicsHandler for PhysicsHandlerWisdomHolman {
+    type Position = Scalar;
+    type Force = Scalar;
+
+    fn new(G : Scalar, _collision_spring_constant : Scalar, _collision_dampening : Scalar) -> Self {
+        PhysicsHandlerWisdomHolman {
+            data : Vec::new(),
+            central : 0,
+            helio_pos : Vec::new(),
+            bary_vel : Vec::new(),
+            cm_pos : Vec2d::zero(),
+            cm_vel : Vec2d::zero(),
+            time : 0.0,
+            next_id : 0,
+            G,
+        }
+    }
+
+    fn zero_momentum_and_cm(&mut self) {
+        let mass = self.total_mass();
+        let pos_diff = self.total_mass_pos()/mass;
+        let momentum_diff = self.total_momentum()/mass;
+        for part in self.data.iter_mut() {
+            part.vel -= momentum_diff;
+            part.pos -= pos_diff;
+        }
+        self.enter_democratic();
+    }
+
+    fn angular_momentum(&self) -> Scalar {
+        compensated_sum(self.data.iter()
+            .map(|part| part.mass * (part.pos.x * part.vel.y - part.pos.y * part.vel.x)))
+    }
+
+    fn total_mass(&self) -> Scalar {
+        compensated_sum(self.data.iter().map(|part| part.mass))
+    }
+    fn total_mass_pos(&self) -> Vec2d {
+        self.data.iter().map(|part| part.mass * part.pos).sum()
+    }
+    fn total_momentum(&self) -> Vec2d {
+        self.data.iter().map(|part| part.mass * part.vel).sum()
+    }
+    fn kinetic_energy(&self) -> Scalar {
+        kinetic_energy_of(&self.data)
+    }
+
+    fn potential_energy(&self) -> Scalar {
+        direct_potential_energy(&self.data, self.G, 0.0)
+    }
+
+    fn num_particles(&self) -> usize {
+        self.data.len()
+    }
+
+    fn load_particles<T : AsRef<[Particle]>>(&mut self, particles : T) {
+        self.data = particles.as_ref().to_vec();
+        adopt_ids(&mut self.data, &mut self.next_id);
+        self.enter_democratic();
+    }
+
+    fn add_particles<T : AsRef<[Particle]>>(&mut self, particles : T) -> Vec<u64> {
+        let mut added = particles.as_ref().to_vec();
+        let ids = assign_ids(&mut added, &mut self.next_id);
+        self.data.extend(added);
+        self.enter_democratic();
+        ids
+    }
+
+    /// Removing the central body hands that role to the most massive
+    /// remaining particle.
+    fn remove_particles<R : FnMut(&Particle) -> bool>(&mut self, predicate : R) -> Vec<Particle> {
+        let parts = std::mem::take(&mut self.data);
+        let (kept, removed, _) = split_removed(parts, predicate);
+        self.data = kept;
+        self.enter_democratic();
+        removed
+    }
+
+    fn update(&mut self, dt : Scalar) {
+        if self.data.is_empty() {
+            return;
+        }
+        self.interaction_kick(0.5 * dt);
+        self.jump(0.5 * dt);
+        self.kepler_drift(dt);
+        self.jump(0.5 * dt);
+        self.interaction_kick(0.5 * dt);
+        self.cm_pos += dt * self.cm_vel;
+        self.time += dt;
+        self.leave_democratic();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn orbit_energy(pos : Vec2d, vel : Vec2d, mu : Scalar) -> Scalar {
+        0.5 * vel.mag_squared() - mu/pos.mag()
+    }
+
+    fn orbit_angular_momentum(pos : Vec2d, vel : Vec2d) -> Scalar {
+        pos.x * vel.y - pos.y * vel.x
+    }
+
+    #[test]
+    fn circular_orbit_returns_after_one_period() {
+        let (start_pos, start_vel) = (Vec2d::new(1.0, 0.0), Vec2d::new(0.0, 1.0));
+        let (mut pos, mut vel) = (start_pos, start_vel);
+        for _ in 0..100 {
+            let (npos, nvel) = kepler_step(pos, vel, 1.0, 2.0 * PI/100.0);
+            pos = npos;
+            vel = nvel;
+        }
+        assert!((pos - start_pos).mag() < 1e-12);
+        assert!((vel - start_vel).mag() < 1e-12);
+    }
+
+    #[test]
+    fn eccentric_and_hyperbolic_steps_conserve_the_orbit() {
+        let mu = 2.0;
+        // Pericentre of an e = 0.9 ellipse and of an e = 2 hyperbola.
+        let speeds = [(mu * 1.9f64).sqrt(), (mu * 3.0f64).sqrt()];
+        for &speed in speeds.iter() {
+            let (pos, vel) = (Vec2d::new(1.0, 0.0), Vec2d::new(0.0, speed));
+            let energy = orbit_energy(pos, vel, mu);
+            let ang_mom = orbit_angular_momentum(pos, vel);
+            for &dt in [1e-6, 0.3, 7.0, -2.5].iter() {
+                let (npos, nvel) = kepler_step(pos, vel, mu, dt);
+                assert!(((orbit_energy(npos, nvel, mu) - energy)/energy).abs() < 1e-12);
+                assert!(((orbit_angular_momentum(npos, nvel) - ang_mom)/ang_mom).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn stumpff_series_meets_closed_form() {
+        for &psi in [0.99e-4, -0.99e-4].iter() {
+            let (c2, c3) = stumpff(psi);
+            let (c2_closed, c3_closed) = if psi > 0.0 {
+                let s = psi.sqrt();
+                ((1.0 - s.cos())/psi, (s - s.sin())/(psi * s))
+            } else {
+                let s = (-psi).sqrt();
+                ((s.cosh() - 1.0)/(-psi), (s.sinh() - s)/(-psi * s))
+            };
+            assert!((c2 - c2_closed).abs() < 1e-11);
+            assert!((c3 - c3_closed).abs() < 1e-11);
+        }
+    }
+
+    #[test]
+    fn two_planet_energy_error_stays_bounded() {
+        let mut handler = PhysicsHandlerWisdomHolman::new(1.0, 0.0, 0.0);
+        let planet = |r : Scalar| Particle::new(1e-3, 0.0, Vec2d::new(r, 0.0), Vec2d::new(0.0, r.sqrt().recip()));
+        handler.load_particles(vec![Particle::new(1.0, 0.0, Vec2d::zero(), Vec2d::zero()), planet(1.0), planet(1.6)]);
+        handler.zero_momentum_and_cm();
+        let initial = handler.total_energy();
+        let dt = 2.0 * PI/50.0;
+        let orbits = 200;
+        let mut max_error = [0.0, 0.0];
+        for step in 0..orbits * 50 {
+            handler.update(dt);
+            let error = ((handler.total_energy() - initial)/initial).abs();
+            let half = if step < orbits * 25 { 0 } else { 1 };
+            max_error[half] = error.max(max_error[half]);
+        }
+        assert!(max_error[1] < 1e-5);
+        // A secular drift would roughly double the error in the second half.
+        assert!(max_error[1] < 1.5 * max_error[0]);
+    }
+}
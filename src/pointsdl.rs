@@ -2,30 +2,34 @@ use sdl2::{self, EventPump, Sdl};
 use sdl2::event::{Event};
 use sdl2::keyboard::{Keycode};
 use sdl2::mouse::{MouseButton};
-use sdl2::video::{Window, WindowContext, GLContext};
-use sdl2::image::{self, InitFlag, Sdl2ImageContext, LoadSurface, LoadTexture};
+use sdl2::video::{Window};
+use sdl2::image::{self, InitFlag, Sdl2ImageContext, LoadSurface};
 use sdl2::render::{BlendMode, Canvas};
 use sdl2::surface::{Surface};
 use sdl2::render::Texture as SDL_Texture;
-use sdl2::pixels::{PixelMasks, PixelFormatEnum};
 use sdl2::rect::{Rect};
 
 use crate::camera::CameraController;
+use rust_nbody::mathvec::{Scalar, Vec2d};
+use rust_nbody::particles::Particle3d;
 
+use std::cmp::Ordering;
 use std::path::Path;
 
 const DEFAULT_WIDTH : u32 = 800;
 const DEFAULT_HEIGHT : u32 = 800;
+const VIEW_ROTATE_STEP : f64 = 0.05;
 
 
 
 pub struct Screen {
     pub scwidth : u32, 
     pub scheight : u32,
-    sdl_ctx : Sdl,
+    // Held only to keep SDL and SDL_image initialised while the screen lives.
+    _sdl_ctx : Sdl,
     pub cam : CameraController,
     event_pump : EventPump,
-    pub image_context : Sdl2ImageContext,
+    _image_context : Sdl2ImageContext,
     running : bool, 
     mouse_context : MouseInfo,
     pub draw_context : ParticleDrawingContext,
@@ -40,7 +44,7 @@ pub struct MouseInfo {
 
 impl Screen {
     pub fn init() -> Result<Self, String> {
-        let cam = CameraController::new(0.0, 0.0,0.0, (DEFAULT_HEIGHT as f64)/(DEFAULT_WIDTH as f64));
+        let cam = CameraController::new(0.0, 0.0, 0.0);
         let sdl_ctx = sdl2::init()?;
         let events = sdl_ctx.event_pump()?;
         let video_subsystem = sdl_ctx.video()?;
@@ -58,10 +62,10 @@ impl Screen {
         Ok(Screen {
             scwidth : DEFAULT_WIDTH,
             scheight : DEFAULT_HEIGHT,
-            sdl_ctx,
+            _sdl_ctx : sdl_ctx,
             cam,
             event_pump : events,
-            image_context : image_ctx,
+            _image_context : image_ctx,
             running : true, 
             mouse_context : MouseInfo::default(),
             draw_context : ParticleDrawingContext::new(renderer),
@@ -84,6 +88,18 @@ impl Screen {
                 Event::Quit{..} | Event::KeyDown {keycode : Some(Keycode::Escape), ..} => {
                     self.running = false;
                 },
+                Event::KeyDown {keycode : Some(Keycode::Left), ..} => {
+                    self.cam.rotate_view(-VIEW_ROTATE_STEP, 0.0);
+                },
+                Event::KeyDown {keycode : Some(Keycode::Right), ..} => {
+                    self.cam.rotate_view(VIEW_ROTATE_STEP, 0.0);
+                },
+                Event::KeyDown {keycode : Some(Keycode::Up), ..} => {
+                    self.cam.rotate_view(0.0, VIEW_ROTATE_STEP);
+                },
+                Event::KeyDown {keycode : Some(Keycode::Down), ..} => {
+                    self.cam.rotate_view(0.0, -VIEW_ROTATE_STEP);
+                },
                 Event::MouseButtonDown{mouse_btn : MouseButton::Left, x, y, ..} => {
                     self.mouse_context.down = true;
                     self.mouse_context.ndcmousex = x as f64;
@@ -113,18 +129,26 @@ impl Screen {
         self.cam.update(1.0/30.0);
     }
 
-    pub fn quit(mut self) {
-
+    /// Draws 3D particles through the camera's view rotation (arrow keys),
+    /// back to front by depth so nearer particles cover farther ones.
+    pub fn draw_particles_3d(&mut self, particles : &[Particle3d]) -> Result<(), String> {
+        let mut projected = particles.iter()
+            .map(|part| self.cam.project(part.pos))
+            .collect::<Vec<(Vec2d, Scalar)>>();
+        projected.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+        let transx = self.cam.x();
+        let transy = self.cam.y();
+        for (pos, _) in projected {
+            self.draw_context.draw_point(
+                (pos.x - transx).floor() as i32,
+                (pos.y - transy).floor() as i32
+            )?;
+        }
+        Ok(())
     }
 
-    pub fn save_screenshot_bmp<T : AsRef<Path>>(filepath : T, renderer : Canvas<Window>) -> Result<(), String> {
-        let format = PixelFormatEnum::ARGB8888;
-        let pixels = renderer.read_pixels(None, format)?;
+    pub fn quit(self) {
 
-        let mut surface = sdl2::surface::Surface::from_pixelmasks(DEFAULT_WIDTH, DEFAULT_HEIGHT, format.into_masks()?)?;
-        let raw_surface = surface.raw();
-        let raw_surface_ref = unsafe { raw_surface.as_mut().ok_or("Error getting raw output surface!")?};
-        Ok(())
     }
 }
 
@@ -159,6 +183,7 @@ impl ParticleDrawingContext {
         Ok(())
     }
 
+    #[allow(dead_code)]
     pub fn init_draw_style_circles(&mut self) -> Result<(), String> {
         self.ptype = 1;
         self.color = (0, 0, 0, 255);
@@ -170,6 +195,7 @@ impl ParticleDrawingContext {
         self.i = 0;
         Ok(())
     }
+    #[allow(dead_code)]
     pub fn init_draw_style_T_circles(&mut self) -> Result<(), String> {
         self.ptype = 2;
         self.color = (0, 0, 0, 255);
@@ -228,6 +254,7 @@ impl ParticleDrawingContext {
     }
 }
 
+#[derive(Default)]
 pub struct LTexture{
     mwidth : u32, 
     mheight : u32, 
@@ -254,7 +281,7 @@ impl LTexture {
             }
         };
 
-        renderer.copy(&texture, None, dst)
+        renderer.copy(texture, None, dst)
     }
 
     pub fn render_wh(&mut self, renderer : &mut Canvas<Window>, x : i32, y : i32, w : u32, h : u32) -> Result<(), String> {
@@ -283,13 +310,3 @@ impl Drop for LTexture {
         }
     }
 }
-
-impl Default for LTexture {
-    fn default() -> LTexture {
-        LTexture {
-            mwidth : 0,
-            mheight : 0,
-            mtexture : None,
-        }
-    }
-}
\ No newline at end of file
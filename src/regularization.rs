@@ -0,0 +1,196 @@
+use crate::mathvec::{Scalar, Vec2d};
+use crate::particles::Particle;
+
+use std::error::Error;
+use std::fmt;
+
+/// Nominal fictitious time step in units of sqrt(r/mu), roughly 125 steps
+/// per orbit.
+const REG_STEP : Scalar = 0.05;
+const MAX_REG_STEPS : usize = 1_000_000;
+
+fn cmul(a : Vec2d, b : Vec2d) -> Vec2d {
+    Vec2d::new(a.x * b.x - a.y * b.y, a.x * b.y + a.y * b.x)
+}
+
+fn conj(a : Vec2d) -> Vec2d {
+    Vec2d::new(a.x, -a.y)
+}
+
+fn csqrt(z : Vec2d) -> Vec2d {
+    let r = z.mag();
+    let x = (0.5 * (r + z.x)).sqrt();
+    let y = (0.5 * (r - z.x)).sqrt();
+    Vec2d::new(x, if z.y < 0.0 { -y } else { y })
+}
+
+/// Two-body energy per unit reduced mass of the relative motion.
+pub fn relative_energy(rel_pos : Vec2d, rel_vel : Vec2d, mu : Scalar) -> Scalar {
+    0.5 * rel_vel.mag_squared() - mu/rel_pos.mag()
+}
+
+/// Mutually nearest pairs closer than `radius` that are bound to each other.
+/// Each particle ends up in at most one pair. A third body coming closer to
+/// either member than its partner breaks the pair up, so compact triples
+/// and larger subsystems are left to the direct integration rather than
+/// regularized as a chain.
+pub fn find_tight_pairs(parts : &[Particle], radius : Scalar, G : Scalar) -> Vec<(usize, usize)> {
+    let nearest = (0..parts.len()).map(|i| {
+        let mut best : Option<(usize, Scalar)> = None;
+        for j in 0..parts.len() {
+            let d = (parts[j].pos - parts[i].pos).mag_squared();
+            if j != i && best.is_none_or(|(_, best_d)| d < best_d) {
+                best = Some((j, d));
+            }
+        }
+        best
+    })
+    .collect::<Vec<_>>();
+
+    let mut pairs = Vec::new();
+    for (i, candidate) in nearest.iter().enumerate() {
+        if let Some((j, d2)) = *candidate {
+            if j <= i || nearest[j].map(|(k, _)| k) != Some(i) || d2 > radius * radius {
+                continue;
+            }
+            let mu = G * (parts[i].mass + parts[j].mass);
+            if relative_energy(parts[i].pos - parts[j].pos, parts[i].vel - parts[j].vel, mu) < 0.0 {
+                pairs.push((i, j));
+            }
+        }
+    }
+    pairs
+}
+
+/// Levi-Civita (two dimensional Kustaanheimo-Stiefel) state: the relative
+/// position is z = u^2 and physical time runs as dt = |z| ds, which turns
+/// the Kepler problem into a harmonic oscillator without the 1/r
+/// singularity.
+#[derive(Copy, Clone, Debug)]
+struct LeviCivita {
+    u : Vec2d,
+    u_prime : Vec2d,
+    energy : Scalar,
+    time : Scalar,
+}
+
+impl LeviCivita {
+    fn from_relative(rel_pos : Vec2d, rel_vel : Vec2d, mu : Scalar) -> LeviCivita {
+        let u = csqrt(rel_pos);
+        LeviCivita {
+            u,
+            u_prime : 0.5 * cmul(conj(u), rel_vel),
+            energy : relative_energy(rel_pos, rel_vel, mu),
+            time : 0.0,
+        }
+    }
+
+    fn to_relative(self) -> (Vec2d, Vec2d) {
+        let r = self.u.mag_squared();
+        (cmul(self.u, self.u), (2.0/r) * cmul(self.u, self.u_prime))
+    }
+
+    fn derivative<P : Fn(Scalar) -> Vec2d>(&self, perturbation : &P) -> LeviCivita {
+        let r = self.u.mag_squared();
+        let p = perturbation(self.time);
+        LeviCivita {
+            u : self.u_prime,
+            u_prime : (0.5 * self.energy) * self.u + (0.5 * r) * cmul(conj(self.u), p),
+            energy : 2.0 * cmul(self.u, self.u_prime).dot(p),
+            time : r,
+        }
+    }
+
+    fn add_scaled(&self, other : &LeviCivita, ds : Scalar) -> LeviCivita {
+        LeviCivita {
+            u : self.u + ds * other.u,
+            u_prime : self.u_prime + ds * other.u_prime,
+            energy : self.energy + ds * other.energy,
+            time : self.time + ds * other.time,
+        }
+    }
+
+    fn rk4_step<P : Fn(Scalar) -> Vec2d>(&self, ds : Scalar, perturbation : &P) -> LeviCivita {
+        let k1 = self.derivative(perturbation);
+        let k2 = self.add_scaled(&k1, 0.5 * ds).derivative(perturbation);
+        let k3 = self.add_scaled(&k2, 0.5 * ds).derivative(perturbation);
+        let k4 = self.add_scaled(&k3, ds).derivative(perturbation);
+        LeviCivita {
+            u : self.u + (ds/6.0) * (k1.u + 2.0 * k2.u + 2.0 * k3.u + k4.u),
+            u_prime : self.u_prime + (ds/6.0) * (k1.u_prime + 2.0 * k2.u_prime + 2.0 * k3.u_prime + k4.u_prime),
+            energy : self.energy + (ds/6.0) * (k1.energy + 2.0 * k2.energy + 2.0 * k3.energy + k4.energy),
+            time : self.time + (ds/6.0) * (k1.time + 2.0 * k2.time + 2.0 * k3.time + k4.time),
+        }
+    }
+}
+
+/// A regularized pair that did not reach the end of its interval within
+/// `MAX_REG_STEPS` fictitious-time steps, e.g. because the outer step spans
+/// thousands of its orbits.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct RegularizationError {
+    pub reached : Scalar,
+    pub dt : Scalar,
+}
+
+impl fmt::Display for RegularizationError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "regularized pair only reached t = {:e} of a {:e} step in {} substeps", self.reached, self.dt, MAX_REG_STEPS)
+    }
+}
+
+impl Error for RegularizationError {}
+
+/// Advances the relative motion `rel_pos`, `rel_vel` of a pair with total
+/// gravitational parameter `mu` = G (m1 + m2) by physical time `dt`.
+/// `perturbation(t)` is the relative acceleration from everything outside the
+/// pair, t measured from the start of the interval.
+pub fn advance_relative<P : Fn(Scalar) -> Vec2d>(rel_pos : Vec2d, rel_vel : Vec2d, mu : Scalar, dt : Scalar, perturbation : P) 
+    -> Result<(Vec2d, Vec2d), RegularizationError> 
+{
+    let mut state = LeviCivita::from_relative(rel_pos, rel_vel, mu);
+    let tolerance = 1e-14 * dt;
+    let mut steps = 0;
+    while dt - state.time > tolerance {
+        if steps == MAX_REG_STEPS {
+            return Err(RegularizationError { reached : state.time, dt });
+        }
+        let r = state.u.mag_squared();
+        // dt/ds = r, so remaining/r lands (close to) on the end of the interval.
+        let ds = (REG_STEP * (r/mu).sqrt()).min((dt - state.time)/r);
+        state = state.rk4_step(ds, &perturbation);
+        steps += 1;
+    }
+    Ok(state.to_relative())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics_handler::wisdom_holman::kepler_step;
+
+    #[test]
+    fn unperturbed_pair_follows_the_kepler_solution_through_pericentre() {
+        // e = 0.99 ellipse with a = 1 started at apocentre, passing
+        // pericentre at r = 0.01 halfway through the period.
+        let (pos, vel, mu) = (Vec2d::new(1.99, 0.0), Vec2d::new(0.0, (0.01f64/1.99).sqrt()), 1.0);
+        let energy = relative_energy(pos, vel, mu);
+        for &dt in [0.5 * std::f64::consts::PI, 2.0 * std::f64::consts::PI].iter() {
+            let (rel_pos, rel_vel) = advance_relative(pos, vel, mu, dt, |_| Vec2d::zero()).unwrap();
+            let (exact_pos, exact_vel) = kepler_step(pos, vel, mu, dt);
+            // The orbit is kept to 1e-9 in energy; what remains is a small
+            // phase error from the fictitious-time integration.
+            assert!(((relative_energy(rel_pos, rel_vel, mu) - energy)/energy).abs() < 1e-8);
+            assert!((rel_pos - exact_pos).mag() < 1e-4);
+            assert!((rel_vel - exact_vel).mag() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn step_cap_is_an_error() {
+        let (pos, vel) = (Vec2d::new(1.0, 0.0), Vec2d::new(0.0, 1.0));
+        let err = advance_relative(pos, vel, 1.0, 1e6, |_| Vec2d::zero()).unwrap_err();
+        assert_eq!(err.dt, 1e6);
+        assert!(err.reached < 1e6);
+    }
+}
@@ -0,0 +1,226 @@
+use crate::mathvec::{Scalar, Vec2d};
+use crate::particles::Particle;
+use crate::binio::*;
+
+use std::io::{self, Read, Write};
+use std::error::Error;
+use std::fmt;
+
+fn cross(a : Vec2d, b : Vec2d) -> Scalar {
+    a.x * b.y - a.y * b.x
+}
+
+/// Why a set of particle indices cannot form a `RigidBody`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RigidBodyError {
+    NoMembers,
+    OutOfRange { index : usize, num_particles : usize },
+    Duplicate { index : usize },
+    NoMass,
+}
+
+impl fmt::Display for RigidBodyError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RigidBodyError::NoMembers => write!(f, "rigid body has no members"),
+            RigidBodyError::OutOfRange { index, num_particles } => 
+                write!(f, "rigid body member {} is out of range for {} particles", index, num_particles),
+            RigidBodyError::Duplicate { index } => write!(f, "rigid body member {} is listed twice", index),
+            RigidBodyError::NoMass => write!(f, "rigid body members have no mass"),
+        }
+    }
+}
+
+impl Error for RigidBodyError {}
+
+/// A group of particles moved as one rigid aggregate. Member positions are
+/// stored as body-frame offsets from the centre of mass at `angle == 0`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct RigidBody {
+    members : Vec<usize>,
+    offsets : Vec<Vec2d>,
+    pub pos : Vec2d,
+    pub vel : Vec2d,
+    pub angle : Scalar,
+    pub angular_velocity : Scalar,
+    pub mass : Scalar,
+    pub moment_of_inertia : Scalar,
+}
+
+impl RigidBody {
+    /// Every index in `members` has to refer to a distinct particle of
+    /// `parts`, and together they need a positive mass.
+    pub fn from_members(members : Vec<usize>, parts : &[Particle]) -> Result<RigidBody, RigidBodyError> {
+        if members.is_empty() {
+            return Err(RigidBodyError::NoMembers);
+        }
+        let mut seen = vec![false ; parts.len()];
+        for &index in members.iter() {
+            match seen.get_mut(index) {
+                None => return Err(RigidBodyError::OutOfRange { index, num_particles : parts.len() }),
+                Some(true) => return Err(RigidBodyError::Duplicate { index }),
+                Some(flag) => *flag = true,
+            }
+        }
+        let mass : Scalar = members.iter().map(|&idx| parts[idx].mass).sum();
+        if mass <= 0.0 {
+            return Err(RigidBodyError::NoMass);
+        }
+        let pos = members.iter().map(|&idx| parts[idx].mass * parts[idx].pos).sum::<Vec2d>()/mass;
+        let vel = members.iter().map(|&idx| parts[idx].mass * parts[idx].vel).sum::<Vec2d>()/mass;
+        let offsets : Vec<Vec2d> = members.iter().map(|&idx| parts[idx].pos - pos).collect();
+        let moment_of_inertia : Scalar = members.iter().zip(offsets.iter())
+            .map(|(&idx, offset)| parts[idx].mass * offset.mag_squared())
+            .sum();
+        let angular_momentum : Scalar = members.iter().zip(offsets.iter())
+            .map(|(&idx, offset)| parts[idx].mass * cross(*offset, parts[idx].vel - vel))
+            .sum();
+        let angular_velocity = if moment_of_inertia > 0.0 { angular_momentum/moment_of_inertia } else { 0.0 };
+        Ok(RigidBody {
+            members,
+            offsets,
+            pos,
+            vel,
+            angle : 0.0,
+            angular_velocity,
+            mass,
+            moment_of_inertia,
+        })
+    }
+
+    /// Rebuilds the body after particles were removed (see
+    /// `BondList::remap`) from the members that remain, or `None` if they
+    /// no longer make a body.
+    pub fn remap(&self, map : &[Option<usize>], parts : &[Particle]) -> Option<RigidBody> {
+        let members = self.members.iter()
+            .filter_map(|&idx| map.get(idx).cloned().unwrap_or(None))
+            .collect::<Vec<usize>>();
+        RigidBody::from_members(members, parts).ok()
+    }
+
+    pub fn write_to<W : Write>(&self, out : &mut W) -> io::Result<()> {
+        write_len(out, self.members.len())?;
+        for (&idx, &offset) in self.members.iter().zip(self.offsets.iter()) {
+            write_len(out, idx)?;
+            write_vec2d(out, offset)?;
+        }
+        write_vec2d(out, self.pos)?;
+        write_vec2d(out, self.vel)?;
+        write_f64(out, self.angle)?;
+        write_f64(out, self.angular_velocity)?;
+        write_f64(out, self.mass)?;
+        write_f64(out, self.moment_of_inertia)
+    }
+    pub fn read_from<R : Read>(input : &mut R) -> io::Result<RigidBody> {
+        let len = read_len(input)?;
+        let mut members = Vec::new();
+        let mut offsets = Vec::new();
+        for _ in 0..len {
+            members.push(read_len(input)?);
+            offsets.push(read_vec2d(input)?);
+        }
+        Ok(RigidBody {
+            members,
+            offsets,
+            pos : read_vec2d(input)?,
+            vel : read_vec2d(input)?,
+            angle : read_f64(input)?,
+            angular_velocity : read_f64(input)?,
+            mass : read_f64(input)?,
+            moment_of_inertia : read_f64(input)?,
+        })
+    }
+
+    pub fn members(&self) -> &[usize] {
+        &self.members
+    }
+
+    pub fn angular_momentum(&self) -> Scalar {
+        self.moment_of_inertia * self.angular_velocity
+    }
+
+    /// Net force and torque (about the centre of mass) on the members.
+    pub fn force_and_torque(&self, parts : &[Particle]) -> (Vec2d, Scalar) {
+        let mut force = Vec2d::zero();
+        let mut torque = 0.0;
+        for (&idx, offset) in self.members.iter().zip(self.offsets.iter()) {
+            let f = parts[idx].force();
+            force += f;
+            torque += cross(offset.rotate(self.angle), f);
+        }
+        (force, torque)
+    }
+
+    pub fn step(&mut self, force : Vec2d, torque : Scalar, dt : Scalar) {
+        let accel = force/self.mass;
+        self.pos += dt * (self.vel + 0.5 * dt * accel);
+        self.vel += dt * accel;
+        if self.moment_of_inertia > 0.0 {
+            let alpha = torque/self.moment_of_inertia;
+            self.angle += dt * (self.angular_velocity + 0.5 * dt * alpha);
+            self.angular_velocity += dt * alpha;
+        }
+    }
+
+    pub fn shift(&mut self, pos_diff : Vec2d, vel_diff : Vec2d) {
+        self.pos -= pos_diff;
+        self.vel -= vel_diff;
+    }
+
+    /// Position and velocity of each member implied by the body state.
+    pub fn member_states(&self) -> impl Iterator<Item=(usize, Vec2d, Vec2d)> + '_ {
+        self.members.iter().zip(self.offsets.iter()).map(move |(&idx, offset)| {
+            let arm = offset.rotate(self.angle);
+            (idx, self.pos + arm, self.vel + self.angular_velocity * arm.perp())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics_handler::PhysicsHandler;
+    use crate::physics_handler::threaded::PhysicsHandlerThreaded;
+
+    fn parts() -> Vec<Particle> {
+        vec![
+            Particle::new(1.0, 0.1, Vec2d::new(0.0, 0.0), Vec2d::new(0.0, -1.0)),
+            Particle::new(2.0, 0.1, Vec2d::new(2.0, 0.0), Vec2d::new(0.0, 1.0)),
+            Particle::new(1.5, 0.1, Vec2d::new(1.0, 3.0), Vec2d::new(-1.0, 0.0)),
+            Particle::new(0.0, 0.1, Vec2d::new(5.0, 5.0), Vec2d::zero()),
+        ]
+    }
+
+    #[test]
+    fn invalid_members_are_rejected() {
+        let parts = parts();
+        assert_eq!(RigidBody::from_members(vec![], &parts), Err(RigidBodyError::NoMembers));
+        assert_eq!(RigidBody::from_members(vec![0, 4], &parts), Err(RigidBodyError::OutOfRange { index : 4, num_particles : 4 }));
+        assert_eq!(RigidBody::from_members(vec![0, 1, 0], &parts), Err(RigidBodyError::Duplicate { index : 0 }));
+        assert_eq!(RigidBody::from_members(vec![3], &parts), Err(RigidBodyError::NoMass));
+        assert!(RigidBody::from_members(vec![0, 1, 2], &parts).is_ok());
+    }
+
+    /// Gravity between the members is internal, so the body keeps spinning at
+    /// a constant rate and stays rigid.
+    #[test]
+    fn torque_free_body_keeps_its_spin() {
+        let mut handler : PhysicsHandlerThreaded = PhysicsHandlerThreaded::new(1.0, 1000.0, 10.0);
+        handler.load_particles(&parts()[..3]);
+        handler.add_rigid_body(vec![0, 1, 2]).unwrap();
+        let initial = handler.rigid_bodies()[0].clone();
+        let distance = |parts : &[Particle], a : usize, b : usize| (parts[a].pos - parts[b].pos).mag();
+        for _ in 0..1000 {
+            handler.step(0.001);
+        }
+        let body = &handler.rigid_bodies()[0];
+        assert!(initial.angular_velocity.abs() > 0.1);
+        assert!((body.angular_velocity - initial.angular_velocity).abs() < 1e-12);
+        assert!((body.angle - initial.angular_velocity).abs() < 1e-9);
+        let before = parts();
+        let after = handler.particles().into_iter().collect::<Vec<Particle>>();
+        for &(a, b) in [(0, 1), (1, 2), (0, 2)].iter() {
+            assert!((distance(&after, a, b) - distance(&before, a, b)).abs() < 1e-12);
+        }
+    }
+}
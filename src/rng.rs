@@ -0,0 +1,122 @@
+use rand::{RngCore, Error};
+use rand_pcg::Mcg128Xsl64;
+
+/// Multiplier of `Mcg128Xsl64`, needed to jump the stream ahead on restore.
+const MULTIPLIER : u128 = 0x2360_ED05_1FC6_5DA4_4385_DF64_9FCC_F645;
+
+fn splitmix64(state : &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Spreads `seed` over the 128 bit state with SplitMix64 so that small
+/// seeds still start far apart. The state of a multiplicative generator
+/// has to be odd.
+fn initial_state(seed : u64) -> u128 {
+    let mut mixer = seed;
+    let low = splitmix64(&mut mixer) as u128;
+    let high = splitmix64(&mut mixer) as u128;
+    (high << 64 | low) | 1
+}
+
+/// `rand_pcg::Mcg128Xsl64` seeded from a `u64`, counting its draws. The
+/// generator uses only integer arithmetic, so a seed gives the same stream
+/// on every platform, and the seed and draw count are all it takes to save
+/// and restore it.
+#[derive(Clone, Debug)]
+pub struct SimRng {
+    seed : u64,
+    draws : u128,
+    inner : Mcg128Xsl64,
+}
+
+impl SimRng {
+    pub fn from_seed(seed : u64) -> SimRng {
+        SimRng::resume(seed, 0)
+    }
+
+    /// The generator of `seed` after `draws` 64 bit outputs, reached by
+    /// raising the multiplier to the `draws`-th power.
+    pub fn resume(seed : u64, draws : u128) -> SimRng {
+        let (mut factor, mut jump, mut remaining) = (1u128, MULTIPLIER, draws);
+        while remaining > 0 {
+            if remaining & 1 == 1 {
+                factor = factor.wrapping_mul(jump);
+            }
+            jump = jump.wrapping_mul(jump);
+            remaining >>= 1;
+        }
+        SimRng {
+            seed,
+            draws,
+            inner : Mcg128Xsl64::new(initial_state(seed).wrapping_mul(factor)),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn draws(&self) -> u128 {
+        self.draws
+    }
+}
+
+impl PartialEq for SimRng {
+    fn eq(&self, other : &SimRng) -> bool {
+        self.seed == other.seed && self.draws == other.draws
+    }
+}
+
+impl Eq for SimRng {}
+
+impl RngCore for SimRng {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.draws += 1;
+        self.inner.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest : &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest : &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resume_continues_the_stream() {
+        let mut rng = SimRng::from_seed(7);
+        for _ in 0..1000 {
+            rng.next_u64();
+        }
+        let mut resumed = SimRng::resume(rng.seed(), rng.draws());
+        assert_eq!(resumed, rng);
+        for _ in 0..10 {
+            assert_eq!(resumed.next_u64(), rng.next_u64());
+        }
+    }
+
+    #[test]
+    fn first_draws_are_pinned() {
+        let mut rng = SimRng::from_seed(0);
+        let draws = (0..3).map(|_| rng.next_u64()).collect::<Vec<u64>>();
+        assert_eq!(draws, vec![10786275978957676184, 8541508437874622699, 2400753157982774436]);
+    }
+}
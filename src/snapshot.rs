@@ -0,0 +1,298 @@
+use crate::mathvec::{Scalar, Vec2d};
+use crate::particles::{Particle, GasState};
+use crate::physconstants::Units;
+use crate::binio::*;
+
+use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::fs::File;
+use std::path::Path;
+
+const SNAPSHOT_MAGIC : &[u8; 4] = b"NBSN";
+const SNAPSHOT_VERSION : u32 = 1;
+
+/// One column of a binary snapshot. Vectors are stored as interleaved x, y
+/// pairs, `Gas` as a per particle flag followed by the internal energy and
+/// smoothing length of every particle (zero where the flag is unset).
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SnapshotField {
+    Position,
+    Velocity,
+    Mass,
+    Radius,
+    Charge,
+    Id,
+    Species,
+    Gas,
+}
+
+impl SnapshotField {
+    pub const ALL : [SnapshotField; 8] = [
+        SnapshotField::Position,
+        SnapshotField::Velocity,
+        SnapshotField::Mass,
+        SnapshotField::Radius,
+        SnapshotField::Charge,
+        SnapshotField::Id,
+        SnapshotField::Species,
+        SnapshotField::Gas,
+    ];
+
+    fn tag(self) -> u8 {
+        match self {
+            SnapshotField::Position => 0,
+            SnapshotField::Velocity => 1,
+            SnapshotField::Mass => 2,
+            SnapshotField::Radius => 3,
+            SnapshotField::Charge => 4,
+            SnapshotField::Id => 5,
+            SnapshotField::Species => 6,
+            SnapshotField::Gas => 7,
+        }
+    }
+    fn from_tag(tag : u8) -> Option<SnapshotField> {
+        SnapshotField::ALL.iter().cloned().find(|field| field.tag() == tag)
+    }
+    /// Bytes per particle in the column.
+    fn width(self) -> u64 {
+        match self {
+            SnapshotField::Position | SnapshotField::Velocity => 16,
+            SnapshotField::Mass | SnapshotField::Radius | SnapshotField::Charge | SnapshotField::Id => 8,
+            SnapshotField::Species => 4,
+            SnapshotField::Gas => 17,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SnapshotHeader {
+    pub time : Scalar,
+    pub num_particles : usize,
+    pub G : Scalar,
+    pub units : Units,
+    pub fields : Vec<SnapshotField>,
+}
+
+/// Compact little endian particle dump: magic, version, header, then one
+/// column per field. Each column is preceded in the header by its tag and
+/// byte length, so readers skip columns from newer versions they don't know.
+/// Forces and handler state are not included; see
+/// `PhysicsHandlerThreaded::write_checkpoint` for a full restart file.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Snapshot {
+    pub header : SnapshotHeader,
+    pub particles : Vec<Particle>,
+}
+
+impl Snapshot {
+    /// Stores every field; narrow it down with `with_fields`.
+    pub fn new(time : Scalar, G : Scalar, particles : Vec<Particle>) -> Snapshot {
+        Snapshot {
+            header : SnapshotHeader {
+                time,
+                num_particles : particles.len(),
+                G,
+                units : Units::default(),
+                fields : SnapshotField::ALL.to_vec(),
+            },
+            particles,
+        }
+    }
+
+    pub fn with_units(mut self, units : Units) -> Snapshot {
+        self.header.units = units;
+        self
+    }
+
+    /// Position and mass are always written.
+    pub fn with_fields(mut self, fields : &[SnapshotField]) -> Snapshot {
+        let mut retval = vec![SnapshotField::Position, SnapshotField::Mass];
+        for &field in fields {
+            if !retval.contains(&field) {
+                retval.push(field);
+            }
+        }
+        self.header.fields = retval;
+        self
+    }
+
+    pub fn write_to<W : Write>(&self, out : &mut W) -> io::Result<()> {
+        let header = &self.header;
+        write_magic(out, SNAPSHOT_MAGIC, SNAPSHOT_VERSION)?;
+        write_f64(out, header.time)?;
+        write_len(out, self.particles.len())?;
+        write_f64(out, header.G)?;
+        write_f64(out, header.units.length)?;
+        write_f64(out, header.units.mass)?;
+        write_f64(out, header.units.time)?;
+        write_u32(out, header.fields.len() as u32)?;
+        for field in header.fields.iter() {
+            write_u8(out, field.tag())?;
+            write_u64(out, field.width() * self.particles.len() as u64)?;
+        }
+        for &field in header.fields.iter() {
+            self.write_column(out, field)?;
+        }
+        Ok(())
+    }
+
+    fn write_column<W : Write>(&self, out : &mut W, field : SnapshotField) -> io::Result<()> {
+        for part in self.particles.iter() {
+            match field {
+                SnapshotField::Position => write_vec2d(out, part.pos)?,
+                SnapshotField::Velocity => write_vec2d(out, part.vel)?,
+                SnapshotField::Mass => write_f64(out, part.mass)?,
+                SnapshotField::Radius => write_f64(out, part.radius)?,
+                SnapshotField::Charge => write_f64(out, part.charge)?,
+                SnapshotField::Id => write_u64(out, part.id)?,
+                SnapshotField::Species => write_u32(out, part.species)?,
+                SnapshotField::Gas => write_bool(out, part.is_gas())?,
+            }
+        }
+        if field == SnapshotField::Gas {
+            for part in self.particles.iter() {
+                write_f64(out, part.gas.map_or(0.0, |gas| gas.internal_energy))?;
+            }
+            for part in self.particles.iter() {
+                write_f64(out, part.gas.map_or(0.0, |gas| gas.smoothing_length))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fields missing from the file are left at their `Particle::new`
+    /// defaults.
+    pub fn read_from<R : Read>(input : &mut R) -> io::Result<Snapshot> {
+        read_magic(input, SNAPSHOT_MAGIC, SNAPSHOT_VERSION)?;
+        let time = read_f64(input)?;
+        let num_particles = read_len(input)?;
+        let G = read_f64(input)?;
+        let units = Units::new(read_f64(input)?, read_f64(input)?, read_f64(input)?);
+        let num_columns = read_u32(input)?;
+        let mut columns = Vec::new();
+        for _ in 0..num_columns {
+            let tag = read_u8(input)?;
+            let len = read_u64(input)?;
+            columns.push((tag, len));
+        }
+
+        // The count is untrusted, so particles are only created as the first
+        // column's data actually arrives.
+        let mut particles = Vec::new();
+        let mut fields = Vec::new();
+        for (tag, len) in columns {
+            let field = match SnapshotField::from_tag(tag) {
+                Some(field) => field,
+                None => {
+                    io::copy(&mut input.take(len), &mut io::sink())?;
+                    continue;
+                },
+            };
+            if field.width().checked_mul(num_particles as u64) != Some(len) {
+                return Err(invalid_data("snapshot column has the wrong length"));
+            }
+            Snapshot::read_column(input, field, num_particles, &mut particles)?;
+            fields.push(field);
+        }
+        if !fields.contains(&SnapshotField::Position) || !fields.contains(&SnapshotField::Mass) {
+            return Err(invalid_data("snapshot lacks positions or masses"));
+        }
+        Ok(Snapshot {
+            header : SnapshotHeader { time, num_particles, G, units, fields },
+            particles,
+        })
+    }
+
+    fn read_column<R : Read>(input : &mut R, field : SnapshotField, num_particles : usize, particles : &mut Vec<Particle>) -> io::Result<()> {
+        for idx in 0..num_particles {
+            if idx == particles.len() {
+                particles.push(Particle::new(0.0, 0.0, Vec2d::zero(), Vec2d::zero()));
+            }
+            let part = &mut particles[idx];
+            match field {
+                SnapshotField::Position => part.pos = read_vec2d(input)?,
+                SnapshotField::Velocity => part.vel = read_vec2d(input)?,
+                SnapshotField::Mass => part.mass = read_f64(input)?,
+                SnapshotField::Radius => part.radius = read_f64(input)?,
+                SnapshotField::Charge => part.charge = read_f64(input)?,
+                SnapshotField::Id => part.id = read_u64(input)?,
+                SnapshotField::Species => part.species = read_u32(input)?,
+                SnapshotField::Gas => part.gas = if read_bool(input)? { Some(GasState::default()) } else { None },
+            }
+        }
+        if field == SnapshotField::Gas {
+            let internal_energy = (0..particles.len()).map(|_| read_f64(input)).collect::<io::Result<Vec<Scalar>>>()?;
+            for (part, &energy) in particles.iter_mut().zip(internal_energy.iter()) {
+                let smoothing_length = read_f64(input)?;
+                if let Some(gas) = part.gas.as_mut() {
+                    *gas = GasState::new(energy, smoothing_length);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save<T : AsRef<Path>>(&self, file_name : T) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(file_name)?);
+        self.write_to(&mut out)?;
+        out.flush()
+    }
+
+    pub fn load<T : AsRef<Path>>(file_name : T) -> io::Result<Snapshot> {
+        let mut input = BufReader::new(File::open(file_name)?);
+        Snapshot::read_from(&mut input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn particles() -> Vec<Particle> {
+        (0..5u64)
+            .map(|idx| {
+                let x = idx as Scalar;
+                let part = Particle::new(1.0 + x, 0.5, Vec2d::new(x, -x), Vec2d::new(0.25 * x, 1e-7))
+                    .with_species(idx as u32 % 2)
+                    .with_charge(-x);
+                Particle { id : 10 + idx, ..part }
+            })
+            .collect()
+    }
+
+    fn round_trip(snapshot : &Snapshot) -> Snapshot {
+        let mut buf = Vec::new();
+        snapshot.write_to(&mut buf).unwrap();
+        Snapshot::read_from(&mut buf.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn all_fields_round_trip() {
+        let mut parts = particles();
+        parts[1].gas = Some(GasState::new(2.5, 0.125));
+        parts[3].gas = Some(GasState::new(0.0, 3.0));
+        let snapshot = Snapshot::new(12.5, 6.674e-11, parts)
+            .with_units(Units::new(1.496e11, 1.989e30, 3.156e7));
+        assert_eq!(round_trip(&snapshot), snapshot);
+    }
+
+    #[test]
+    fn missing_fields_read_as_defaults() {
+        let snapshot = Snapshot::new(1.0, 1.0, particles()).with_fields(&[SnapshotField::Velocity]);
+        let read = round_trip(&snapshot);
+        assert_eq!(read.header, snapshot.header);
+        let expected = snapshot.particles.iter()
+            .map(|part| Particle::new(part.mass, 0.0, part.pos, part.vel))
+            .collect::<Vec<Particle>>();
+        assert_eq!(read.particles, expected);
+    }
+
+    #[test]
+    fn huge_particle_count_is_invalid_data() {
+        let mut buf = Vec::new();
+        Snapshot::new(0.0, 1.0, particles()).write_to(&mut buf).unwrap();
+        // N follows the magic, version and time.
+        buf[16..24].copy_from_slice(&(u64::MAX/2).to_le_bytes());
+        let err = Snapshot::read_from(&mut buf.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
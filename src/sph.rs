@@ -0,0 +1,232 @@
+use crate::mathvec::{Scalar, Vec2d};
+use crate::particles::Particle;
+use crate::gridhandler::GridHandler;
+use crate::binio::*;
+
+use std::io::{self, Read, Write};
+
+const PI : Scalar = std::f64::consts::PI;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SphParams {
+    pub gamma : Scalar,
+    pub alpha : Scalar,
+    pub beta : Scalar,
+}
+
+impl Default for SphParams {
+    fn default() -> SphParams {
+        SphParams {
+            gamma : 5.0/3.0,
+            alpha : 1.0,
+            beta : 2.0,
+        }
+    }
+}
+
+impl SphParams {
+    pub fn write_to<W : Write>(&self, out : &mut W) -> io::Result<()> {
+        write_f64(out, self.gamma)?;
+        write_f64(out, self.alpha)?;
+        write_f64(out, self.beta)
+    }
+    pub fn read_from<R : Read>(input : &mut R) -> io::Result<SphParams> {
+        Ok(SphParams {
+            gamma : read_f64(input)?,
+            alpha : read_f64(input)?,
+            beta : read_f64(input)?,
+        })
+    }
+}
+
+/// 2D cubic spline kernel (Monaghan & Lattanzio 1985) with support radius `2h`.
+pub fn kernel(r : Scalar, h : Scalar) -> Scalar {
+    let sigma = 10.0/(7.0 * PI * h * h);
+    let q = r/h;
+    if q < 1.0 {
+        sigma * (1.0 - 1.5 * q * q + 0.75 * q * q * q)
+    }
+    else if q < 2.0 {
+        sigma * 0.25 * (2.0 - q).powi(3)
+    }
+    else {
+        0.0
+    }
+}
+
+pub fn kernel_derivative(r : Scalar, h : Scalar) -> Scalar {
+    let sigma = 10.0/(7.0 * PI * h * h);
+    let q = r/h;
+    if q < 1.0 {
+        sigma/h * (-3.0 * q + 2.25 * q * q)
+    }
+    else if q < 2.0 {
+        sigma/h * (-0.75 * (2.0 - q).powi(2))
+    }
+    else {
+        0.0
+    }
+}
+
+/// Gradient of the kernel with respect to the first particle, symmetrized
+/// over the two smoothing lengths.
+pub fn kernel_gradient(diff : Vec2d, h_a : Scalar, h_b : Scalar) -> Vec2d {
+    let r = diff.mag();
+    if r < 1e-12 {
+        return Vec2d::zero();
+    }
+    let dw = 0.5 * (kernel_derivative(r, h_a) + kernel_derivative(r, h_b));
+    diff * (dw/r)
+}
+
+pub struct SphHandler {
+    params : SphParams,
+    grid : GridHandler,
+}
+
+impl SphHandler {
+    pub fn new(params : SphParams) -> SphHandler {
+        SphHandler {
+            params,
+            grid : GridHandler::new(0.0, 0),
+        }
+    }
+
+    pub fn params(&self) -> SphParams {
+        self.params
+    }
+
+    /// Bins the gas particles, then computes their densities and pressures,
+    /// writing the results back into `parts`.
+    pub fn build(params : SphParams, parts : &mut [Particle]) -> SphHandler {
+        let max_h = parts.iter()
+            .filter_map(|p| p.gas.map(|g| g.smoothing_length))
+            .fold(0.0, |acc : Scalar, h| acc.max(h));
+        if max_h <= 0.0 {
+            return SphHandler::new(params);
+        }
+        let num_gas = parts.iter().filter(|p| p.is_gas()).count();
+        let multiplier = 1.0/(2.0 * max_h);
+
+        let mut grid = GridHandler::new(multiplier, num_gas);
+        for part in parts.iter().filter(|p| p.is_gas()) {
+            grid.add_particle(*part);
+        }
+
+        for part in parts.iter_mut() {
+            let pos = part.pos;
+            let gas = match part.gas.as_mut() {
+                Some(g) => g, 
+                None => {continue;}
+            };
+            let h = gas.smoothing_length;
+            let density : Scalar = grid.neighbors(pos)
+                .map(|other| other.mass * kernel((other.pos - pos).mag(), h))
+                .sum();
+            gas.density = density;
+            gas.pressure = (params.gamma - 1.0) * density * gas.internal_energy;
+        }
+
+        grid.clear_points();
+        for part in parts.iter().filter(|p| p.is_gas()) {
+            grid.add_particle(*part);
+        }
+        SphHandler {
+            params, 
+            grid,
+        }
+    }
+
+    fn sound_speed(&self, pressure : Scalar, density : Scalar) -> Scalar {
+        (self.params.gamma * pressure/density).max(0.0).sqrt()
+    }
+
+    /// Returns the pressure + artificial viscosity force on `arg` and the rate
+    /// of change of its specific internal energy.
+    pub fn calculate_hydro_force(&self, arg : Particle) -> (Vec2d, Scalar) {
+        let gas_a = match arg.gas {
+            Some(g) if g.density > 0.0 => g,
+            _ => { return (Vec2d::zero(), 0.0); }
+        };
+        let c_a = self.sound_speed(gas_a.pressure, gas_a.density);
+        let p_term_a = gas_a.pressure/(gas_a.density * gas_a.density);
+
+        let mut accel = Vec2d::zero();
+        let mut du_dt = 0.0;
+        for other in self.grid.neighbors(arg.pos) {
+            let gas_b = match other.gas {
+                Some(g) if g.density > 0.0 => g,
+                _ => {continue;}
+            };
+            let diff = arg.pos - other.pos;
+            let grad = kernel_gradient(diff, gas_a.smoothing_length, gas_b.smoothing_length);
+            let relvel = arg.vel - other.vel;
+            let vr = relvel.dot(diff);
+
+            let viscosity = if vr < 0.0 {
+                let h = 0.5 * (gas_a.smoothing_length + gas_b.smoothing_length);
+                let mu = h * vr/(diff.mag_squared() + 0.01 * h * h);
+                let c = 0.5 * (c_a + self.sound_speed(gas_b.pressure, gas_b.density));
+                let rho = 0.5 * (gas_a.density + gas_b.density);
+                (-self.params.alpha * c * mu + self.params.beta * mu * mu)/rho
+            } else {
+                0.0
+            };
+
+            let p_term_b = gas_b.pressure/(gas_b.density * gas_b.density);
+            let coeff = other.mass * (p_term_a + p_term_b + viscosity);
+            accel -= coeff * grad;
+            du_dt += 0.5 * coeff * relvel.dot(grad);
+        }
+        (arg.mass * accel, du_dt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kernel_is_normalized() {
+        let (h, steps) = (0.7, 100_000);
+        let dr = 2.0 * h/steps as Scalar;
+        let integral : Scalar = (0..steps)
+            .map(|i| {
+                let r = (i as Scalar + 0.5) * dr;
+                kernel(r, h) * 2.0 * PI * r * dr
+            })
+            .sum();
+        assert!((integral - 1.0).abs() < 1e-8);
+    }
+
+    #[test]
+    fn derivative_matches_finite_difference() {
+        let (h, eps) = (0.7, 1e-6);
+        for &r in [0.1, 0.5, 0.69, 0.71, 1.0, 1.39].iter() {
+            let numeric = (kernel(r + eps, h) - kernel(r - eps, h))/(2.0 * eps);
+            assert!((kernel_derivative(r, h) - numeric).abs() < 1e-6, "r = {}", r);
+        }
+    }
+
+    /// A square lattice of spacing 0.5 and particle mass 0.25, density one.
+    fn lattice() -> Vec<Particle> {
+        (0..400).map(|i| {
+            let pos = Vec2d::new(0.5 * (i % 20) as Scalar, 0.5 * (i / 20) as Scalar);
+            Particle::new_gas(0.25, pos, Vec2d::zero(), 2.0, 0.6)
+        })
+        .collect()
+    }
+
+    #[test]
+    fn uniform_lattice_has_uniform_density_and_no_force() {
+        let params = SphParams::default();
+        let mut parts = lattice();
+        let sph = SphHandler::build(params, &mut parts);
+        let centre = parts[10 * 20 + 10];
+        let gas = centre.gas.unwrap();
+        assert!((gas.density - 1.0).abs() < 0.01, "density {}", gas.density);
+        assert_eq!(gas.pressure, (params.gamma - 1.0) * gas.density * 2.0);
+        let (force, du_dt) = sph.calculate_hydro_force(centre);
+        assert!(force.mag() < 1e-12 && du_dt.abs() < 1e-12);
+    }
+}
@@ -0,0 +1,243 @@
+use crate::mathvec::{Scalar, Vec2d};
+use crate::particles::Particle;
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+/// A column of the plain text particle format.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TextColumn {
+    X,
+    Y,
+    Vx,
+    Vy,
+    Mass,
+    Radius,
+    Id,
+    Species,
+    Charge,
+}
+
+impl TextColumn {
+    pub const ALL : [TextColumn; 9] = [
+        TextColumn::X,
+        TextColumn::Y,
+        TextColumn::Vx,
+        TextColumn::Vy,
+        TextColumn::Mass,
+        TextColumn::Radius,
+        TextColumn::Id,
+        TextColumn::Species,
+        TextColumn::Charge,
+    ];
+    const REQUIRED : [TextColumn; 5] = [TextColumn::X, TextColumn::Y, TextColumn::Vx, TextColumn::Vy, TextColumn::Mass];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TextColumn::X => "x",
+            TextColumn::Y => "y",
+            TextColumn::Vx => "vx",
+            TextColumn::Vy => "vy",
+            TextColumn::Mass => "mass",
+            TextColumn::Radius => "radius",
+            TextColumn::Id => "id",
+            TextColumn::Species => "species",
+            TextColumn::Charge => "charge",
+        }
+    }
+
+    pub fn from_name(name : &str) -> Option<TextColumn> {
+        TextColumn::ALL.iter().cloned().find(|column| column.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Why a text particle file could not be read, with the 1-based line and,
+/// where it applies, the column.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ParseError {
+    pub line : usize,
+    pub column : Option<&'static str>,
+    pub message : String,
+}
+
+impl ParseError {
+    fn new(line : usize, column : Option<&'static str>, message : String) -> ParseError {
+        ParseError { line, column, message }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self.column {
+            Some(column) => write!(f, "line {}, column `{}`: {}", self.line, column, self.message),
+            None => write!(f, "line {}: {}", self.line, self.message),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+impl From<ParseError> for io::Error {
+    fn from(err : ParseError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Particles read from a text file together with the columns it had, so the
+/// caller knows whether ids and species were given.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TextParticles {
+    pub columns : Vec<TextColumn>,
+    pub particles : Vec<Particle>,
+}
+
+fn parse_field<T : FromStr>(token : &str, line : usize, column : TextColumn, what : &str) -> Result<T, ParseError> {
+    token.parse().map_err(|_| ParseError::new(line, Some(column.name()), format!("`{}` is not {}", token, what)))
+}
+
+/// Reads whitespace separated particle rows. Everything after a `#` is a
+/// comment and blank lines are skipped. If the first row starts with a
+/// column name it is a header naming the columns in any order; x, y, vx, vy
+/// and mass are required, radius, id, species and charge optional. Files
+/// without a header use the order `x y vx vy mass radius [id species
+/// [charge]]` written by older versions.
+pub fn read_particles<R : BufRead>(input : R) -> io::Result<TextParticles> {
+    let mut columns : Option<Vec<TextColumn>> = None;
+    let mut particles = Vec::new();
+    for (idx, line) in input.lines().enumerate() {
+        let line_number = idx + 1;
+        let line = line?;
+        let content = line.split('#').next().unwrap_or("");
+        let tokens = content.split_whitespace().collect::<Vec<&str>>();
+        if tokens.is_empty() {
+            continue;
+        }
+        let header = match columns.as_ref() {
+            Some(header) => header,
+            None => {
+                if f64::from_str(tokens[0]).is_err() {
+                    columns = Some(parse_header(&tokens, line_number)?);
+                    continue;
+                }
+                let legacy = match tokens.len() {
+                    6 | 8 | 9 => TextColumn::ALL[..tokens.len()].to_vec(),
+                    len => return Err(ParseError::new(line_number, None,
+                        format!("found {} fields but no header; expected 6, 8 or 9", len)).into()),
+                };
+                columns.get_or_insert(legacy)
+            },
+        };
+        if tokens.len() != header.len() {
+            return Err(ParseError::new(line_number, None,
+                format!("expected {} fields, found {}", header.len(), tokens.len())).into());
+        }
+        let mut part = Particle::new(0.0, 0.0, Vec2d::zero(), Vec2d::zero());
+        for (&column, token) in header.iter().zip(tokens.iter()) {
+            let number = "a number";
+            match column {
+                TextColumn::X => part.pos.x = parse_field(token, line_number, column, number)?,
+                TextColumn::Y => part.pos.y = parse_field(token, line_number, column, number)?,
+                TextColumn::Vx => part.vel.x = parse_field(token, line_number, column, number)?,
+                TextColumn::Vy => part.vel.y = parse_field(token, line_number, column, number)?,
+                TextColumn::Mass => part.mass = parse_field(token, line_number, column, number)?,
+                TextColumn::Radius => part.radius = parse_field(token, line_number, column, number)?,
+                TextColumn::Charge => part.charge = parse_field(token, line_number, column, number)?,
+                TextColumn::Id => part.id = parse_id(token, line_number, column)?,
+                TextColumn::Species => part.species = parse_field(token, line_number, column, "a non-negative integer")?,
+            }
+        }
+        particles.push(part);
+    }
+    Ok(TextParticles {
+        columns : columns.unwrap_or_default(),
+        particles,
+    })
+}
+
+/// Ids are integers, but files written before the header existed may hold
+/// them as whole floats.
+fn parse_id(token : &str, line : usize, column : TextColumn) -> Result<u64, ParseError> {
+    u64::from_str(token).or_else(|_| {
+        let val : Scalar = parse_field(token, line, column, "a non-negative integer")?;
+        if val >= 0.0 && val.fract() == 0.0 && val < u64::MAX as Scalar {
+            Ok(val as u64)
+        } else {
+            Err(ParseError::new(line, Some(column.name()), format!("`{}` is not a non-negative integer", token)))
+        }
+    })
+}
+
+fn parse_header(tokens : &[&str], line : usize) -> Result<Vec<TextColumn>, ParseError> {
+    let mut retval = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        let column = TextColumn::from_name(token)
+            .ok_or_else(|| ParseError::new(line, None, format!("unknown column `{}`", token)))?;
+        if retval.contains(&column) {
+            return Err(ParseError::new(line, Some(column.name()), "column appears twice".to_string()));
+        }
+        retval.push(column);
+    }
+    for column in TextColumn::REQUIRED.iter() {
+        if !retval.contains(column) {
+            return Err(ParseError::new(line, Some(column.name()), "required column is missing".to_string()));
+        }
+    }
+    Ok(retval)
+}
+
+/// Writes a header row and one row per particle with every column. `{}`
+/// prints the shortest representation that reads back to the same float.
+pub fn write_particles<W : Write, T : IntoIterator<Item=Particle>>(out : &mut W, data : T) -> io::Result<()> {
+    let names = TextColumn::ALL.iter().map(|column| column.name()).collect::<Vec<&str>>();
+    writeln!(out, "{}", names.join(" "))?;
+    for part in data.into_iter() {
+        writeln!(out, "{} {} {} {} {} {} {} {} {}", part.pos.x, part.pos.y, part.vel.x, part.vel.y, part.mass, part.radius, part.id, part.species, part.charge)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(text : &str) -> ParseError {
+        let err = read_particles(text.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        err.get_ref().and_then(|inner| inner.downcast_ref::<ParseError>()).unwrap().clone()
+    }
+
+    #[test]
+    fn header_in_any_order_with_blanks_comments_and_tabs() {
+        let text = "# generated by hand\n\
+                    \n\
+                    mass\tspecies  y x vy vx   # header\n\
+                    \t\n\
+                    2.5\t3 -1 1.5 0.25 -0.5\n\
+                    # between rows\n\
+                    \x20 1 0 2 3 4 5  # trailing comment\n";
+        let read = read_particles(text.as_bytes()).unwrap();
+        assert_eq!(read.columns, vec![TextColumn::Mass, TextColumn::Species, TextColumn::Y, TextColumn::X, TextColumn::Vy, TextColumn::Vx]);
+        let expected = vec![
+            Particle::new(2.5, 0.0, Vec2d::new(1.5, -1.0), Vec2d::new(-0.5, 0.25)).with_species(3),
+            Particle::new(1.0, 0.0, Vec2d::new(3.0, 2.0), Vec2d::new(5.0, 4.0)),
+        ];
+        assert_eq!(read.particles, expected);
+    }
+
+    #[test]
+    fn bad_field_reports_line_and_column() {
+        let err = parse_error("x y vx vy mass\n\n1 2 3 4 5\n1 2 three 4 5\n");
+        assert_eq!(err.line, 4);
+        assert_eq!(err.column, Some("vx"));
+
+        let err = parse_error("# legacy rows\n0 0 0 0 1 1 7 0\n0 0 0 0 1 1 -2 0\n");
+        assert_eq!(err.line, 3);
+        assert_eq!(err.column, Some("id"));
+
+        let err = parse_error("x y vx vy\n");
+        assert_eq!(err.line, 1);
+        assert_eq!(err.column, Some("mass"));
+    }
+}